@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS password_resets;
+ALTER TABLE users DROP COLUMN tokens_valid_after;
+ALTER TABLE users DROP COLUMN role;
\ No newline at end of file
@@ -0,0 +1,13 @@
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'student';
+ALTER TABLE users ADD COLUMN tokens_valid_after TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00';
+
+CREATE TABLE password_resets (
+    id SERIAL PRIMARY KEY,
+    usr_id INT NOT NULL,
+    issued_by INT,
+    code TEXT NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP,
+    CONSTRAINT fk_users FOREIGN KEY(usr_id) REFERENCES users(id) ON DELETE CASCADE,
+    CONSTRAINT fk_issued_by FOREIGN KEY(issued_by) REFERENCES users(id) ON DELETE SET NULL
+);
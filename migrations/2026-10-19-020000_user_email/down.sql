@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS email_verifications;
+DROP INDEX IF EXISTS users_verified_email_idx;
+ALTER TABLE users DROP COLUMN email_verified;
+ALTER TABLE users DROP COLUMN email;
\ No newline at end of file
@@ -0,0 +1,14 @@
+ALTER TABLE users ADD COLUMN email TEXT;
+ALTER TABLE users ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+CREATE UNIQUE INDEX users_verified_email_idx ON users (email) WHERE email_verified;
+
+CREATE TABLE email_verifications (
+    id SERIAL PRIMARY KEY,
+    usr_id INT NOT NULL,
+    email TEXT NOT NULL,
+    selector TEXT NOT NULL UNIQUE,
+    verifier TEXT NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP,
+    CONSTRAINT fk_users FOREIGN KEY(usr_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS users_usr_lower_idx;
+DROP TABLE IF EXISTS username_renames;
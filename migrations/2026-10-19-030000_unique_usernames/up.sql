@@ -0,0 +1,22 @@
+-- Usernames only had to match exactly before, so some may differ by nothing but case.
+-- Every one but the oldest is given its id as a suffix, and the renames are kept until
+-- the audit log exists, see 2026-10-19-060000_unlocks_and_audit.
+CREATE TABLE username_renames (
+    usr_id INT NOT NULL,
+    old_usr TEXT NOT NULL,
+    new_usr TEXT NOT NULL,
+    renamed_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc')
+);
+INSERT INTO username_renames (usr_id, old_usr, new_usr)
+SELECT id, usr, usr || '_' || id
+FROM (
+    SELECT id, usr, ROW_NUMBER() OVER (PARTITION BY lower(usr) ORDER BY id) AS age
+    FROM users
+) AS ranked
+WHERE age > 1;
+UPDATE users SET usr = username_renames.new_usr
+FROM username_renames
+WHERE users.id = username_renames.usr_id;
+
+DROP INDEX IF EXISTS users_usr_lower_idx;
+CREATE UNIQUE INDEX users_usr_lower_idx ON users (lower(usr));
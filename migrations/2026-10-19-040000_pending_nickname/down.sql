@@ -0,0 +1 @@
+ALTER TABLE users DROP COLUMN pending_nickname;
\ No newline at end of file
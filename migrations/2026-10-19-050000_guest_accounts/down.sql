@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS users_guest_activity_idx;
+ALTER TABLE users DROP COLUMN last_active_at;
+ALTER TABLE users DROP COLUMN is_guest;
\ No newline at end of file
@@ -0,0 +1,3 @@
+ALTER TABLE users ADD COLUMN is_guest BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE users ADD COLUMN last_active_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc');
+CREATE INDEX users_guest_activity_idx ON users (last_active_at) WHERE is_guest;
@@ -0,0 +1,29 @@
+CREATE TABLE unlocks (
+    id SERIAL PRIMARY KEY,
+    usr_id INT NOT NULL,
+    kind TEXT NOT NULL,
+    name TEXT NOT NULL,
+    unlocked_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+    CONSTRAINT fk_users FOREIGN KEY(usr_id) REFERENCES users(id) ON DELETE CASCADE,
+    CONSTRAINT unique_unlock UNIQUE (usr_id, kind, name)
+);
+
+-- Deliberately has no foreign keys, so that history is kept when a user is deleted
+CREATE TABLE audit_events (
+    id SERIAL PRIMARY KEY,
+    actor_id INT,
+    target_id INT,
+    action TEXT NOT NULL,
+    before JSONB,
+    after JSONB,
+    ip TEXT,
+    user_agent TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc')
+);
+CREATE INDEX audit_events_target_idx ON audit_events (target_id, created_at);
+
+-- Usernames renamed when they became case insensitive, see 2026-10-19-030000_unique_usernames
+INSERT INTO audit_events (target_id, action, before, after, created_at)
+SELECT usr_id, 'student.username', jsonb_build_object('usr', old_usr), jsonb_build_object('usr', new_usr), renamed_at
+FROM username_renames;
+DROP TABLE username_renames;
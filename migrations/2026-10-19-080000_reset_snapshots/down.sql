@@ -0,0 +1 @@
+DROP TABLE reset_snapshots;
\ No newline at end of file
@@ -0,0 +1,14 @@
+CREATE TABLE reset_snapshots (
+    id SERIAL PRIMARY KEY,
+    usr_id INT NOT NULL,
+    scores JSONB NOT NULL,
+    unlocks JSONB NOT NULL,
+    current_costume TEXT NOT NULL,
+    costumes TEXT[] NOT NULL,
+    achievements TEXT[] NOT NULL,
+    stars INT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+    restored_at TIMESTAMP,
+    CONSTRAINT fk_users FOREIGN KEY(usr_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX reset_snapshots_usr_idx ON reset_snapshots (usr_id, created_at);
@@ -0,0 +1,2 @@
+DROP INDEX audit_events_action_idx;
+DROP INDEX audit_events_actor_idx;
\ No newline at end of file
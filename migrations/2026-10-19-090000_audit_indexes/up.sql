@@ -0,0 +1,2 @@
+CREATE INDEX audit_events_actor_idx ON audit_events (actor_id, created_at);
+CREATE INDEX audit_events_action_idx ON audit_events (action, created_at);
\ No newline at end of file
@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS scores_flagged_idx;
+ALTER TABLE scores DROP COLUMN flagged;
+ALTER TABLE scores DROP COLUMN voided;
+ALTER TABLE reset_snapshots DROP COLUMN bonus_stars;
+ALTER TABLE users DROP COLUMN bonus_stars;
@@ -0,0 +1,5 @@
+ALTER TABLE users ADD COLUMN bonus_stars INT NOT NULL DEFAULT 0;
+ALTER TABLE reset_snapshots ADD COLUMN bonus_stars INT NOT NULL DEFAULT 0;
+ALTER TABLE scores ADD COLUMN voided BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE scores ADD COLUMN flagged BOOLEAN NOT NULL DEFAULT FALSE;
+CREATE INDEX scores_flagged_idx ON scores (id) WHERE flagged AND NOT voided;
@@ -0,0 +1,4 @@
+DROP INDEX IF EXISTS users_classroom_idx;
+ALTER TABLE users DROP COLUMN year_level;
+ALTER TABLE users DROP COLUMN classroom_id;
+DROP TABLE classrooms;
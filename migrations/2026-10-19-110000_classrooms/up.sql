@@ -0,0 +1,11 @@
+CREATE TABLE classrooms (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    teacher_id INT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+    CONSTRAINT fk_teacher FOREIGN KEY(teacher_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+ALTER TABLE users ADD COLUMN classroom_id INT REFERENCES classrooms(id) ON DELETE SET NULL;
+ALTER TABLE users ADD COLUMN year_level INT;
+CREATE INDEX users_classroom_idx ON users (classroom_id);
@@ -0,0 +1 @@
+DROP TABLE login_links;
\ No newline at end of file
@@ -0,0 +1,10 @@
+CREATE TABLE login_links (
+    id SERIAL PRIMARY KEY,
+    usr_id INT NOT NULL,
+    secret TEXT NOT NULL,
+    created_by INT,
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP,
+    CONSTRAINT fk_users FOREIGN KEY(usr_id) REFERENCES users(id) ON DELETE CASCADE,
+    CONSTRAINT fk_created_by FOREIGN KEY(created_by) REFERENCES users(id) ON DELETE SET NULL
+);
@@ -0,0 +1,2 @@
+ALTER TABLE users DROP COLUMN badge_secret;
+ALTER TABLE users DROP COLUMN picture_password;
@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN picture_password TEXT;
+ALTER TABLE users ADD COLUMN badge_secret TEXT;
@@ -0,0 +1,23 @@
+CREATE TABLE user_identities (
+    id SERIAL PRIMARY KEY,
+    usr_id INT NOT NULL,
+    provider TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    email TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+    last_login_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+    CONSTRAINT fk_users FOREIGN KEY(usr_id) REFERENCES users(id) ON DELETE CASCADE,
+    CONSTRAINT unique_identity UNIQUE(provider, subject)
+);
+CREATE INDEX user_identities_usr_id ON user_identities(usr_id);
+
+-- A sign in which has been sent to a provider and not come back yet
+CREATE TABLE oidc_logins (
+    state TEXT PRIMARY KEY,
+    provider TEXT NOT NULL,
+    code_verifier TEXT NOT NULL,
+    nonce TEXT NOT NULL,
+    link_usr_id INT,
+    created_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+    CONSTRAINT fk_link_users FOREIGN KEY(link_usr_id) REFERENCES users(id) ON DELETE CASCADE
+);
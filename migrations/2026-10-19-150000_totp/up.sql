@@ -0,0 +1,14 @@
+-- The secret is kept until enrolment is confirmed, at which point totp_enabled_at is set
+ALTER TABLE users ADD COLUMN totp_secret TEXT;
+ALTER TABLE users ADD COLUMN totp_enabled_at TIMESTAMP;
+-- The last time step a code was accepted for, so a code can't be used twice
+ALTER TABLE users ADD COLUMN totp_last_step BIGINT NOT NULL DEFAULT 0;
+
+CREATE TABLE recovery_codes (
+    id SERIAL PRIMARY KEY,
+    usr_id INT NOT NULL,
+    code TEXT NOT NULL,
+    used_at TIMESTAMP,
+    CONSTRAINT fk_users FOREIGN KEY(usr_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX recovery_codes_usr_id ON recovery_codes(usr_id);
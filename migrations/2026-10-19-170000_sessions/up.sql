@@ -0,0 +1,12 @@
+CREATE TABLE sessions (
+    id SERIAL PRIMARY KEY,
+    usr_id INT NOT NULL,
+    device_label TEXT,
+    user_agent TEXT,
+    ip TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+    last_seen_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+    revoked_at TIMESTAMP,
+    CONSTRAINT fk_users FOREIGN KEY(usr_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX sessions_usr_id ON sessions(usr_id);
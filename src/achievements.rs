@@ -0,0 +1,87 @@
+use crate::{common, models, ACHIEVEMENTS, COSTUMES};
+use diesel::prelude::*;
+
+/// What a user has done so far, used to check their achievements
+pub struct Progress {
+    pub num_games: i64,
+    pub high_score: i32,
+    pub stars: i64,
+    pub all_costumes: bool,
+}
+
+impl Progress {
+    /// Work out the progress of a user from their scores and costumes
+    pub fn load(
+        c: &diesel::PgConnection,
+        user: &models::User,
+    ) -> Result<Progress, diesel::result::Error> {
+        use crate::schema::scores::dsl::*;
+        let counted = scores
+            .filter(usr_id.eq(user.id))
+            .filter(voided.eq(false))
+            .filter(flagged.eq(false));
+        let num_games: i64 = counted.count().get_result(c)?;
+        let high_score: Option<i32> = counted.select(diesel::dsl::max(score)).get_result(c)?;
+        Ok(Progress {
+            num_games,
+            high_score: high_score.unwrap_or(0),
+            stars: common::star_balance(c, user.id)?,
+            all_costumes: COSTUMES
+                .keys()
+                .all(|k| user.costumes.iter().any(|owned| &owned.name == k)),
+        })
+    }
+
+    /// Whether every requirement given has been met
+    pub fn meets(&self, requirements: &models::Requirements) -> bool {
+        requirements.num_games.map(|n| self.num_games >= n).unwrap_or(true)
+            && requirements.high_score.map(|n| self.high_score >= n).unwrap_or(true)
+            && requirements.stars.map(|n| self.stars >= n).unwrap_or(true)
+            && requirements
+                .costumes
+                .as_ref()
+                .map(|c| c != "all" || self.all_costumes)
+                .unwrap_or(true)
+    }
+}
+
+/// Find the achievements a user has earned but hasn't been given yet.
+/// Achievements without requirements in `./achievement.toml` are only given by the game, so are never returned.
+#[tracing::instrument(name = "catalog.achievements", skip_all, fields(user_id = user.id))]
+pub fn missing(
+    c: &diesel::PgConnection,
+    user: &models::User,
+) -> Result<Vec<String>, diesel::result::Error> {
+    let progress = Progress::load(c, user)?;
+    let mut earned: Vec<String> = ACHIEVEMENTS
+        .values()
+        .filter(|a| !user.achievements.iter().any(|owned| owned.name == a.name))
+        .filter(|a| a.requirements.as_ref().map(|r| progress.meets(r)).unwrap_or(false))
+        .map(|a| a.name.clone())
+        .collect();
+    earned.sort();
+    Ok(earned)
+}
+
+/// Give a user every achievement they have earned but don't have yet, returning the names of those given
+pub fn evaluate(
+    c: &diesel::PgConnection,
+    user: &models::User,
+) -> Result<Vec<String>, diesel::result::Error> {
+    let earned = missing(c, user)?;
+    if earned.is_empty() {
+        return Ok(earned);
+    }
+    let mut all: Vec<String> = user.achievements.iter().map(|a| a.name.clone()).collect();
+    all.extend(earned.iter().cloned());
+    {
+        use crate::schema::users::dsl::*;
+        diesel::update(users.filter(id.eq(user.id)))
+            .set(achievements.eq(all))
+            .execute(c)?;
+    }
+    for name in &earned {
+        common::record_unlock(c, user.id, models::UNLOCK_ACHIEVEMENT, name)?;
+    }
+    Ok(earned)
+}
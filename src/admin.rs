@@ -0,0 +1,638 @@
+use crate::openapi::{Auth, Operation};
+use crate::{audit, common, models, sessions, validation, UsersDbConn};
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use std::convert::TryFrom;
+
+/// Every route under `/api/admin`, all of which require an admin token
+pub fn routes() -> Vec<rocket::Route> {
+    routes![
+        get_audit_events,
+        list_users,
+        get_user,
+        set_costumes,
+        set_achievements,
+        set_stars,
+        force_logout,
+        reset_mfa,
+        get_flagged_scores,
+        approve_score,
+        void_score,
+        delete_score,
+    ]
+}
+
+pub fn operations() -> Vec<Operation> {
+    vec![
+        Operation::new("get_audit_events", "Search the audit log")
+            .auth(Auth::Admin)
+            .param::<i32>("usr_id")
+            .param::<i64>("offset")
+            .param::<i64>("limit")
+            .returns::<Vec<models::AuditEvent>>(200, "Matching events, newest first")
+            .errors(&[400]),
+        Operation::new("list_users", "List and search users")
+            .auth(Auth::Admin)
+            .param::<i64>("offset")
+            .param::<i64>("limit")
+            .returns::<Vec<models::User>>(200, "Matching users"),
+        Operation::new("get_user", "View a user and their star balance")
+            .auth(Auth::Admin)
+            .param::<i32>("user_id")
+            .returns::<models::AdminUser>(200, "The user")
+            .errors(&[404]),
+        Operation::new("set_costumes", "Replace the costumes a user owns")
+            .auth(Auth::Admin)
+            .param::<i32>("user_id")
+            .body::<models::SetCostumes>()
+            .returns::<models::AdminUser>(200, "The updated user")
+            .errors(&[400, 404]),
+        Operation::new("set_achievements", "Replace the achievements a user has")
+            .auth(Auth::Admin)
+            .param::<i32>("user_id")
+            .body::<models::SetAchievements>()
+            .returns::<models::AdminUser>(200, "The updated user")
+            .errors(&[400, 404]),
+        Operation::new("set_stars", "Set a user's star balance")
+            .auth(Auth::Admin)
+            .param::<i32>("user_id")
+            .body::<models::SetStars>()
+            .returns::<models::AdminUser>(200, "The updated user")
+            .errors(&[400, 404]),
+        Operation::new("force_logout", "Sign a user out everywhere")
+            .auth(Auth::Admin)
+            .param::<i32>("user_id")
+            .returns::<models::AdminUser>(200, "The updated user")
+            .errors(&[404]),
+        Operation::new("reset_mfa", "Turn off a user's two-factor authentication")
+            .auth(Auth::Admin)
+            .param::<i32>("user_id")
+            .returns::<models::AdminUser>(200, "The updated user")
+            .errors(&[404]),
+        Operation::new("get_flagged_scores", "List suspicious scores")
+            .auth(Auth::Admin)
+            .param::<i64>("offset")
+            .param::<i64>("limit")
+            .returns::<Vec<models::Score>>(200, "Flagged scores which haven't been voided"),
+        Operation::new("approve_score", "Approve a flagged score")
+            .auth(Auth::Admin)
+            .param::<i32>("score_id")
+            .returns::<models::Score>(200, "The approved score")
+            .errors(&[404]),
+        Operation::new("void_score", "Void a score")
+            .auth(Auth::Admin)
+            .param::<i32>("score_id")
+            .returns::<models::Score>(200, "The voided score")
+            .errors(&[404]),
+        Operation::new("delete_score", "Permanently delete a score")
+            .auth(Auth::Admin)
+            .param::<i32>("score_id")
+            .returns::<String>(200, "Confirmation the score was deleted")
+            .errors(&[404]),
+    ]
+}
+
+/// Load a user along with their star balance, returning None if they don't exist
+fn load_user(
+    c: &diesel::PgConnection,
+    user_id: i32,
+) -> Result<Option<models::AdminUser>, diesel::result::Error> {
+    let user = match common::find_user(c, user_id)? {
+        Some(u) => u,
+        None => return Ok(None),
+    };
+    Ok(Some(models::AdminUser {
+        stars: common::star_balance(c, user_id)?,
+        bonus_stars: user.bonus_stars,
+        deleted_at: user.deleted_at,
+        last_active_at: user.last_active_at,
+        user,
+    }))
+}
+
+/// Apply a change to a user within a transaction, recording the before and after in the audit log
+fn update_user<F>(
+    c: &diesel::PgConnection,
+    event: audit::Event,
+    user_id: i32,
+    change: F,
+) -> Result<Option<models::AdminUser>, diesel::result::Error>
+where
+    F: FnOnce(&models::AdminUser) -> Result<(), diesel::result::Error>,
+{
+    c.transaction(|| {
+        let before = match load_user(c, user_id)? {
+            Some(u) => u,
+            None => return Ok(None),
+        };
+        change(&before)?;
+        let after = load_user(c, user_id)?;
+        event.before(&before).after(&after).save(c)?;
+        Ok(after)
+    })
+}
+
+/// Turn the result of a query into a response, where None means the thing couldn't be found
+fn respond<T: Serialize>(
+    r: Result<Option<T>, diesel::result::Error>,
+    not_found: &'static str,
+) -> models::Response {
+    match r {
+        Ok(Some(data)) => models::ResponseBuilder {
+            data,
+            status: Status::Ok,
+        }
+        .build(),
+        Ok(None) => models::ResponseBuilder {
+            data: not_found,
+            status: Status::NotFound,
+        }
+        .build(),
+        Err(e) => models::Response::database_error(e),
+    }
+}
+
+/// Parse an RFC 3339 timestamp from a query string into UTC
+fn parse_query_time(
+    field: &'static str,
+    value: Option<String>,
+    errors: &mut validation::Errors,
+) -> Option<chrono::NaiveDateTime> {
+    match chrono::DateTime::parse_from_rfc3339(&value?) {
+        Ok(t) => Some(t.naive_utc()),
+        Err(_) => {
+            errors.add(
+                field,
+                "Must be an RFC 3339 timestamp, such as 2021-10-08T19:46:10Z".into(),
+            );
+            None
+        }
+    }
+}
+
+/// Search the audit log
+#[get("/api/admin/audit?<usr_id>&<action>&<from>&<to>&<offset>&<limit>")]
+async fn get_audit_events(
+    admin: Result<models::Admin, models::Response>,
+    conn: UsersDbConn,
+    usr_id: Option<i32>,
+    action: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    offset: Option<i64>,
+    limit: Option<i64>,
+) -> models::Response {
+    if let Err(e) = admin {
+        return e;
+    }
+
+    let mut errors = validation::Errors::default();
+    let filter = models::AuditFilter {
+        usr_id,
+        action,
+        from: parse_query_time("from", from, &mut errors),
+        to: parse_query_time("to", to, &mut errors),
+        offset: offset.unwrap_or(0).abs(),
+        limit: limit.unwrap_or(100).abs(),
+    };
+    if let Err(e) = errors.into_result() {
+        return e;
+    }
+
+    let r = conn.run(move |c| audit::search(c, filter).map(Some)).await;
+    respond(r, "")
+}
+
+/// List users, optionally searching by username or nickname and filtering by role
+#[get("/api/admin/users?<search>&<role>&<offset>&<limit>")]
+async fn list_users(
+    admin: Result<models::Admin, models::Response>,
+    conn: UsersDbConn,
+    search: Option<String>,
+    role: Option<String>,
+    offset: Option<i64>,
+    limit: Option<i64>,
+) -> models::Response {
+    if let Err(e) = admin {
+        return e;
+    }
+    let offset: i64 = offset.unwrap_or(0).abs();
+    let limit: i64 = limit.unwrap_or(100).abs();
+
+    let r = conn
+        .run(move |c| {
+            use crate::schema::users;
+            let mut query = users::table.into_boxed();
+            if let Some(search) = search {
+                //Treat the search as plain text, rather than as a LIKE pattern
+                let pattern = format!(
+                    "%{}%",
+                    search
+                        .replace('\\', "\\\\")
+                        .replace('%', "\\%")
+                        .replace('_', "\\_")
+                );
+                query = query.filter(
+                    users::usr
+                        .ilike(pattern.clone())
+                        .or(users::nickname.ilike(pattern)),
+                );
+            }
+            if let Some(role) = role {
+                query = query.filter(users::role.eq(role));
+            }
+            query
+                .order(users::id.asc())
+                .limit(limit)
+                .offset(offset)
+                .load::<models::User>(c)
+                .map(Some)
+        })
+        .await;
+    respond(r, "")
+}
+
+/// View a single user, including their star balance
+#[get("/api/admin/users/<user_id>")]
+async fn get_user(
+    admin: Result<models::Admin, models::Response>,
+    conn: UsersDbConn,
+    user_id: i32,
+) -> models::Response {
+    if let Err(e) = admin {
+        return e;
+    }
+    let r = conn.run(move |c| load_user(c, user_id)).await;
+    respond(r, "User Not Found")
+}
+
+/// Replace the costumes a user owns, and optionally the one they are wearing
+#[put(
+    "/api/admin/users/<user_id>/costumes",
+    data = "<change>",
+    format = "application/json"
+)]
+async fn set_costumes(
+    admin: Result<models::Admin, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    user_id: i32,
+    change: Json<models::SetCostumes>,
+) -> models::Response {
+    if let Err(e) = admin {
+        return e;
+    }
+    let admin = admin.unwrap();
+    let mut change = change.into_inner();
+
+    //Everyone owns the default costume
+    if !change.costumes.iter().any(|c| c == "default") {
+        change.costumes.push("default".into());
+    }
+    change.costumes.sort();
+    change.costumes.dedup();
+    let mut errors = validation::Errors::default();
+    for name in &change.costumes {
+        if common::find_costume(name).is_none() {
+            errors.add("costumes", format!("Costume {} does not exist", name));
+        }
+    }
+    if let Some(wearing) = &change.current_costume {
+        if !change.costumes.contains(wearing) {
+            errors.add("current_costume", "Must be one of the costumes given".into());
+        }
+    }
+    if let Err(e) = errors.into_result() {
+        return e;
+    }
+
+    let event = recorder.event(Some(admin.0.sub), Some(user_id), "admin.costumes");
+    let r = conn
+        .run(move |c| {
+            update_user(c, event, user_id, |before| {
+                use crate::schema::users::dsl::*;
+                //Take off a costume they no longer own
+                let wearing = match change.current_costume {
+                    Some(w) => w,
+                    None if change.costumes.contains(&before.user.current_costume) => {
+                        before.user.current_costume.clone()
+                    }
+                    None => "default".into(),
+                };
+                for name in &change.costumes {
+                    common::record_unlock(c, user_id, models::UNLOCK_COSTUME, name)?;
+                }
+                diesel::update(users.filter(id.eq(user_id)))
+                    .set((costumes.eq(&change.costumes), current_costume.eq(wearing)))
+                    .execute(c)?;
+                Ok(())
+            })
+        })
+        .await;
+    respond(r, "User Not Found")
+}
+
+/// Replace the achievements a user has
+#[put(
+    "/api/admin/users/<user_id>/achievements",
+    data = "<change>",
+    format = "application/json"
+)]
+async fn set_achievements(
+    admin: Result<models::Admin, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    user_id: i32,
+    change: Json<models::SetAchievements>,
+) -> models::Response {
+    if let Err(e) = admin {
+        return e;
+    }
+    let admin = admin.unwrap();
+    let mut change = change.into_inner();
+    change.achievements.sort();
+    change.achievements.dedup();
+
+    let mut errors = validation::Errors::default();
+    for name in &change.achievements {
+        if common::find_achievement(name).is_none() {
+            errors.add("achievements", format!("Achievement {} does not exist", name));
+        }
+    }
+    if let Err(e) = errors.into_result() {
+        return e;
+    }
+
+    let event = recorder.event(Some(admin.0.sub), Some(user_id), "admin.achievements");
+    let r = conn
+        .run(move |c| {
+            update_user(c, event, user_id, |_| {
+                use crate::schema::users::dsl::*;
+                for name in &change.achievements {
+                    common::record_unlock(c, user_id, models::UNLOCK_ACHIEVEMENT, name)?;
+                }
+                diesel::update(users.filter(id.eq(user_id)))
+                    .set(achievements.eq(&change.achievements))
+                    .execute(c)?;
+                Ok(())
+            })
+        })
+        .await;
+    respond(r, "User Not Found")
+}
+
+/// Set a user's star balance, by giving or taking away bonus stars
+#[put(
+    "/api/admin/users/<user_id>/stars",
+    data = "<change>",
+    format = "application/json"
+)]
+async fn set_stars(
+    admin: Result<models::Admin, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    user_id: i32,
+    change: Json<models::SetStars>,
+) -> models::Response {
+    if let Err(e) = admin {
+        return e;
+    }
+    let admin = admin.unwrap();
+    let change = change.into_inner();
+
+    let event = recorder.event(Some(admin.0.sub), Some(user_id), "admin.stars");
+    let r = conn
+        .run(move |c| {
+            update_user(c, event, user_id, |before| {
+                use crate::schema::users::dsl::*;
+                let earned = before.stars - before.bonus_stars as i64;
+                //A balance too far from what was earned can't be stored, the rollback is turned into a 400 below
+                let bonus = i32::try_from(change.stars - earned)
+                    .map_err(|_| diesel::result::Error::RollbackTransaction)?;
+                diesel::update(users.filter(id.eq(user_id)))
+                    .set(bonus_stars.eq(bonus))
+                    .execute(c)?;
+                Ok(())
+            })
+        })
+        .await;
+    match r {
+        Err(diesel::result::Error::RollbackTransaction) => models::ResponseBuilder {
+            data: "Stars is too far from the number earned from scores",
+            status: Status::BadRequest,
+        }
+        .build(),
+        r => respond(r, "User Not Found"),
+    }
+}
+
+/// Revoke every token issued to a user, signing them out everywhere
+#[post("/api/admin/users/<user_id>/logout")]
+async fn force_logout(
+    admin: Result<models::Admin, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    user_id: i32,
+) -> models::Response {
+    if let Err(e) = admin {
+        return e;
+    }
+    let admin = admin.unwrap();
+
+    let event = recorder.event(Some(admin.0.sub), Some(user_id), "admin.logout");
+    let r = conn
+        .run(move |c| {
+            update_user(c, event, user_id, |_| {
+                use crate::schema::users::dsl::*;
+                diesel::update(users.filter(id.eq(user_id)))
+                    .set(tokens_valid_after.eq(chrono::Utc::now().naive_utc()))
+                    .execute(c)?;
+                sessions::revoke_all(c, vec![user_id])?;
+                Ok(())
+            })
+        })
+        .await;
+    respond(r, "User Not Found")
+}
+
+/// Turn off two-factor authentication for someone who has lost their authenticator and recovery codes.
+/// Staff will be asked to enrol again the next time they log in.
+#[delete("/api/admin/users/<user_id>/mfa")]
+async fn reset_mfa(
+    admin: Result<models::Admin, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    user_id: i32,
+) -> models::Response {
+    if let Err(e) = admin {
+        return e;
+    }
+    let admin = admin.unwrap();
+
+    let event = recorder.event(Some(admin.0.sub), Some(user_id), "admin.mfa.reset");
+    let r = conn
+        .run(move |c| {
+            update_user(c, event, user_id, |_| {
+                {
+                    use crate::schema::users::dsl::*;
+                    diesel::update(users.filter(id.eq(user_id)))
+                        .set((
+                            totp_secret.eq(None::<String>),
+                            totp_enabled_at.eq(None::<chrono::NaiveDateTime>),
+                            totp_last_step.eq(0),
+                        ))
+                        .execute(c)?;
+                }
+                use crate::schema::recovery_codes::dsl::*;
+                diesel::delete(recovery_codes.filter(usr_id.eq(user_id))).execute(c)?;
+                Ok(())
+            })
+        })
+        .await;
+    respond(r, "User Not Found")
+}
+
+/// List scores which have been flagged as suspicious and haven't been voided yet
+#[get("/api/admin/scores/flagged?<offset>&<limit>")]
+async fn get_flagged_scores(
+    admin: Result<models::Admin, models::Response>,
+    conn: UsersDbConn,
+    offset: Option<i64>,
+    limit: Option<i64>,
+) -> models::Response {
+    if let Err(e) = admin {
+        return e;
+    }
+    let offset: i64 = offset.unwrap_or(0).abs();
+    let limit: i64 = limit.unwrap_or(100).abs();
+
+    let r = conn
+        .run(move |c| {
+            use crate::schema::scores::dsl::*;
+            scores
+                .filter(flagged.eq(true))
+                .filter(voided.eq(false))
+                .order(id.asc())
+                .limit(limit)
+                .offset(offset)
+                .load::<models::Score>(c)
+                .map(Some)
+        })
+        .await;
+    respond(r, "")
+}
+
+/// Approve a flagged score, so it counts towards the leaderboard and the user's stars
+#[post("/api/admin/scores/<score_id>/approve")]
+async fn approve_score(
+    admin: Result<models::Admin, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    score_id: i32,
+) -> models::Response {
+    if let Err(e) = admin {
+        return e;
+    }
+    let admin = admin.unwrap();
+
+    let r = conn
+        .run(move |c| {
+            c.transaction(|| {
+                use crate::schema::scores::dsl::*;
+                let before = match scores
+                    .filter(id.eq(score_id))
+                    .first::<models::Score>(c)
+                    .optional()?
+                {
+                    Some(s) => s,
+                    None => return Ok(None),
+                };
+                let after: models::Score = diesel::update(scores.filter(id.eq(score_id)))
+                    .set(flagged.eq(false))
+                    .get_result(c)?;
+                recorder
+                    .event(Some(admin.0.sub), Some(before.usr_id), "admin.score.approve")
+                    .before(&before)
+                    .after(&after)
+                    .save(c)?;
+                Ok(Some(after))
+            })
+        })
+        .await;
+    respond(r, "Score Not Found")
+}
+
+/// Void a score, so it no longer counts towards the leaderboard or the user's stars
+#[post("/api/admin/scores/<score_id>/void")]
+async fn void_score(
+    admin: Result<models::Admin, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    score_id: i32,
+) -> models::Response {
+    if let Err(e) = admin {
+        return e;
+    }
+    let admin = admin.unwrap();
+
+    let r = conn
+        .run(move |c| {
+            c.transaction(|| {
+                use crate::schema::scores::dsl::*;
+                let before = match scores
+                    .filter(id.eq(score_id))
+                    .first::<models::Score>(c)
+                    .optional()?
+                {
+                    Some(s) => s,
+                    None => return Ok(None),
+                };
+                let after: models::Score = diesel::update(scores.filter(id.eq(score_id)))
+                    .set(voided.eq(true))
+                    .get_result(c)?;
+                recorder
+                    .event(Some(admin.0.sub), Some(before.usr_id), "admin.score.void")
+                    .before(&before)
+                    .after(&after)
+                    .save(c)?;
+                Ok(Some(after))
+            })
+        })
+        .await;
+    respond(r, "Score Not Found")
+}
+
+/// Permanently delete a score
+#[delete("/api/admin/scores/<score_id>")]
+async fn delete_score(
+    admin: Result<models::Admin, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    score_id: i32,
+) -> models::Response {
+    if let Err(e) = admin {
+        return e;
+    }
+    let admin = admin.unwrap();
+
+    let r = conn
+        .run(move |c| {
+            c.transaction(|| {
+                use crate::schema::scores::dsl::*;
+                let before: Option<models::Score> = diesel::delete(scores.filter(id.eq(score_id)))
+                    .get_result(c)
+                    .optional()?;
+                if let Some(before) = &before {
+                    recorder
+                        .event(Some(admin.0.sub), Some(before.usr_id), "admin.score.delete")
+                        .before(before)
+                        .save(c)?;
+                }
+                Ok(before.map(|s| format!("Score {} deleted", s.id)))
+            })
+        })
+        .await;
+    respond(r, "Score Not Found")
+}
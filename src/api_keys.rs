@@ -0,0 +1,293 @@
+use crate::openapi::{Auth, Operation};
+use crate::{audit, common, models, validation, UsersDbConn};
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::json::Json;
+
+/// Put in front of every key, so they are easy to spot if they are leaked
+const KEY_PREFIX: &str = "kk_";
+const MAX_NAME_LENGTH: usize = 64;
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![create_api_key, list_api_keys, revoke_api_key, issue_token]
+}
+
+pub fn operations() -> Vec<Operation> {
+    vec![
+        Operation::new("create_api_key", "Create an API key")
+            .auth(Auth::Token)
+            .body::<models::NewApiKey>()
+            .returns::<models::CreatedApiKey>(201, "The key, which is only shown this once")
+            .errors(&[400, 403, 404]),
+        Operation::new("list_api_keys", "List your API keys")
+            .auth(Auth::Token)
+            .returns::<Vec<models::ApiKey>>(200, "Every key, including revoked ones"),
+        Operation::new("revoke_api_key", "Revoke an API key")
+            .auth(Auth::Token)
+            .param::<i32>("key_id")
+            .returns::<models::ApiKey>(200, "The revoked key")
+            .errors(&[404]),
+        Operation::new("issue_token", "Get a token for an API key")
+            .auth(Auth::ApiKey)
+            .returns::<models::ApiKeyToken>(200, "A token limited to the key's scopes"),
+    ]
+}
+
+/// The key in the `X-Api-Key` header
+struct ApiKeyHeader(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiKeyHeader {
+    type Error = models::Response;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, models::Response> {
+        match req.headers().get_one("X-Api-Key") {
+            Some(key) => request::Outcome::Success(ApiKeyHeader(key.to_owned())),
+            None => request::Outcome::Failure((
+                Status::Unauthorized,
+                models::ResponseBuilder {
+                    data: "X-Api-Key Header Not Present",
+                    status: Status::Unauthorized,
+                }
+                .build(),
+            )),
+        }
+    }
+}
+
+/// Create an API key for the signed in teacher or admin. The key is only returned this once, as only its hash is kept.
+#[post("/api/v1/api-keys", data = "<new_key>", format = "application/json")]
+async fn create_api_key(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    new_key: Json<models::NewApiKey>,
+) -> Result<models::Response, models::Response> {
+    let token = token?;
+    token.deny_guest()?;
+    let user = match conn.run(move |c| common::find_user(c, token.sub)).await {
+        Ok(Some(u)) => u,
+        Ok(None) => {
+            return Err(models::ResponseBuilder {
+                data: "User Not Found",
+                status: Status::NotFound,
+            }
+            .build())
+        }
+        Err(e) => return Err(models::Response::database_error(e)),
+    };
+    if !user.is_staff() {
+        return Err(models::ResponseBuilder {
+            data: "Only teachers and admins can create API keys",
+            status: Status::Forbidden,
+        }
+        .build());
+    }
+
+    let new_key = new_key.into_inner();
+    let name = new_key.name.trim().to_owned();
+    let mut scopes = new_key.scopes;
+    scopes.sort();
+    scopes.dedup();
+    let mut errors = validation::Errors::default();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        errors.add("name", format!("Must be between 1 and {} characters", MAX_NAME_LENGTH));
+    }
+    if scopes.is_empty() {
+        errors.add("scopes", "Must have at least one scope".into());
+    }
+    for scope in &scopes {
+        if !models::SCOPES.contains(&scope.as_str()) {
+            errors.add(
+                "scopes",
+                format!("{} is not a scope, use one of {}", scope, models::SCOPES.join(", ")),
+            );
+        } else if scope == models::SCOPE_ADMIN && user.role != models::ROLE_ADMIN {
+            errors.add("scopes", "Only admins can create keys with the admin scope".into());
+        }
+    }
+    errors.into_result()?;
+
+    let secret = common::generate_code(32);
+    let hashed = match common::hash_string_with_salt(secret.clone()) {
+        Ok(h) => h,
+        Err(e) => {
+            return Err(models::ResponseBuilder {
+                data: format!("Unable to hash key {}", e.to_string()),
+                status: Status::InternalServerError,
+            }
+            .build())
+        }
+    };
+    let user_id = user.id;
+    let r: Result<models::ApiKey, diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                let key: models::ApiKey = diesel::insert_into(crate::schema::api_keys::table)
+                    .values(models::InsertableApiKey {
+                        usr_id: user_id,
+                        name,
+                        secret: hashed,
+                        scopes,
+                    })
+                    .get_result(c)?;
+                recorder
+                    .event(Some(user_id), Some(user_id), "api_key.create")
+                    .after(&key)
+                    .save(c)?;
+                Ok(key)
+            })
+        })
+        .await;
+    let api_key = r.map_err(models::Response::database_error)?;
+
+    Ok(models::ResponseBuilder {
+        data: models::CreatedApiKey {
+            key: format!("{}{}.{}", KEY_PREFIX, api_key.id, secret),
+            api_key,
+        },
+        status: Status::Created,
+    }
+    .build())
+}
+
+/// List the API keys belonging to the signed in user, including revoked ones
+#[get("/api/v1/api-keys")]
+async fn list_api_keys(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+) -> Result<models::Response, models::Response> {
+    let token = token?;
+    let r = conn
+        .run(move |c| {
+            use crate::schema::api_keys::dsl::*;
+            api_keys
+                .filter(usr_id.eq(token.sub))
+                .order(created_at.desc())
+                .load::<models::ApiKey>(c)
+        })
+        .await
+        .map_err(models::Response::database_error)?;
+    Ok(models::ResponseBuilder {
+        data: r,
+        status: Status::Ok,
+    }
+    .build())
+}
+
+/// Revoke an API key, which also stops any tokens issued for it from working
+#[delete("/api/v1/api-keys/<key_id>")]
+async fn revoke_api_key(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    key_id: i32,
+) -> Result<models::Response, models::Response> {
+    let token = token?;
+    let r: Result<Option<models::ApiKey>, diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                use crate::schema::api_keys::dsl::*;
+                let key = diesel::update(
+                    api_keys
+                        .filter(id.eq(key_id))
+                        .filter(usr_id.eq(token.sub))
+                        .filter(revoked_at.is_null()),
+                )
+                .set(revoked_at.eq(Some(chrono::Utc::now().naive_utc())))
+                .get_result::<models::ApiKey>(c)
+                .optional()?;
+                if let Some(key) = &key {
+                    recorder
+                        .event(Some(token.sub), Some(token.sub), "api_key.revoke")
+                        .after(key)
+                        .save(c)?;
+                }
+                Ok(key)
+            })
+        })
+        .await;
+
+    match r.map_err(models::Response::database_error)? {
+        Some(key) => Ok(models::ResponseBuilder {
+            data: key,
+            status: Status::Ok,
+        }
+        .build()),
+        None => Err(models::ResponseBuilder {
+            data: "API Key Not Found",
+            status: Status::NotFound,
+        }
+        .build()),
+    }
+}
+
+/// Exchange the API key in the `X-Api-Key` header for a short lived token limited to the key's scopes
+#[post("/api/v1/api-keys/token")]
+async fn issue_token(
+    key: Result<ApiKeyHeader, models::Response>,
+    conn: UsersDbConn,
+) -> Result<models::Response, models::Response> {
+    let key = key?.0;
+    let invalid = || {
+        models::ResponseBuilder {
+            data: "Invalid API Key",
+            status: Status::Unauthorized,
+        }
+        .build()
+    };
+    let (key_id, secret) = match key.strip_prefix(KEY_PREFIX).and_then(common::split_token) {
+        Some((key_id, secret)) => (key_id, secret.to_owned()),
+        None => return Err(invalid()),
+    };
+
+    let r: Result<Option<(models::ApiKey, models::User)>, diesel::result::Error> = conn
+        .run(move |c| {
+            use crate::schema::api_keys::dsl::*;
+            let key = api_keys
+                .filter(id.eq(key_id))
+                .filter(revoked_at.is_null())
+                .first::<models::ApiKey>(c)
+                .optional()?;
+            let key = match key {
+                Some(k) => k,
+                None => return Ok(None),
+            };
+            Ok(common::find_user(c, key.usr_id)?.map(|u| (key, u)))
+        })
+        .await;
+    let (api_key, user) = match r.map_err(models::Response::database_error)? {
+        Some((k, u)) if u.deleted_at.is_none() => (k, u),
+        _ => return Err(invalid()),
+    };
+    match common::compare_hashed_strings(secret, api_key.secret.clone()) {
+        Ok(true) => {}
+        Ok(false) => return Err(invalid()),
+        Err(e) => {
+            return Err(models::ResponseBuilder {
+                data: format!("Failed to compare hashes {}", e.to_string()),
+                status: Status::InternalServerError,
+            }
+            .build())
+        }
+    }
+
+    let key_id = api_key.id;
+    conn.run(move |c| {
+        use crate::schema::api_keys::dsl::*;
+        diesel::update(api_keys.filter(id.eq(key_id)))
+            .set(last_used_at.eq(Some(chrono::Utc::now().naive_utc())))
+            .execute(c)
+    })
+    .await
+    .map_err(models::Response::database_error)?;
+
+    Ok(models::ResponseBuilder {
+        data: models::ApiKeyToken {
+            token: models::Claims::new_api_key_token(&api_key, user.role),
+            expires_in: models::API_KEY_TOKEN_MINUTES * 60,
+        },
+        status: Status::Ok,
+    }
+    .build())
+}
@@ -0,0 +1,97 @@
+use crate::models;
+use diesel::prelude::*;
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::Serialize;
+
+/// Records audit events for a single request, filling in where the request came from
+#[derive(Clone)]
+pub struct Recorder {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Recorder {
+    type Error = ();
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        request::Outcome::Success(Recorder {
+            ip: req.client_ip().map(|i| i.to_string()),
+            user_agent: req.headers().get_one("User-Agent").map(|u| u.to_owned()),
+        })
+    }
+}
+
+impl Recorder {
+    /// Start a new event, `actor` is the user making the request and `target` the user it affects
+    pub fn event(&self, actor: Option<i32>, target: Option<i32>, action: &str) -> Event {
+        Event(models::InsertableAuditEvent {
+            actor_id: actor,
+            target_id: target,
+            action: action.to_owned(),
+            before: None,
+            after: None,
+            ip: self.ip.clone(),
+            user_agent: self.user_agent.clone(),
+        })
+    }
+}
+
+/// An audit event which hasn't been saved yet
+pub struct Event(models::InsertableAuditEvent);
+
+impl Event {
+    /// The state of whatever was changed before the change
+    pub fn before<T: Serialize>(mut self, value: &T) -> Event {
+        self.0.before = serde_json::to_value(value).ok();
+        self
+    }
+
+    /// The state of whatever was changed after the change
+    pub fn after<T: Serialize>(mut self, value: &T) -> Event {
+        self.0.after = serde_json::to_value(value).ok();
+        self
+    }
+
+    /// Save the event, this should be done within the same transaction as the change where possible
+    pub fn save(self, c: &diesel::PgConnection) -> Result<(), diesel::result::Error> {
+        record(c, self.0)
+    }
+}
+
+/// Save an event to the audit log
+pub fn record(
+    c: &diesel::PgConnection,
+    event: models::InsertableAuditEvent,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::audit_events;
+    diesel::insert_into(audit_events::table)
+        .values(event)
+        .execute(c)?;
+    Ok(())
+}
+
+/// Load audit events matching the filters, newest first
+pub fn search(
+    c: &diesel::PgConnection,
+    filter: models::AuditFilter,
+) -> Result<Vec<models::AuditEvent>, diesel::result::Error> {
+    use crate::schema::audit_events::dsl::*;
+    let mut query = audit_events.into_boxed();
+    if let Some(user) = filter.usr_id {
+        query = query.filter(target_id.eq(user).or(actor_id.eq(user)));
+    }
+    if let Some(a) = filter.action {
+        query = query.filter(action.eq(a));
+    }
+    if let Some(from) = filter.from {
+        query = query.filter(created_at.ge(from));
+    }
+    if let Some(to) = filter.to {
+        query = query.filter(created_at.lt(to));
+    }
+    query
+        .order((created_at.desc(), id.desc()))
+        .limit(filter.limit)
+        .offset(filter.offset)
+        .load::<models::AuditEvent>(c)
+}
@@ -0,0 +1,329 @@
+use api::{achievements, common, models, validation, COSTUMES};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Maintenance tasks for the Kemu Kupu api, run against the database in `DATABASE_URL`
+#[derive(StructOpt)]
+#[structopt(name = "kemu-admin")]
+struct Opt {
+    /// Show what would change, without saving anything
+    #[structopt(long, global = true)]
+    dry_run: bool,
+    /// Print the result as JSON, for use in scripts
+    #[structopt(long, global = true)]
+    json: bool,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Create a teacher account
+    CreateTeacher {
+        #[structopt(long)]
+        usr: String,
+        #[structopt(long)]
+        nickname: String,
+        /// A password is generated and printed if one isn't given
+        #[structopt(long)]
+        password: Option<String>,
+        /// Create an admin rather than a teacher
+        #[structopt(long)]
+        admin: bool,
+    },
+    /// Give a user a costume, without spending any of their stars
+    GrantCostume {
+        #[structopt(long)]
+        usr: String,
+        #[structopt(long)]
+        costume: String,
+    },
+    /// Create student accounts from a CSV file with the columns `usr`, `nickname` and optionally `password`
+    ImportRoster {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Give users any achievements in `./achievement.toml` they have earned but are missing
+    EvaluateAchievements {
+        /// Only check this user, rather than everyone
+        #[structopt(long)]
+        usr: Option<String>,
+    },
+}
+
+/// A single change made by a command
+#[derive(Serialize, Debug)]
+struct Change {
+    action: &'static str,
+    usr: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+/// Everything a command changed, printed once it has finished
+#[derive(Serialize)]
+struct Report {
+    dry_run: bool,
+    changes: Vec<Change>,
+}
+
+#[derive(Debug)]
+enum CliError {
+    Invalid(String),
+    Database(diesel::result::Error),
+    /// Returned to roll back the transaction at the end of a dry run
+    DryRun(Vec<Change>),
+}
+
+impl From<diesel::result::Error> for CliError {
+    fn from(e: diesel::result::Error) -> CliError {
+        CliError::Database(e)
+    }
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CliError::Invalid(e) => write!(f, "{}", e),
+            CliError::Database(e) => write!(f, "Failed to query the database due to error {}", e),
+            CliError::DryRun(_) => write!(f, "Dry run"),
+        }
+    }
+}
+
+/// A row of a class roster
+#[derive(Deserialize)]
+struct RosterRow {
+    usr: String,
+    nickname: String,
+    password: Option<String>,
+}
+
+/// Check a new account against `./policy.toml`, and that the username is free
+fn check_new_user(
+    c: &diesel::PgConnection,
+    usr: &str,
+    nickname: &str,
+    password: &str,
+) -> Result<(), CliError> {
+    let mut problems = vec![];
+    for (field, result) in [
+        ("usr", validation::validate_username(usr)),
+        ("pwd", validation::validate_password(password, usr)),
+        ("nickname", validation::validate_nickname(nickname)),
+    ] {
+        if let Err(messages) = result {
+            problems.push(format!("{}: {}", field, messages.join(", ")));
+        }
+    }
+    if common::find_user_by_name(c, usr.to_owned())?.is_some() {
+        problems.push("usr: Username Taken".into());
+    }
+    if !problems.is_empty() {
+        return Err(CliError::Invalid(format!("{} is invalid, {}", usr, problems.join("; "))));
+    }
+    Ok(())
+}
+
+/// Create an account with the given role
+fn create_user(
+    c: &diesel::PgConnection,
+    usr: String,
+    nickname: String,
+    password: &str,
+    role: &str,
+) -> Result<models::User, CliError> {
+    use api::schema::users;
+    let hashed = common::hash_string_with_salt(password.to_owned())
+        .map_err(|e| CliError::Invalid(format!("Unable to hash password {}", e)))?;
+    let user: models::User = diesel::insert_into(users::table)
+        .values(models::NewUser {
+            usr,
+            pwd: hashed,
+            nickname,
+            pending_nickname: None,
+            is_guest: false,
+            current_costume: "default".into(),
+            costumes: vec!["default".into()],
+            achievements: vec![],
+        })
+        .get_result(c)?;
+    Ok(diesel::update(users::table.filter(users::id.eq(user.id)))
+        .set(users::role.eq(role))
+        .get_result(c)?)
+}
+
+fn find_user(c: &diesel::PgConnection, usr: &str) -> Result<models::User, CliError> {
+    common::find_user_by_name(c, usr.to_owned())?
+        .ok_or_else(|| CliError::Invalid(format!("User {} not found", usr)))
+}
+
+fn create_teacher(
+    c: &diesel::PgConnection,
+    usr: String,
+    nickname: String,
+    password: Option<String>,
+    admin: bool,
+) -> Result<Vec<Change>, CliError> {
+    let generated = password.is_none();
+    let password = password.unwrap_or_else(|| common::generate_code(12));
+    check_new_user(c, &usr, &nickname, &password)?;
+    let role = if admin { models::ROLE_ADMIN } else { models::ROLE_TEACHER };
+    let user = create_user(c, usr, nickname, &password, role)?;
+    Ok(vec![Change {
+        action: "create",
+        usr: user.usr,
+        detail: Some(if generated {
+            format!("{} with password {}", role, password)
+        } else {
+            role.to_owned()
+        }),
+    }])
+}
+
+fn grant_costume(
+    c: &diesel::PgConnection,
+    usr: String,
+    costume: String,
+) -> Result<Vec<Change>, CliError> {
+    if !COSTUMES.contains_key(&costume) {
+        return Err(CliError::Invalid(format!("Costume {} does not exist", costume)));
+    }
+    let user = find_user(c, &usr)?;
+    if user.costumes.iter().any(|owned| owned.name == costume) {
+        return Ok(vec![]);
+    }
+    let mut owned: Vec<String> = user.costumes.into_iter().map(|c| c.name).collect();
+    owned.push(costume.clone());
+    {
+        use api::schema::users::dsl::*;
+        diesel::update(users.filter(id.eq(user.id)))
+            .set(costumes.eq(owned))
+            .execute(c)?;
+    }
+    common::record_unlock(c, user.id, models::UNLOCK_COSTUME, &costume)?;
+    Ok(vec![Change {
+        action: "grant-costume",
+        usr: user.usr,
+        detail: Some(costume),
+    }])
+}
+
+fn import_roster(c: &diesel::PgConnection, file: PathBuf) -> Result<Vec<Change>, CliError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(&file)
+        .map_err(|e| CliError::Invalid(format!("Unable to read {} {}", file.display(), e)))?;
+    let mut changes = vec![];
+    for (line, row) in reader.deserialize::<RosterRow>().enumerate() {
+        //The header is line 1
+        let row = row.map_err(|e| CliError::Invalid(format!("Line {}: {}", line + 2, e)))?;
+        let generated = row.password.as_deref().map(str::is_empty).unwrap_or(true);
+        let password = match row.password {
+            Some(p) if !p.is_empty() => p,
+            _ => common::generate_code(8),
+        };
+        check_new_user(c, &row.usr, &row.nickname, &password)
+            .map_err(|e| CliError::Invalid(format!("Line {}: {}", line + 2, e)))?;
+        let user = create_user(c, row.usr, row.nickname, &password, models::ROLE_STUDENT)?;
+        changes.push(Change {
+            action: "create",
+            usr: user.usr,
+            detail: if generated {
+                Some(format!("student with password {}", password))
+            } else {
+                None
+            },
+        });
+    }
+    Ok(changes)
+}
+
+fn evaluate_achievements(
+    c: &diesel::PgConnection,
+    usr: Option<String>,
+) -> Result<Vec<Change>, CliError> {
+    let users = match usr {
+        Some(usr) => vec![find_user(c, &usr)?],
+        None => {
+            use api::schema::users::dsl::*;
+            users
+                .filter(deleted_at.is_null())
+                .order(id.asc())
+                .load::<models::User>(c)?
+        }
+    };
+    let mut changes = vec![];
+    for user in users {
+        for name in achievements::evaluate(c, &user)? {
+            changes.push(Change {
+                action: "grant-achievement",
+                usr: user.usr.clone(),
+                detail: Some(name),
+            });
+        }
+    }
+    Ok(changes)
+}
+
+fn run(c: &diesel::PgConnection, command: Command) -> Result<Vec<Change>, CliError> {
+    match command {
+        Command::CreateTeacher {
+            usr,
+            nickname,
+            password,
+            admin,
+        } => create_teacher(c, usr, nickname, password, admin),
+        Command::GrantCostume { usr, costume } => grant_costume(c, usr, costume),
+        Command::ImportRoster { file } => import_roster(c, file),
+        Command::EvaluateAchievements { usr } => evaluate_achievements(c, usr),
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    let url = common::env_opt("DATABASE_URL").expect("Env var DATABASE_URL not set!");
+    let c = diesel::PgConnection::establish(&url).expect("Unable to connect to the database");
+
+    //Everything is done in one transaction, so a failure part way through changes nothing
+    let dry_run = opt.dry_run;
+    let command = opt.command;
+    let r = c.transaction::<_, CliError, _>(|| {
+        let changes = run(&c, command)?;
+        if dry_run {
+            return Err(CliError::DryRun(changes));
+        }
+        Ok(changes)
+    });
+    let changes = match r {
+        Ok(changes) | Err(CliError::DryRun(changes)) => changes,
+        Err(e) => {
+            if opt.json {
+                println!("{}", serde_json::json!({ "error": e.to_string() }));
+            } else {
+                eprintln!("{}", e);
+            }
+            std::process::exit(1);
+        }
+    };
+
+    let report = Report { dry_run, changes };
+    if opt.json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        return;
+    }
+    for change in &report.changes {
+        match &change.detail {
+            Some(detail) => println!("{} {}: {}", change.action, change.usr, detail),
+            None => println!("{} {}", change.action, change.usr),
+        }
+    }
+    if report.changes.is_empty() {
+        println!("Nothing to change");
+    }
+    if report.dry_run {
+        println!("Dry run, nothing was saved");
+    }
+}
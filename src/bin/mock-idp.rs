@@ -0,0 +1,186 @@
+//! A stand-in OpenID Connect provider, for trying out and testing sign in without a real school identity provider.
+//! Everyone who reaches `/authorize` is signed straight in as the subject in `login_hint`, or `teacher` if there isn't one.
+//! ID tokens are signed with HS256 using the client secret, so there are no keys to publish.
+#[macro_use]
+extern crate rocket;
+
+use api::common;
+use rocket::form::Form;
+use rocket::http::Status;
+use rocket::response::Redirect;
+use rocket::serde::json::{json, Json, Value};
+use rocket::State;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Set from `MOCK_IDP_ISSUER`, `MOCK_IDP_CLIENT_ID` and `MOCK_IDP_CLIENT_SECRET`
+struct Config {
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+}
+
+/// An authorization code waiting to be exchanged for tokens
+struct PendingCode {
+    client_id: String,
+    redirect_uri: String,
+    code_challenge: String,
+    nonce: Option<String>,
+    subject: String,
+}
+
+#[derive(Default)]
+struct Codes(Mutex<HashMap<String, PendingCode>>);
+
+#[derive(FromForm)]
+struct TokenRequest {
+    grant_type: String,
+    code: String,
+    redirect_uri: String,
+    client_id: String,
+    client_secret: Option<String>,
+    code_verifier: String,
+}
+
+fn token_error(error: &str) -> (Status, Json<Value>) {
+    (Status::BadRequest, Json(json!({ "error": error })))
+}
+
+#[get("/.well-known/openid-configuration")]
+fn discovery(config: &State<Config>) -> Json<Value> {
+    Json(json!({
+        "issuer": config.issuer,
+        "authorization_endpoint": format!("{}/authorize", config.issuer),
+        "token_endpoint": format!("{}/token", config.issuer),
+        "jwks_uri": format!("{}/jwks", config.issuer),
+        "response_types_supported": ["code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["HS256"],
+        "code_challenge_methods_supported": ["S256"],
+    }))
+}
+
+#[get("/jwks")]
+fn jwks() -> Json<Value> {
+    Json(json!({ "keys": [] }))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/authorize?<response_type>&<client_id>&<redirect_uri>&<state>&<nonce>&<code_challenge>&<code_challenge_method>&<login_hint>")]
+fn authorize(
+    config: &State<Config>,
+    codes: &State<Codes>,
+    response_type: String,
+    client_id: String,
+    redirect_uri: String,
+    state: Option<String>,
+    nonce: Option<String>,
+    code_challenge: String,
+    code_challenge_method: String,
+    login_hint: Option<String>,
+) -> Result<Redirect, (Status, String)> {
+    if response_type != "code" {
+        return Err((Status::BadRequest, "Only the code flow is supported".into()));
+    }
+    if client_id != config.client_id {
+        return Err((Status::BadRequest, format!("Unknown client {}", client_id)));
+    }
+    if code_challenge_method != "S256" {
+        return Err((Status::BadRequest, "Only S256 code challenges are supported".into()));
+    }
+    let code = common::generate_code(32);
+    let mut params = vec![("code", code.clone())];
+    if let Some(state) = state {
+        params.push(("state", state));
+    }
+    let url = reqwest::Url::parse_with_params(&redirect_uri, &params)
+        .map_err(|e| (Status::BadRequest, format!("Invalid redirect_uri {}", e)))?;
+    codes.0.lock().unwrap().insert(
+        code,
+        PendingCode {
+            client_id,
+            redirect_uri,
+            code_challenge,
+            nonce,
+            subject: login_hint.unwrap_or_else(|| "teacher".into()),
+        },
+    );
+    Ok(Redirect::to(url.to_string()))
+}
+
+#[post("/token", data = "<form>")]
+fn token(
+    config: &State<Config>,
+    codes: &State<Codes>,
+    form: Form<TokenRequest>,
+) -> Result<Json<Value>, (Status, Json<Value>)> {
+    let form = form.into_inner();
+    if form.grant_type != "authorization_code" {
+        return Err(token_error("unsupported_grant_type"));
+    }
+    if form.client_id != config.client_id
+        || form.client_secret.as_deref() != Some(config.client_secret.as_str())
+    {
+        return Err((Status::Unauthorized, Json(json!({ "error": "invalid_client" }))));
+    }
+    //Codes can only be used once, even if the request is refused
+    let pending = match codes.0.lock().unwrap().remove(&form.code) {
+        Some(p) => p,
+        None => return Err(token_error("invalid_grant")),
+    };
+    let challenge = base64::encode_config(
+        Sha256::digest(form.code_verifier.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    );
+    if pending.client_id != form.client_id
+        || pending.redirect_uri != form.redirect_uri
+        || pending.code_challenge != challenge
+    {
+        return Err(token_error("invalid_grant"));
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+    let claims = json!({
+        "iss": config.issuer,
+        "sub": pending.subject,
+        "aud": config.client_id,
+        "iat": now,
+        "exp": now + 300,
+        "nonce": pending.nonce,
+        "email": format!("{}@example.school", pending.subject),
+        "email_verified": true,
+        "name": format!("Mock {}", pending.subject),
+        "preferred_username": pending.subject,
+    });
+    let id_token = jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &claims,
+        &jsonwebtoken::EncodingKey::from_secret(config.client_secret.as_bytes()),
+    )
+    .map_err(|_| (Status::InternalServerError, Json(json!({ "error": "server_error" }))))?;
+    Ok(Json(json!({
+        "access_token": common::generate_code(32),
+        "token_type": "Bearer",
+        "expires_in": 300,
+        "id_token": id_token,
+    })))
+}
+
+#[launch]
+fn rocket() -> _ {
+    let port: u16 = common::env_or("MOCK_IDP_PORT", 8001);
+    let config = Config {
+        issuer: common::env_or("MOCK_IDP_ISSUER", format!("http://localhost:{}", port)),
+        client_id: common::env_or("MOCK_IDP_CLIENT_ID", "kemu".to_owned()),
+        client_secret: common::env_or("MOCK_IDP_CLIENT_SECRET", "mock-secret".to_owned()),
+    };
+    rocket::custom(rocket::Config::figment().merge(("port", port)))
+        .mount("/", routes![discovery, jwks, authorize, token])
+        .manage(config)
+        .manage(Codes::default())
+}
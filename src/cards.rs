@@ -0,0 +1,176 @@
+use image::GenericImageView;
+use printpdf::{
+    Color, Greyscale, Image, IndirectFontRef, Line, Mm, PdfDocument, PdfDocumentReference,
+    PdfLayerReference, Point,
+};
+use std::collections::HashMap;
+use std::io::BufWriter;
+
+const PAGE_WIDTH: f64 = 210.0;
+const PAGE_HEIGHT: f64 = 297.0;
+const MARGIN: f64 = 10.0;
+/// Space between the edge of a card and what's printed on it
+const PADDING: f64 = 4.0;
+/// Blank modules around a QR code, so it can be scanned next to other printing
+const QR_QUIET_ZONE: usize = 2;
+const PT_TO_MM: f64 = 0.3528;
+
+/// The login details printed on a single card
+pub struct LoginCard {
+    pub nickname: String,
+    pub usr: String,
+    /// Only known when the password has just been set
+    pub pwd: Option<String>,
+    pub costume: String,
+    /// Encoded in a QR code on the card
+    pub login_link: Option<String>,
+}
+
+/// Add a font from `./fonts/` to the document. The fonts built into PDF readers can't show macrons,
+/// so one with every letter used in te reo Māori is embedded instead.
+fn add_font(doc: &PdfDocumentReference, file: &str) -> Result<IndirectFontRef, String> {
+    let path = format!("./fonts/{}", file);
+    let data = std::fs::read(&path).map_err(|e| format!("Unable to load font {} {}", path, e))?;
+    doc.add_external_font(data.as_slice()).map_err(|e| e.to_string())
+}
+
+/// Load a costume image from `./static/docs/costume/`, flattened onto white as PDF images have no transparency
+fn load_costume(name: &str) -> Option<image::DynamicImage> {
+    let path = format!("./static/docs/costume/{}.png", name);
+    let img = image::open(path)
+        .or_else(|_| image::open("./static/docs/costume/default.png"))
+        .ok()?
+        .to_rgba8();
+    let flat = image::RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let p = img.get_pixel(x, y);
+        let a = p[3] as u32;
+        let blend = |v: u8| ((v as u32 * a + 255 * (255 - a)) / 255) as u8;
+        image::Rgb([blend(p[0]), blend(p[1]), blend(p[2])])
+    });
+    Some(image::DynamicImage::ImageRgb8(flat))
+}
+
+fn fill_colour(layer: &PdfLayerReference, level: f64) {
+    layer.set_fill_color(Color::Greyscale(Greyscale::new(level, None)));
+}
+
+fn rect(x: f64, y: f64, w: f64, h: f64, fill: bool) -> Line {
+    Line {
+        points: vec![
+            (Point::new(Mm(x), Mm(y)), false),
+            (Point::new(Mm(x + w), Mm(y)), false),
+            (Point::new(Mm(x + w), Mm(y + h)), false),
+            (Point::new(Mm(x), Mm(y + h)), false),
+        ],
+        is_closed: true,
+        has_fill: fill,
+        has_stroke: !fill,
+        is_clipping_path: false,
+    }
+}
+
+/// Draw a QR code as filled squares, with its bottom left corner at (x, y)
+fn draw_qr(layer: &PdfLayerReference, data: &str, x: f64, y: f64, size: f64) -> Result<(), String> {
+    let code = qrcode::QrCode::new(data.as_bytes()).map_err(|e| e.to_string())?;
+    let width = code.width();
+    let module = size / (width + QR_QUIET_ZONE * 2) as f64;
+    fill_colour(layer, 0.0);
+    for row in 0..width {
+        //Join dark modules next to each other into one rectangle to keep the file small
+        let mut col = 0;
+        while col < width {
+            if code[(col, row)] != qrcode::Color::Dark {
+                col += 1;
+                continue;
+            }
+            let start = col;
+            while col < width && code[(col, row)] == qrcode::Color::Dark {
+                col += 1;
+            }
+            layer.add_shape(rect(
+                x + (start + QR_QUIET_ZONE) as f64 * module,
+                y + size - (row + QR_QUIET_ZONE + 1) as f64 * module,
+                (col - start) as f64 * module,
+                module,
+                true,
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Render login cards onto A4 pages, `per_page` to a page, ready to be printed and cut out.
+/// Cards are laid out in two columns unless there is only one per page.
+pub fn render(title: &str, cards: &[LoginCard], per_page: usize) -> Result<Vec<u8>, String> {
+    let columns = if per_page == 1 { 1 } else { 2 };
+    let rows = (per_page + columns - 1) / columns;
+    let card_width = (PAGE_WIDTH - MARGIN * 2.0) / columns as f64;
+    let card_height = (PAGE_HEIGHT - MARGIN * 2.0) / rows as f64;
+    //The costume sits in a square on the left of the card, and the QR code in one on the right
+    let square = (card_height - PADDING * 2.0).min(card_width / 5.0);
+    let name_size = (square / 3.0 / PT_TO_MM).min(16.0);
+    let text_size = (name_size * 0.6).max(8.0);
+
+    let (doc, page, layer) = PdfDocument::new(title, Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Cards");
+    let regular = add_font(&doc, "DejaVuSans.ttf")?;
+    let bold = add_font(&doc, "DejaVuSans-Bold.ttf")?;
+    let mut images: HashMap<String, Option<image::DynamicImage>> = HashMap::new();
+    let mut layer = doc.get_page(page).get_layer(layer);
+
+    for (i, card) in cards.iter().enumerate() {
+        let slot = i % per_page;
+        if i > 0 && slot == 0 {
+            let (page, l) = doc.add_page(Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Cards");
+            layer = doc.get_page(page).get_layer(l);
+        }
+        let x = MARGIN + (slot % columns) as f64 * card_width;
+        let y = PAGE_HEIGHT - MARGIN - (slot / columns + 1) as f64 * card_height;
+
+        //Outline to cut along
+        layer.set_outline_color(Color::Greyscale(Greyscale::new(0.6, None)));
+        layer.set_outline_thickness(0.5);
+        layer.add_shape(rect(x, y, card_width, card_height, false));
+
+        let top = y + (card_height + square) / 2.0;
+        let img = images
+            .entry(card.costume.clone())
+            .or_insert_with(|| load_costume(&card.costume));
+        if let Some(img) = img {
+            //Pick the dpi which makes the image exactly fill the square
+            let dpi = img.width() as f64 * 25.4 / square;
+            Image::from_dynamic_image(img).add_to_layer(
+                layer.clone(),
+                Some(Mm(x + PADDING)),
+                Some(Mm(top - square)),
+                None,
+                None,
+                None,
+                Some(dpi),
+            );
+        }
+        if let Some(link) = &card.login_link {
+            draw_qr(&layer, link, x + card_width - PADDING - square, top - square, square)?;
+        }
+
+        let text_x = x + PADDING * 2.0 + square;
+        let line = text_size * PT_TO_MM * 1.6;
+        let mut text_y = top - name_size * PT_TO_MM;
+        fill_colour(&layer, 0.0);
+        layer.use_text(card.nickname.clone(), name_size, Mm(text_x), Mm(text_y), &bold);
+        text_y -= line * 1.5;
+        layer.use_text(format!("Username: {}", card.usr), text_size, Mm(text_x), Mm(text_y), &regular);
+        text_y -= line;
+        let pwd = card.pwd.clone().unwrap_or_else(|| "____________".into());
+        layer.use_text(format!("Password: {}", pwd), text_size, Mm(text_x), Mm(text_y), &regular);
+        if card.login_link.is_some() {
+            text_y -= line;
+            layer.use_text("Or scan the code to log in", text_size * 0.8, Mm(text_x), Mm(text_y), &regular);
+        }
+        fill_colour(&layer, 0.4);
+        layer.use_text(title, text_size * 0.8, Mm(text_x), Mm(y + PADDING), &regular);
+    }
+
+    let mut out = BufWriter::new(Vec::new());
+    doc.save(&mut out).map_err(|e| e.to_string())?;
+    out.into_inner().map_err(|e| e.to_string())
+}
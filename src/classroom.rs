@@ -0,0 +1,848 @@
+use crate::openapi::{Auth, Operation};
+use crate::{
+    audit, cards, common, login_links, metrics, models, moderation, sessions, validation, UsersDbConn,
+};
+use diesel::prelude::*;
+use rocket::http::{ContentType, Status};
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use std::collections::HashSet;
+
+/// The most students that can be imported from a single roster
+const MAX_ROSTER_SIZE: usize = 200;
+/// The options for how many login cards are printed on each page
+const CARDS_PER_PAGE: &[usize] = &[1, 2, 4, 6, 8, 10];
+
+/// How many usernames or passwords are tried for a student before giving up
+const MAX_GENERATE_ATTEMPTS: usize = 100;
+
+const PASSWORD_WORDS: &[&str] = &[
+    "sunny", "happy", "brave", "clever", "speedy", "jolly", "bright", "kind", "busy", "fluffy",
+    "tiny", "giant", "lucky", "silly", "swift", "calm", "shiny", "proud", "funny", "gentle",
+    "quiet", "bouncy", "cosy", "sleepy", "wild",
+];
+const PASSWORD_ANIMALS: &[&str] = &[
+    "kiwi", "tui", "kea", "weka", "ruru", "pukeko", "kereru", "tuatara", "weta", "gecko",
+    "takahe", "kaka", "kakapo", "hoiho", "tieke", "fantail", "dolphin", "orca", "penguin", "seal",
+    "shark", "whale", "turtle", "eel", "snail",
+];
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![
+        create_classroom,
+        get_classroom,
+        get_classroom_results,
+        import_roster,
+        get_login_cards,
+        issue_login_cards,
+        sign_out_classroom
+    ]
+}
+
+pub fn operations() -> Vec<Operation> {
+    vec![
+        Operation::new("create_classroom", "Create a classroom")
+            .auth(Auth::Token)
+            .body::<models::NewClassroom>()
+            .returns::<models::Classroom>(201, "The new classroom")
+            .errors(&[400, 403]),
+        Operation::new("get_classroom", "View a classroom and its students")
+            .auth(Auth::Scoped(models::SCOPE_CLASSROOM_READ))
+            .param::<i32>("classroom_id")
+            .returns::<ClassroomDetails>(200, "The classroom")
+            .errors(&[404]),
+        Operation::new(
+            "get_classroom_results",
+            "How each student in a classroom is getting on",
+        )
+        .auth(Auth::Scoped(models::SCOPE_SCORES_READ))
+        .param::<i32>("classroom_id")
+        .returns::<Vec<StudentResults>>(200, "Results for every student")
+        .errors(&[404]),
+        Operation::new("import_roster", "Create students from a class roster")
+            .auth(Auth::Token)
+            .param::<i32>("classroom_id")
+            .raw_body()
+            .returns_raw(
+                200,
+                "text/csv",
+                "The new students' logins, when `format` is csv",
+            )
+            .returns_raw(
+                200,
+                "text/html",
+                "The new students' logins, when `format` is html",
+            )
+            .errors(&[400, 403, 404]),
+        Operation::new("get_login_cards", "Printable login cards for a classroom")
+            .auth(Auth::Token)
+            .param::<i32>("classroom_id")
+            .param::<usize>("per_page")
+            .returns_raw(200, "application/pdf", "The cards, without passwords or QR codes")
+            .errors(&[400, 403, 404]),
+        Operation::new(
+            "issue_login_cards",
+            "Printable login cards with new passwords or login links",
+        )
+        .auth(Auth::Token)
+        .param::<i32>("classroom_id")
+        .param::<usize>("per_page")
+        .param::<bool>("qr")
+        .param::<bool>("reset_passwords")
+        .returns_raw(200, "application/pdf", "The cards")
+        .errors(&[400, 403, 404]),
+        Operation::new(
+            "sign_out_classroom",
+            "Sign every student in a classroom out",
+        )
+        .auth(Auth::Token)
+        .param::<i32>("classroom_id")
+        .returns::<SignedOut>(200, "How many sessions were signed out")
+        .errors(&[403, 404]),
+    ]
+}
+
+/// A classroom along with the students in it
+#[derive(Serialize, JsonSchema)]
+struct ClassroomDetails {
+    #[serde(flatten)]
+    classroom: models::Classroom,
+    students: Vec<models::User>,
+}
+
+/// How a student in a classroom is getting on, from the scores which haven't been voided or flagged
+#[derive(Serialize, JsonSchema)]
+struct StudentResults {
+    id: i32,
+    usr: String,
+    nickname: String,
+    year_level: Option<i32>,
+    num_games: i64,
+    high_score: Option<i32>,
+    stars: i64,
+}
+
+/// How many sessions were signed out when signing out a classroom
+#[derive(Serialize, JsonSchema)]
+struct SignedOut {
+    sessions: usize,
+}
+
+/// A row of a class roster uploaded by a teacher
+#[derive(Deserialize)]
+struct RosterRow {
+    #[serde(alias = "first name")]
+    first_name: String,
+    #[serde(alias = "year level")]
+    year_level: i32,
+    #[serde(default)]
+    username: Option<String>,
+}
+
+enum RosterError {
+    Taken(String),
+    Hash(String),
+    /// No free username or valid password could be found within `MAX_GENERATE_ATTEMPTS`
+    Exhausted(String),
+    Database(diesel::result::Error),
+}
+
+impl From<login_links::LinkError> for RosterError {
+    fn from(e: login_links::LinkError) -> RosterError {
+        match e {
+            login_links::LinkError::Hash(e) => RosterError::Hash(e.to_string()),
+            login_links::LinkError::Database(e) => RosterError::Database(e),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for RosterError {
+    fn from(e: diesel::result::Error) -> RosterError {
+        RosterError::Database(e)
+    }
+}
+
+/// Load a classroom, checking that the user is a teacher who owns it or an admin
+pub async fn load_managed_classroom(
+    conn: &UsersDbConn,
+    staff_id: i32,
+    classroom_id: i32,
+) -> Result<(models::User, models::Classroom), models::Response> {
+    let r = conn
+        .run(move |c| -> Result<_, diesel::result::Error> {
+            use crate::schema::classrooms::dsl::*;
+            let classroom = classrooms
+                .filter(id.eq(classroom_id))
+                .first::<models::Classroom>(c)
+                .optional()?;
+            Ok((common::find_user(c, staff_id)?, classroom))
+        })
+        .await;
+    let (staff, classroom) = match r {
+        Ok((Some(staff), Some(classroom))) => (staff, classroom),
+        Ok(_) => {
+            return Err(models::ResponseBuilder {
+                data: "Classroom Not Found",
+                status: Status::NotFound,
+            }
+            .build())
+        }
+        Err(e) => {
+            return Err(models::Response::database_error(e))
+        }
+    };
+
+    if staff.role != models::ROLE_ADMIN && classroom.teacher_id != staff.id {
+        return Err(models::ResponseBuilder {
+            data: "You do not have permission to manage this classroom",
+            status: Status::Forbidden,
+        }
+        .build());
+    }
+    Ok((staff, classroom))
+}
+
+/// Turn a first name into the start of a username, e.g. `Mere Ānaru` becomes `mereanaru`
+fn username_base(first_name: &str) -> String {
+    let base: String = first_name
+        .chars()
+        .filter_map(|c| match c {
+            'ā' | 'Ā' => Some('a'),
+            'ē' | 'Ē' => Some('e'),
+            'ī' | 'Ī' => Some('i'),
+            'ō' | 'Ō' => Some('o'),
+            'ū' | 'Ū' => Some('u'),
+            c if c.is_ascii_alphabetic() => Some(c.to_ascii_lowercase()),
+            _ => None,
+        })
+        .take(12)
+        .collect();
+    if base.is_empty() || moderation::check(&base) != moderation::Verdict::Allowed {
+        return "student".into();
+    }
+    base
+}
+
+/// Find a username based on the student's first name which isn't taken, e.g. `aroha42`
+fn generate_username(
+    c: &diesel::PgConnection,
+    first_name: &str,
+    taken: &HashSet<String>,
+) -> Result<String, RosterError> {
+    let base = username_base(first_name);
+    for attempt in 0..MAX_GENERATE_ATTEMPTS {
+        //Two digits are easier to remember, but fall back to more if the name is popular
+        let number = if attempt < 20 {
+            10 + common::random_index(90)
+        } else {
+            1000 + common::random_index(9000)
+        };
+        let candidate = format!("{}{}", base, number);
+        if taken.contains(&candidate) || validation::validate_username(&candidate).is_err() {
+            continue;
+        }
+        if common::find_user_by_name(c, candidate.clone())?.is_none() {
+            return Ok(candidate);
+        }
+    }
+    Err(RosterError::Exhausted(format!(
+        "Unable to find a free username for {}",
+        first_name
+    )))
+}
+
+/// A password made of simple words that a young child can type, e.g. `sunnykiwi427`
+fn generate_word_password(usr: &str) -> Result<String, RosterError> {
+    for _ in 0..MAX_GENERATE_ATTEMPTS {
+        let password = format!(
+            "{}{}{}",
+            PASSWORD_WORDS[common::random_index(PASSWORD_WORDS.len())],
+            PASSWORD_ANIMALS[common::random_index(PASSWORD_ANIMALS.len())],
+            100 + common::random_index(900)
+        );
+        if validation::validate_password(&password, usr).is_ok() {
+            return Ok(password);
+        }
+    }
+    //Only possible if the password policy has been set up so word passwords can never pass it
+    Err(RosterError::Exhausted(format!(
+        "Unable to generate a password for {} which meets the password policy",
+        usr
+    )))
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// A printable page listing the login details of every student
+fn credentials_html(classroom: &models::Classroom, students: &[models::RosterCredentials]) -> String {
+    let mut rows = String::new();
+    for s in students {
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td><code>{}</code></td><td><code>{}</code></td></tr>\n",
+            escape_html(&s.nickname),
+            s.year_level.map(|y| y.to_string()).unwrap_or_default(),
+            escape_html(&s.usr),
+            escape_html(&s.pwd),
+        ));
+    }
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{name} logins</title>\n\
+<style>body {{ font-family: sans-serif; }} table {{ border-collapse: collapse; width: 100%; }} \
+td, th {{ border: 1px solid #444; padding: 0.5em; text-align: left; }} code {{ font-size: 1.3em; }}</style>\n\
+</head>\n<body>\n<h1>{name}</h1>\n<table>\n<tr><th>Name</th><th>Year</th><th>Username</th><th>Password</th></tr>\n{rows}</table>\n</body>\n</html>\n",
+        name = escape_html(&classroom.name),
+        rows = rows,
+    )
+}
+
+fn credentials_csv(students: &[models::RosterCredentials]) -> Result<String, csv::Error> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for s in students {
+        writer.serialize(s)?;
+    }
+    let data = writer.into_inner().map_err(|e| e.into_error())?;
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+/// Create a classroom, only available to teachers and admins
+#[post("/api/v1/classroom", data = "<new_classroom>", format = "application/json")]
+async fn create_classroom(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    new_classroom: Json<models::NewClassroom>,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+    let mut new_classroom = new_classroom.into_inner();
+    new_classroom.name = new_classroom.name.trim().to_owned();
+    new_classroom.teacher_id = token.sub;
+
+    let mut errors = validation::Errors::default();
+    let len = new_classroom.name.chars().count();
+    if len == 0 || len > 64 {
+        errors.add("name", "Must be between 1 and 64 characters long".into());
+    }
+    if let Err(e) = errors.into_result() {
+        return e;
+    }
+
+    let subject = token.sub;
+    let r: Result<Option<models::Classroom>, diesel::result::Error> = conn
+        .run(move |c| {
+            match common::find_user(c, subject)? {
+                Some(u) if u.is_staff() => {}
+                _ => return Ok(None),
+            }
+            use crate::schema::classrooms;
+            diesel::insert_into(classrooms::table)
+                .values(new_classroom)
+                .get_result(c)
+                .map(Some)
+        })
+        .await;
+
+    match r {
+        Ok(Some(classroom)) => models::ResponseBuilder {
+            data: classroom,
+            status: Status::Created,
+        }
+        .build(),
+        Ok(None) => models::ResponseBuilder {
+            data: "Only teachers can create classrooms",
+            status: Status::Forbidden,
+        }
+        .build(),
+        Err(e) => models::Response::database_error(e),
+    }
+}
+
+/// View a classroom and the students in it
+#[get("/api/v1/classroom/<classroom_id>")]
+async fn get_classroom(
+    token: Result<models::Scoped<models::ClassroomRead>, models::Response>,
+    conn: UsersDbConn,
+    classroom_id: i32,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap().0;
+    let (_, classroom) = match load_managed_classroom(&conn, token.sub, classroom_id).await {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+
+    let class_id = classroom.id;
+    let r: Result<Vec<models::User>, diesel::result::Error> = conn
+        .run(move |c| {
+            use crate::schema::users::dsl::*;
+            users
+                .filter(classroom_id.eq(Some(class_id)))
+                .filter(deleted_at.is_null())
+                .order(nickname.asc())
+                .load::<models::User>(c)
+        })
+        .await;
+
+    match r {
+        Ok(students) => models::ResponseBuilder {
+            data: ClassroomDetails {
+                classroom,
+                students,
+            },
+            status: Status::Ok,
+        }
+        .build(),
+        Err(e) => models::Response::database_error(e),
+    }
+}
+
+/// The results of every student in a classroom, for gradebooks and other school systems
+#[get("/api/v1/classroom/<classroom_id>/results")]
+async fn get_classroom_results(
+    token: Result<models::Scoped<models::ScoresRead>, models::Response>,
+    conn: UsersDbConn,
+    classroom_id: i32,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap().0;
+    let (_, classroom) = match load_managed_classroom(&conn, token.sub, classroom_id).await {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+
+    let r: Result<Vec<StudentResults>, diesel::result::Error> = conn
+        .run(move |c| {
+            use crate::schema::{scores, users};
+            let students = users::table
+                .filter(users::classroom_id.eq(Some(classroom.id)))
+                .filter(users::deleted_at.is_null())
+                .order(users::nickname.asc())
+                .load::<models::User>(c)?;
+            let ids: Vec<i32> = students.iter().map(|s| s.id).collect();
+            let played: Vec<(i32, i32, i32)> = scores::table
+                .filter(scores::usr_id.eq_any(ids))
+                .filter(scores::voided.eq(false))
+                .filter(scores::flagged.eq(false))
+                .select((scores::usr_id, scores::score, scores::num_stars))
+                .load(c)?;
+            Ok(students
+                .into_iter()
+                .map(|s| {
+                    let games: Vec<&(i32, i32, i32)> = played.iter().filter(|p| p.0 == s.id).collect();
+                    StudentResults {
+                        id: s.id,
+                        usr: s.usr,
+                        nickname: s.nickname,
+                        year_level: s.year_level,
+                        num_games: games.len() as i64,
+                        high_score: games.iter().map(|p| p.1).max(),
+                        stars: games.iter().map(|p| p.2 as i64).sum(),
+                    }
+                })
+                .collect())
+        })
+        .await;
+
+    match r {
+        Ok(results) => models::ResponseBuilder {
+            data: results,
+            status: Status::Ok,
+        }
+        .build(),
+        Err(e) => models::Response::database_error(e),
+    }
+}
+
+/// Create a student account for every row of a CSV roster with the columns `first_name`, `year_level`
+/// and optionally `username`. Usernames and passwords are generated where needed, and the login details
+/// are returned as a CSV file or a printable HTML page.
+#[post(
+    "/api/v1/classroom/<classroom_id>/roster?<format>",
+    data = "<roster>",
+    format = "text/csv"
+)]
+async fn import_roster(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    classroom_id: i32,
+    format: Option<String>,
+    roster: String,
+) -> Result<(ContentType, String), models::Response> {
+    let token = token?;
+    token.deny_guest()?;
+    let as_html = match format.as_deref() {
+        None | Some("csv") => false,
+        Some("html") => true,
+        Some(_) => {
+            return Err(models::ResponseBuilder {
+                data: "Format must be either csv or html",
+                status: Status::BadRequest,
+            }
+            .build())
+        }
+    };
+    let (staff, classroom) = load_managed_classroom(&conn, token.sub, classroom_id).await?;
+
+    //Check every row before creating anything
+    let mut errors = validation::Errors::default();
+    let mut rows: Vec<RosterRow> = vec![];
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(roster.as_bytes());
+    for (i, row) in reader.deserialize::<RosterRow>().enumerate() {
+        //The header is row 1
+        let line = i + 2;
+        let mut row = match row {
+            Ok(r) => r,
+            Err(e) => {
+                errors.add("roster", format!("Row {}: {}", line, e));
+                continue;
+            }
+        };
+        row.username = row.username.filter(|u| !u.is_empty());
+        if let Err(messages) = validation::validate_nickname(&row.first_name) {
+            errors.add("roster", format!("Row {}: first name {}", line, messages.join(", ")));
+        }
+        //A teacher is entering these, so a name only needing review, like Dickson, is fine
+        if moderation::check(&row.first_name) == moderation::Verdict::Rejected {
+            errors.add("roster", format!("Row {}: this first name isn't allowed", line));
+        }
+        if !(0..=13).contains(&row.year_level) {
+            errors.add("roster", format!("Row {}: year level must be between 0 and 13", line));
+        }
+        if let Some(usr) = &row.username {
+            if let Err(messages) = validation::validate_username(usr) {
+                errors.add("roster", format!("Row {}: username {}", line, messages.join(", ")));
+            }
+            if moderation::check(usr) != moderation::Verdict::Allowed {
+                errors.add("roster", format!("Row {}: this username isn't allowed", line));
+            }
+            let lower = usr.to_lowercase();
+            if rows.iter().any(|r| r.username.as_ref().map(|u| u.to_lowercase()) == Some(lower.clone())) {
+                errors.add("roster", format!("Row {}: username {} is used more than once", line, usr));
+            }
+        }
+        rows.push(row);
+    }
+    if rows.is_empty() {
+        errors.add("roster", "Must contain at least one student".into());
+    }
+    if rows.len() > MAX_ROSTER_SIZE {
+        errors.add("roster", format!("Must contain at most {} students", MAX_ROSTER_SIZE));
+    }
+    errors.into_result()?;
+
+    //Create every account in one go, so a problem part way through doesn't leave half a class
+    let class_id = classroom.id;
+    let r: Result<Vec<models::RosterCredentials>, RosterError> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                let mut taken: HashSet<String> = rows
+                    .iter()
+                    .filter_map(|r| r.username.as_ref().map(|u| u.to_lowercase()))
+                    .collect();
+                let mut created = vec![];
+                for row in rows {
+                    let usr = match row.username {
+                        Some(usr) => {
+                            if common::find_user_by_name(c, usr.clone())?.is_some() {
+                                return Err(RosterError::Taken(usr));
+                            }
+                            usr
+                        }
+                        None => generate_username(c, &row.first_name, &taken)?,
+                    };
+                    taken.insert(usr.to_lowercase());
+                    let pwd = generate_word_password(&usr)?;
+                    let hashed = common::hash_string_with_salt(pwd.clone())
+                        .map_err(|e| RosterError::Hash(e.to_string()))?;
+                    let user: models::User = diesel::insert_into(crate::schema::users::table)
+                        .values(models::NewRosterStudent {
+                            usr,
+                            pwd: hashed,
+                            nickname: row.first_name,
+                            current_costume: "default".into(),
+                            costumes: vec!["default".into()],
+                            achievements: vec![],
+                            classroom_id: Some(class_id),
+                            year_level: Some(row.year_level),
+                        })
+                        .get_result(c)?;
+                    recorder
+                        .event(Some(staff.id), Some(user.id), "student.create")
+                        .after(&user)
+                        .save(c)?;
+                    created.push(models::RosterCredentials {
+                        id: user.id,
+                        nickname: user.nickname,
+                        year_level: user.year_level,
+                        usr: user.usr,
+                        pwd,
+                    });
+                }
+                Ok(created)
+            })
+        })
+        .await;
+
+    let students = match r {
+        Ok(s) => s,
+        Err(RosterError::Taken(usr)) => {
+            return Err(models::ResponseBuilder {
+                data: format!("Username {} is taken", usr),
+                status: Status::BadRequest,
+            }
+            .build())
+        }
+        Err(RosterError::Hash(e)) => {
+            return Err(models::ResponseBuilder {
+                data: format!("Unable to hash password {}", e),
+                status: Status::InternalServerError,
+            }
+            .build())
+        }
+        Err(RosterError::Exhausted(e)) => {
+            return Err(models::ResponseBuilder {
+                data: e,
+                status: Status::InternalServerError,
+            }
+            .build())
+        }
+        Err(RosterError::Database(e)) => {
+            return Err(models::Response::database_error(e))
+        }
+    };
+    metrics::account_created("roster", students.len() as u64);
+
+    if as_html {
+        return Ok((ContentType::HTML, credentials_html(&classroom, &students)));
+    }
+    match credentials_csv(&students) {
+        Ok(body) => Ok((ContentType::CSV, body)),
+        Err(e) => Err(models::ResponseBuilder {
+            data: format!("Failed to write credentials due to error {}", e.to_string()),
+            status: Status::InternalServerError,
+        }
+        .build()),
+    }
+}
+
+/// Printable login cards for every student in a classroom, showing their username and costume.
+/// Passwords are only stored hashed, so they are left blank, use `issue_login_cards` for cards with new passwords.
+#[get("/api/v1/classroom/<classroom_id>/login-cards.pdf?<per_page>")]
+async fn get_login_cards(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    classroom_id: i32,
+    per_page: Option<usize>,
+) -> Result<(ContentType, Vec<u8>), models::Response> {
+    login_cards(token?, conn, recorder, classroom_id, per_page, false, false).await
+}
+
+/// Printable login cards like `get_login_cards`, but `reset_passwords` gives every student a new password to print,
+/// and with `qr` each card also has a QR code containing a one-time login link.
+/// These change the students' accounts, so unlike viewing the cards this is a POST, which browsers won't repeat or prefetch.
+#[post("/api/v1/classroom/<classroom_id>/login-cards.pdf?<per_page>&<qr>&<reset_passwords>")]
+async fn issue_login_cards(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    classroom_id: i32,
+    per_page: Option<usize>,
+    qr: Option<bool>,
+    reset_passwords: Option<bool>,
+) -> Result<(ContentType, Vec<u8>), models::Response> {
+    login_cards(
+        token?,
+        conn,
+        recorder,
+        classroom_id,
+        per_page,
+        qr.unwrap_or(false),
+        reset_passwords.unwrap_or(false),
+    )
+    .await
+}
+
+/// Draw the login cards for a classroom, first giving each student a new password and login link if asked to
+async fn login_cards(
+    token: models::Claims,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    classroom_id: i32,
+    per_page: Option<usize>,
+    qr: bool,
+    reset_passwords: bool,
+) -> Result<(ContentType, Vec<u8>), models::Response> {
+    token.deny_guest()?;
+    let per_page = per_page.unwrap_or(8);
+    if !CARDS_PER_PAGE.contains(&per_page) {
+        return Err(models::ResponseBuilder {
+            data: format!("Cards per page must be one of {:?}", CARDS_PER_PAGE),
+            status: Status::BadRequest,
+        }
+        .build());
+    }
+    let (staff, classroom) = load_managed_classroom(&conn, token.sub, classroom_id).await?;
+
+    //New passwords and login links are created together, so a failure leaves every student as they were
+    let class_id = classroom.id;
+    let r: Result<Vec<cards::LoginCard>, RosterError> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                let students = {
+                    use crate::schema::users::dsl::*;
+                    users
+                        .filter(classroom_id.eq(Some(class_id)))
+                        .filter(deleted_at.is_null())
+                        .order(nickname.asc())
+                        .load::<models::User>(c)?
+                };
+                let expires_at = chrono::Utc::now().naive_utc()
+                    + chrono::Duration::days(*crate::LOGIN_LINK_EXPIRY_DAYS);
+                let mut created = vec![];
+                for student in students {
+                    let new_pwd = if reset_passwords {
+                        let new_pwd = generate_word_password(&student.usr)?;
+                        let hashed = common::hash_string_with_salt(new_pwd.clone())
+                            .map_err(|e| RosterError::Hash(e.to_string()))?;
+                        {
+                            use crate::schema::users::dsl::*;
+                            diesel::update(users.filter(id.eq(student.id)))
+                                .set((
+                                    pwd.eq(hashed),
+                                    tokens_valid_after.eq(chrono::Utc::now().naive_utc()),
+                                ))
+                                .execute(c)?;
+                        }
+                        recorder
+                            .event(Some(staff.id), Some(student.id), "student.password")
+                            .save(c)?;
+                        Some(new_pwd)
+                    } else {
+                        None
+                    };
+                    let login_link = if qr {
+                        let link = login_links::create(c, student.id, Some(staff.id), expires_at)?;
+                        Some(format!("{}/login?link={}", *crate::BROWSER_BASE_URL, link))
+                    } else {
+                        None
+                    };
+                    created.push(cards::LoginCard {
+                        nickname: student.nickname,
+                        usr: student.usr,
+                        pwd: new_pwd,
+                        costume: student.current_costume,
+                        login_link,
+                    });
+                }
+                Ok(created)
+            })
+        })
+        .await;
+
+    let students = match r {
+        Ok(s) => s,
+        Err(RosterError::Taken(usr)) => {
+            return Err(models::ResponseBuilder {
+                data: format!("Username {} is taken", usr),
+                status: Status::BadRequest,
+            }
+            .build())
+        }
+        Err(RosterError::Hash(e)) => {
+            return Err(models::ResponseBuilder {
+                data: format!("Unable to hash password {}", e),
+                status: Status::InternalServerError,
+            }
+            .build())
+        }
+        Err(RosterError::Exhausted(e)) => {
+            return Err(models::ResponseBuilder {
+                data: e,
+                status: Status::InternalServerError,
+            }
+            .build())
+        }
+        Err(RosterError::Database(e)) => {
+            return Err(models::Response::database_error(e))
+        }
+    };
+
+    //Drawing and compressing the pages is slow, so keep it off the async workers
+    let title = classroom.name;
+    let r = rocket::tokio::task::spawn_blocking(move || cards::render(&title, &students, per_page)).await;
+    match r {
+        Ok(Ok(pdf)) => Ok((ContentType::PDF, pdf)),
+        Ok(Err(e)) => Err(models::ResponseBuilder {
+            data: format!("Failed to draw login cards due to error {}", e),
+            status: Status::InternalServerError,
+        }
+        .build()),
+        Err(e) => Err(models::ResponseBuilder {
+            data: format!("Failed to draw login cards due to error {}", e.to_string()),
+            status: Status::InternalServerError,
+        }
+        .build()),
+    }
+}
+
+/// Sign every student in a classroom out of every device, such as at the end of a lesson on shared iPads
+#[delete("/api/v1/classroom/<classroom_id>/sessions")]
+async fn sign_out_classroom(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    classroom_id: i32,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+    let (staff, classroom) = match load_managed_classroom(&conn, token.sub, classroom_id).await {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+
+    let r: Result<usize, diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                use crate::schema::users::dsl::*;
+                let students: Vec<i32> = users
+                    .filter(classroom_id.eq(Some(classroom.id)))
+                    .select(id)
+                    .load(c)?;
+                let signed_out = sessions::revoke_all(c, students)?;
+                recorder
+                    .event(Some(staff.id), None, "classroom.sign_out")
+                    .after(&serde_json::json!({ "classroom_id": classroom.id, "sessions": signed_out }))
+                    .save(c)?;
+                Ok(signed_out)
+            })
+        })
+        .await;
+
+    match r {
+        Ok(n) => models::ResponseBuilder {
+            data: SignedOut { sessions: n },
+            status: Status::Ok,
+        }
+        .build(),
+        Err(e) => models::Response::database_error(e),
+    }
+}
@@ -2,10 +2,23 @@ use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
+use diesel::prelude::*;
+use rand_core::RngCore;
+use std::str::FromStr;
+
+sql_function!(
+    /// Lowercase a string in the database, used to compare usernames case-insensitively
+    fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text
+);
+
+/// Characters used when generating one-time codes, ambiguous characters (0/O, 1/I/L) are left out
+/// so that codes can be read aloud or copied from a whiteboard.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
 
 /// Hash a string with a random salt to be stored in the database.
 /// Utilizes the argon2id algorithm
 /// Followed best practices as laid out here: https://cheatsheetseries.owasp.org/cheatsheets/Password_Storage_Cheat_Sheet.html
+#[tracing::instrument(name = "password.hash", skip_all)]
 pub fn hash_string_with_salt(s: String) -> Result<String, argon2::password_hash::Error> {
     let salt = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
@@ -15,6 +28,7 @@ pub fn hash_string_with_salt(s: String) -> Result<String, argon2::password_hash:
 
 /// A function which checks whether the first string can be hashed into the second string.
 /// Returns a boolean true if they are the same, and false otherwise.
+#[tracing::instrument(name = "password.verify", skip_all)]
 pub fn compare_hashed_strings(
     orignal: String,
     hashed: String,
@@ -25,3 +39,117 @@ pub fn compare_hashed_strings(
         .verify_password(orignal.as_bytes(), &parsed_hash)
         .is_ok())
 }
+
+/// Pick a random number in `0..len` without any bias towards the start of the range.
+pub fn random_index(len: usize) -> usize {
+    let mut rng = OsRng;
+    let len = len as u32;
+    let limit = u32::MAX - u32::MAX % len;
+    loop {
+        let v = rng.next_u32();
+        if v < limit {
+            return (v % len) as usize;
+        }
+    }
+}
+
+/// Generate a random code of the provided length, suitable for reading out to a student.
+pub fn generate_code(len: usize) -> String {
+    (0..len)
+        .map(|_| CODE_ALPHABET[random_index(CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+/// Load a user by their id, returning None if they don't exist.
+pub fn find_user(
+    c: &diesel::PgConnection,
+    user_id: i32,
+) -> Result<Option<crate::models::User>, diesel::result::Error> {
+    use crate::schema::users::dsl::*;
+    users
+        .filter(id.eq(user_id))
+        .first::<crate::models::User>(c)
+        .optional()
+}
+
+/// Load a user by their username, ignoring case, returning None if they don't exist.
+pub fn find_user_by_name(
+    c: &diesel::PgConnection,
+    name: String,
+) -> Result<Option<crate::models::User>, diesel::result::Error> {
+    use crate::schema::users::dsl::*;
+    users
+        .filter(lower(usr).eq(lower(name)))
+        .first::<crate::models::User>(c)
+        .optional()
+}
+
+/// Look up a costume from `./costume.toml` by its key
+#[tracing::instrument(name = "catalog.costume")]
+pub fn find_costume(name: &str) -> Option<&'static crate::models::Costume> {
+    crate::COSTUMES.get(name)
+}
+
+/// Look up an achievement from `./achievement.toml` by its key
+#[tracing::instrument(name = "catalog.achievement")]
+pub fn find_achievement(name: &str) -> Option<&'static crate::models::Achievement> {
+    crate::ACHIEVEMENTS.get(name)
+}
+
+/// Read an optional env var, treating an empty value the same as it being unset.
+pub fn env_opt(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.trim().is_empty())
+}
+
+/// Read and parse an optional env var, falling back to the default if it isn't set.
+pub fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env_opt(name) {
+        Some(v) => v
+            .parse()
+            .unwrap_or_else(|_| panic!("Env var {} could not be parsed!", name)),
+        None => default,
+    }
+}
+
+/// Record when a costume or achievement was first unlocked by a user.
+pub fn record_unlock(
+    c: &diesel::PgConnection,
+    user_id: i32,
+    unlock_kind: &str,
+    unlock_name: &str,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::unlocks;
+    diesel::insert_into(unlocks::table)
+        .values(crate::models::InsertableUnlock {
+            usr_id: user_id,
+            kind: unlock_kind.to_owned(),
+            name: unlock_name.to_owned(),
+        })
+        .on_conflict_do_nothing()
+        .execute(c)?;
+    Ok(())
+}
+
+/// The number of stars a user has to spend, from every score that hasn't been voided or flagged plus any bonus stars.
+pub fn star_balance(c: &diesel::PgConnection, user_id: i32) -> Result<i64, diesel::result::Error> {
+    use crate::schema::{scores, users};
+    let earned: Option<i64> = scores::table
+        .filter(scores::usr_id.eq(user_id))
+        .filter(scores::voided.eq(false))
+        .filter(scores::flagged.eq(false))
+        .select(diesel::dsl::sum(scores::num_stars))
+        .first(c)?;
+    let bonus: i32 = users::table
+        .filter(users::id.eq(user_id))
+        .select(users::bonus_stars)
+        .first(c)?;
+    Ok(earned.unwrap_or(0) + bonus as i64)
+}
+
+/// Split a token of the form `<id>.<secret>` into its parts, so the row can be found before checking the secret.
+pub fn split_token(token: &str) -> Option<(i32, &str)> {
+    let mut parts = token.trim().splitn(2, '.');
+    let id = parts.next()?.parse().ok()?;
+    let secret = parts.next().filter(|s| !s.is_empty())?;
+    Some((id, secret))
+}
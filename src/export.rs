@@ -0,0 +1,297 @@
+use crate::models;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use rocket::http::ContentType;
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use rocket::serde::Serialize;
+use std::io::{Seek, SeekFrom, Write};
+use zip::write::{FileOptions, ZipWriter};
+
+/// Rows are loaded from the database this many at a time, so large histories are never fully in memory
+const PAGE_SIZE: i64 = 1000;
+
+pub type ExportError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Everything in the users table, apart from the password hash
+#[derive(Serialize)]
+struct ExportedUser {
+    id: i32,
+    usr: String,
+    nickname: String,
+    pending_nickname: Option<String>,
+    role: String,
+    email: Option<String>,
+    email_verified: bool,
+    is_guest: bool,
+    current_costume: String,
+    costumes: Vec<String>,
+    achievements: Vec<String>,
+    tokens_valid_after: NaiveDateTime,
+    last_active_at: NaiveDateTime,
+    deleted_at: Option<NaiveDateTime>,
+    bonus_stars: i32,
+    classroom_id: Option<i32>,
+    year_level: Option<i32>,
+}
+
+impl From<models::User> for ExportedUser {
+    fn from(u: models::User) -> ExportedUser {
+        ExportedUser {
+            id: u.id,
+            usr: u.usr,
+            nickname: u.nickname,
+            pending_nickname: u.pending_nickname,
+            role: u.role,
+            email: u.email,
+            email_verified: u.email_verified,
+            is_guest: u.is_guest,
+            current_costume: u.current_costume,
+            costumes: u.costumes.into_iter().map(|c| c.name).collect(),
+            achievements: u.achievements.into_iter().map(|a| a.name).collect(),
+            tokens_valid_after: u.tokens_valid_after,
+            last_active_at: u.last_active_at,
+            deleted_at: u.deleted_at,
+            bonus_stars: u.bonus_stars,
+            classroom_id: u.classroom_id,
+            year_level: u.year_level,
+        }
+    }
+}
+
+/// An audit event as a CSV row, with the before and after values written out as JSON text
+#[derive(Serialize)]
+struct AuditEventRow {
+    id: i32,
+    actor_id: Option<i32>,
+    target_id: Option<i32>,
+    action: String,
+    before: Option<String>,
+    after: Option<String>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    created_at: NaiveDateTime,
+}
+
+impl From<models::AuditEvent> for AuditEventRow {
+    fn from(e: models::AuditEvent) -> AuditEventRow {
+        AuditEventRow {
+            id: e.id,
+            actor_id: e.actor_id,
+            target_id: e.target_id,
+            action: e.action,
+            before: e.before.map(|v| v.to_string()),
+            after: e.after.map(|v| v.to_string()),
+            ip: e.ip,
+            user_agent: e.user_agent,
+            created_at: e.created_at,
+        }
+    }
+}
+
+fn load_scores(
+    c: &diesel::PgConnection,
+    user: i32,
+    after: i32,
+) -> Result<Vec<models::Score>, diesel::result::Error> {
+    use crate::schema::scores::dsl::*;
+    scores
+        .filter(usr_id.eq(user))
+        .filter(id.gt(after))
+        .order(id.asc())
+        .limit(PAGE_SIZE)
+        .load::<models::Score>(c)
+}
+
+fn load_unlocks(
+    c: &diesel::PgConnection,
+    user: i32,
+    after: i32,
+) -> Result<Vec<models::Unlock>, diesel::result::Error> {
+    use crate::schema::unlocks::dsl::*;
+    unlocks
+        .filter(usr_id.eq(user))
+        .filter(id.gt(after))
+        .order(id.asc())
+        .limit(PAGE_SIZE)
+        .load::<models::Unlock>(c)
+}
+
+fn load_sessions(
+    c: &diesel::PgConnection,
+    user: i32,
+    after: i32,
+) -> Result<Vec<models::Session>, diesel::result::Error> {
+    use crate::schema::sessions::dsl::*;
+    sessions
+        .filter(usr_id.eq(user))
+        .filter(id.gt(after))
+        .order(id.asc())
+        .limit(PAGE_SIZE)
+        .load::<models::Session>(c)
+}
+
+fn load_identities(
+    c: &diesel::PgConnection,
+    user: i32,
+    after: i32,
+) -> Result<Vec<models::UserIdentity>, diesel::result::Error> {
+    use crate::schema::user_identities::dsl::*;
+    user_identities
+        .filter(usr_id.eq(user))
+        .filter(id.gt(after))
+        .order(id.asc())
+        .limit(PAGE_SIZE)
+        .load::<models::UserIdentity>(c)
+}
+
+fn load_audit_events(
+    c: &diesel::PgConnection,
+    user: i32,
+    after_id: i32,
+) -> Result<Vec<models::AuditEvent>, diesel::result::Error> {
+    //Not a glob import, audit_events has its own `after` column
+    use crate::schema::audit_events::dsl::{audit_events, id, target_id};
+    audit_events
+        .filter(target_id.eq(user))
+        .filter(id.gt(after_id))
+        .order(id.asc())
+        .limit(PAGE_SIZE)
+        .load::<models::AuditEvent>(c)
+        .map(|events| {
+            events
+                .into_iter()
+                .map(|mut e| {
+                    //Where and what from staff acted on this account is theirs, not the user's
+                    if e.actor_id != Some(user) {
+                        e.ip = None;
+                        e.user_agent = None;
+                    }
+                    e
+                })
+                .collect()
+        })
+}
+
+/// Write every row returned by `load_page` as a JSON array, a page at a time
+fn write_json<T, W, F, I>(out: &mut W, mut load_page: F, id_of: I) -> Result<(), ExportError>
+where
+    T: Serialize,
+    W: Write,
+    F: FnMut(i32) -> Result<Vec<T>, diesel::result::Error>,
+    I: Fn(&T) -> i32,
+{
+    out.write_all(b"[")?;
+    let mut first = true;
+    let mut after = 0;
+    loop {
+        let page = load_page(after)?;
+        if page.is_empty() {
+            break;
+        }
+        for row in &page {
+            if !first {
+                out.write_all(b",")?;
+            }
+            first = false;
+            serde_json::to_writer(&mut *out, row)?;
+        }
+        after = id_of(page.last().unwrap());
+    }
+    out.write_all(b"]")?;
+    Ok(())
+}
+
+/// Write every row returned by `load_page` as a CSV file, a page at a time
+fn write_csv<T, W, F, I>(out: &mut W, mut load_page: F, id_of: I) -> Result<(), ExportError>
+where
+    T: Serialize,
+    W: Write,
+    F: FnMut(i32) -> Result<Vec<T>, diesel::result::Error>,
+    I: Fn(&T) -> i32,
+{
+    let mut writer = csv::Writer::from_writer(out);
+    let mut after = 0;
+    loop {
+        let page = load_page(after)?;
+        if page.is_empty() {
+            break;
+        }
+        for row in &page {
+            writer.serialize(row)?;
+        }
+        after = id_of(page.last().unwrap());
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Build a zip archive of everything we hold about a user in a temporary file.
+/// The file is unlinked as soon as it is created, so it is cleaned up once it has been sent.
+pub fn write_archive(
+    c: &diesel::PgConnection,
+    user: models::User,
+) -> Result<std::fs::File, ExportError> {
+    let user_id = user.id;
+    let mut zip = ZipWriter::new(tempfile::tempfile()?);
+    let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+
+    zip.start_file("account.json", options)?;
+    serde_json::to_writer_pretty(&mut zip, &ExportedUser::from(user))?;
+
+    zip.start_file("scores.json", options)?;
+    write_json(&mut zip, |after| load_scores(c, user_id, after), |s| s.id)?;
+    zip.start_file("scores.csv", options)?;
+    write_csv(&mut zip, |after| load_scores(c, user_id, after), |s| s.id)?;
+
+    zip.start_file("unlocks.json", options)?;
+    write_json(&mut zip, |after| load_unlocks(c, user_id, after), |u| u.id)?;
+    zip.start_file("unlocks.csv", options)?;
+    write_csv(&mut zip, |after| load_unlocks(c, user_id, after), |u| u.id)?;
+
+    zip.start_file("sessions.json", options)?;
+    write_json(&mut zip, |after| load_sessions(c, user_id, after), |s| s.id)?;
+    zip.start_file("sessions.csv", options)?;
+    write_csv(&mut zip, |after| load_sessions(c, user_id, after), |s| s.id)?;
+
+    zip.start_file("identities.json", options)?;
+    write_json(&mut zip, |after| load_identities(c, user_id, after), |i| i.id)?;
+    zip.start_file("identities.csv", options)?;
+    write_csv(&mut zip, |after| load_identities(c, user_id, after), |i| i.id)?;
+
+    zip.start_file("audit_events.json", options)?;
+    write_json(&mut zip, |after| load_audit_events(c, user_id, after), |e| e.id)?;
+    zip.start_file("audit_events.csv", options)?;
+    write_csv(
+        &mut zip,
+        |after| {
+            load_audit_events(c, user_id, after)
+                .map(|events| events.into_iter().map(AuditEventRow::from).collect())
+        },
+        |e| e.id,
+    )?;
+
+    let mut file = zip.finish()?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+/// A finished export, streamed to the client as a download
+pub struct ExportArchive {
+    pub file: rocket::tokio::fs::File,
+    pub filename: String,
+}
+
+#[rocket::async_trait]
+impl<'r> Responder<'r, 'static> for ExportArchive {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        rocket::response::Response::build()
+            .header(ContentType::ZIP)
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.filename),
+            )
+            .streamed_body(self.file)
+            .ok()
+    }
+}
@@ -0,0 +1,110 @@
+use crate::common::env_or;
+use crate::UsersDbConn;
+use diesel::prelude::*;
+use rocket::fairing::AdHoc;
+use rocket_sync_db_pools::ConnectionPool;
+use std::time::Duration;
+
+/// How often the background jobs run
+const JOB_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Starts the background jobs once the server has launched
+pub fn fairing() -> AdHoc {
+    AdHoc::on_liftoff("Background Jobs", |rocket| {
+        Box::pin(async move {
+            let pool = match ConnectionPool::<crate::PooledConn, diesel::PgConnection>::get_pool(rocket).await {
+                Some(pool) => pool,
+                None => {
+                    tracing::error!("No database pool, background jobs won't run");
+                    return;
+                }
+            };
+            //Connections can only be taken through a Rocket, and the server's own can't be kept,
+            //so the jobs hold one which manages nothing but a handle to the same pool
+            let holder = rocket::build().manage(pool);
+            let guest_expiry_days: i64 = env_or("GUEST_EXPIRY_DAYS", 30);
+            let deletion_grace_days: i64 = env_or("ACCOUNT_DELETION_GRACE_DAYS", 30);
+            rocket::tokio::spawn(async move {
+                let mut interval = rocket::tokio::time::interval(JOB_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    //A connection is taken for each run and given back afterwards, so one that drops is replaced
+                    match UsersDbConn::get_one(&holder).await {
+                        Some(conn) => run_jobs(&conn, guest_expiry_days, deletion_grace_days).await,
+                        None => tracing::error!("Unable to get a database connection for background jobs"),
+                    }
+                }
+            });
+        })
+    })
+}
+
+async fn run_jobs(conn: &UsersDbConn, guest_expiry_days: i64, deletion_grace_days: i64) {
+    match purge_inactive_guests(conn, guest_expiry_days).await {
+        Ok(n) => tracing::info!(purged = n, "Purged inactive guests"),
+        Err(e) => tracing::error!(error = %e, "Failed to purge inactive guests"),
+    }
+    match purge_deleted_accounts(conn, deletion_grace_days).await {
+        Ok(n) => tracing::info!(purged = n, "Purged deleted accounts"),
+        Err(e) => tracing::error!(error = %e, "Failed to purge deleted accounts"),
+    }
+    match purge_expired_oidc_logins(conn).await {
+        Ok(n) => tracing::info!(purged = n, "Purged expired provider sign ins"),
+        Err(e) => tracing::error!(error = %e, "Failed to purge expired provider sign ins"),
+    }
+}
+
+/// Delete any guest accounts which haven't been used in the given number of days, along with their scores
+pub async fn purge_inactive_guests(
+    conn: &UsersDbConn,
+    days: i64,
+) -> Result<usize, diesel::result::Error> {
+    conn.run(move |c| {
+        c.transaction(|| {
+            use crate::schema::{scores, users};
+            let cutoff = chrono::Utc::now().naive_utc() - chrono::Duration::days(days);
+            let expired = users::table
+                .filter(users::is_guest.eq(true))
+                .filter(users::last_active_at.lt(cutoff))
+                .select(users::id);
+            diesel::delete(scores::table.filter(scores::usr_id.eq_any(expired))).execute(c)?;
+            diesel::delete(
+                users::table
+                    .filter(users::is_guest.eq(true))
+                    .filter(users::last_active_at.lt(cutoff)),
+            )
+            .execute(c)
+        })
+    })
+    .await
+}
+
+/// Permanently remove accounts which were deleted more than the given number of days ago, along with their scores
+pub async fn purge_deleted_accounts(
+    conn: &UsersDbConn,
+    grace_days: i64,
+) -> Result<usize, diesel::result::Error> {
+    conn.run(move |c| {
+        c.transaction(|| {
+            use crate::schema::{scores, users};
+            let cutoff = chrono::Utc::now().naive_utc() - chrono::Duration::days(grace_days);
+            let expired = users::table
+                .filter(users::deleted_at.lt(cutoff))
+                .select(users::id);
+            diesel::delete(scores::table.filter(scores::usr_id.eq_any(expired))).execute(c)?;
+            diesel::delete(users::table.filter(users::deleted_at.lt(cutoff))).execute(c)
+        })
+    })
+    .await
+}
+
+/// Remove provider sign ins which were started but never finished, these can no longer be completed
+pub async fn purge_expired_oidc_logins(conn: &UsersDbConn) -> Result<usize, diesel::result::Error> {
+    conn.run(move |c| {
+        use crate::schema::oidc_logins;
+        let cutoff = chrono::Utc::now().naive_utc()
+            - chrono::Duration::minutes(crate::oidc::LOGIN_EXPIRY_MINUTES);
+        diesel::delete(oidc_logins::table.filter(oidc_logins::created_at.lt(cutoff))).execute(c)
+    })
+    .await
+}
@@ -0,0 +1,394 @@
+use crate::openapi::Operation;
+use crate::{common, JWT_EXPIRY_TIME_HOURS};
+use chrono::NaiveDateTime;
+use jsonwebtoken::errors::{Error, ErrorKind};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use lazy_static::lazy_static;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rsa::pkcs8::FromPublicKey;
+use rsa::PublicKeyParts;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use std::path::Path;
+
+/// The DER prefix of an Ed25519 public key in a `PUBLIC KEY` pem, which is followed by the 32 byte key
+const ED25519_SPKI_PREFIX: &[u8] = &[0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![get_jwks]
+}
+
+pub fn operations() -> Vec<Operation> {
+    vec![
+        Operation::new("get_jwks", "The public keys tokens are signed with")
+            .returns_unwrapped::<Jwks>(200, "Every key a token may be signed with, see RFC 7517"),
+    ]
+}
+
+/// A key listed in the manifest at `JWT_KEYS_FILE`, with files relative to the manifest
+#[derive(Deserialize)]
+struct KeyConfig {
+    kid: String,
+    /// Either RS256 or EdDSA
+    algorithm: String,
+    private_key: String,
+    public_key: String,
+    /// When the key starts signing new tokens
+    not_before: NaiveDateTime,
+    /// When the key stops signing new tokens, it is still accepted for `JWT_KEY_GRACE_HOURS` afterwards
+    #[serde(default)]
+    retire_at: Option<NaiveDateTime>,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    key: Vec<KeyConfig>,
+}
+
+/// A public key in the form other services expect, see RFC 7517
+#[derive(Serialize, Clone, JsonSchema)]
+pub struct Jwk {
+    kty: &'static str,
+    kid: String,
+    alg: &'static str,
+    #[serde(rename = "use")]
+    usage: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    e: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crv: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    x: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+struct Key {
+    kid: String,
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    jwk: Jwk,
+    not_before: NaiveDateTime,
+    retire_at: Option<NaiveDateTime>,
+}
+
+/// Every key tokens can be signed with. `JWT_SECRET` is kept as a fallback so tokens signed with it
+/// before asymmetric keys were set up stay valid, but it is only used to sign when no other key is active,
+/// and its tokens are only accepted until the grace period after the first key starts signing.
+struct KeyRing {
+    keys: Vec<Key>,
+    secret: Option<String>,
+    grace: chrono::Duration,
+}
+
+lazy_static! {
+    static ref KEYS: KeyRing = KeyRing::load();
+}
+
+/// Load the keys, so that mistakes are found at startup
+pub fn initialize() {
+    lazy_static::initialize(&KEYS);
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Pull the raw public key out of an Ed25519 `PUBLIC KEY` pem
+fn ed25519_public_key(pem: &str) -> Option<Vec<u8>> {
+    let body: String = pem.lines().filter(|l| !l.starts_with("-----")).collect();
+    let der = base64::decode(body.trim()).ok()?;
+    if der.len() != ED25519_SPKI_PREFIX.len() + 32 || !der.starts_with(ED25519_SPKI_PREFIX) {
+        return None;
+    }
+    Some(der[ED25519_SPKI_PREFIX.len()..].to_vec())
+}
+
+fn invalid_key(kid: &str, e: impl std::fmt::Display) -> ! {
+    panic!("Key {} is invalid {}", kid, e)
+}
+
+fn load_key(dir: &Path, config: KeyConfig) -> Key {
+    let read = |file: &str| {
+        std::fs::read_to_string(dir.join(file))
+            .unwrap_or_else(|e| panic!("Unable to read key file {} {}", file, e))
+    };
+    let private = read(&config.private_key);
+    let public = read(&config.public_key);
+    let (algorithm, encoding, decoding, jwk) = match config.algorithm.as_str() {
+        "RS256" => {
+            let parsed = rsa::RsaPublicKey::from_public_key_pem(&public)
+                .unwrap_or_else(|e| invalid_key(&config.kid, e));
+            let jwk = Jwk {
+                kty: "RSA",
+                kid: config.kid.clone(),
+                alg: "RS256",
+                usage: "sig",
+                n: Some(base64url(&parsed.n().to_bytes_be())),
+                e: Some(base64url(&parsed.e().to_bytes_be())),
+                crv: None,
+                x: None,
+            };
+            (
+                Algorithm::RS256,
+                EncodingKey::from_rsa_pem(private.as_bytes()).unwrap_or_else(|e| invalid_key(&config.kid, e)),
+                DecodingKey::from_rsa_pem(public.as_bytes()).unwrap_or_else(|e| invalid_key(&config.kid, e)),
+                jwk,
+            )
+        }
+        "EdDSA" => {
+            let x = ed25519_public_key(&public)
+                .unwrap_or_else(|| panic!("Key {} is not an Ed25519 public key", config.kid));
+            let jwk = Jwk {
+                kty: "OKP",
+                kid: config.kid.clone(),
+                alg: "EdDSA",
+                usage: "sig",
+                n: None,
+                e: None,
+                crv: Some("Ed25519"),
+                x: Some(base64url(&x)),
+            };
+            (
+                Algorithm::EdDSA,
+                EncodingKey::from_ed_pem(private.as_bytes()).unwrap_or_else(|e| invalid_key(&config.kid, e)),
+                DecodingKey::from_ed_pem(public.as_bytes()).unwrap_or_else(|e| invalid_key(&config.kid, e)),
+                jwk,
+            )
+        }
+        other => panic!(
+            "Key {} uses the unsupported algorithm {}, use RS256 or EdDSA",
+            config.kid, other
+        ),
+    };
+    Key {
+        kid: config.kid,
+        algorithm,
+        encoding,
+        decoding,
+        jwk,
+        not_before: config.not_before,
+        retire_at: config.retire_at,
+    }
+}
+
+impl KeyRing {
+    fn load() -> KeyRing {
+        let keys = match common::env_opt("JWT_KEYS_FILE") {
+            Some(file) => {
+                let path = Path::new(&file);
+                let data = std::fs::read_to_string(path)
+                    .unwrap_or_else(|_| panic!("Unable to find `{}`", file));
+                let manifest: Manifest =
+                    toml::from_str(&data).unwrap_or_else(|e| panic!("Unable to parse `{}` {}", file, e));
+                let dir = path.parent().unwrap_or_else(|| Path::new("."));
+                manifest.key.into_iter().map(|k| load_key(dir, k)).collect()
+            }
+            None => vec![],
+        };
+        let ring = KeyRing {
+            keys,
+            secret: common::env_opt("JWT_SECRET"),
+            grace: chrono::Duration::hours(common::env_or(
+                "JWT_KEY_GRACE_HOURS",
+                *JWT_EXPIRY_TIME_HOURS as i64,
+            )),
+        };
+        if ring.secret.is_none() && ring.signing_key(chrono::Utc::now().naive_utc()).is_none() {
+            panic!("No key to sign tokens with, set JWT_SECRET or add an active key to JWT_KEYS_FILE");
+        }
+        ring
+    }
+
+    /// The newest key which has started signing and hasn't retired
+    fn signing_key(&self, now: NaiveDateTime) -> Option<&Key> {
+        self.keys
+            .iter()
+            .filter(|k| k.not_before <= now && k.retire_at.map(|r| now < r).unwrap_or(true))
+            .max_by_key(|k| k.not_before)
+    }
+
+    /// Whether tokens signed by a key are still accepted, which they are until the grace period after it retires
+    fn accepts(&self, key: &Key, now: NaiveDateTime) -> bool {
+        key.retire_at.map(|r| now < r + self.grace).unwrap_or(true)
+    }
+
+    /// Whether tokens signed with `JWT_SECRET` are still accepted. Once a key has taken over signing the
+    /// secret is treated like a retired key, so a leaked secret can't be used to forge tokens forever.
+    fn accepts_secret(&self, now: NaiveDateTime) -> bool {
+        let took_over = self.keys.iter().map(|k| k.not_before).min();
+        self.secret.is_some() && took_over.map(|t| now < t + self.grace).unwrap_or(true)
+    }
+}
+
+/// Sign a set of claims with the current key
+pub fn encode<T: Serialize>(claims: &T) -> String {
+    let now = chrono::Utc::now().naive_utc();
+    let r = match KEYS.signing_key(now) {
+        Some(key) => {
+            let mut header = Header::new(key.algorithm);
+            header.kid = Some(key.kid.clone());
+            jsonwebtoken::encode(&header, claims, &key.encoding)
+        }
+        None => {
+            //Checked at startup, either a key is active or there is a secret
+            let secret = KEYS.secret.as_ref().expect("No key to sign tokens with");
+            jsonwebtoken::encode(&Header::default(), claims, &EncodingKey::from_secret(secret.as_bytes()))
+        }
+    };
+    r.expect("Unable to sign token")
+}
+
+/// Check the signature and expiry of a token, picking the key by its `kid`.
+/// Tokens without a `kid` were signed with `JWT_SECRET`, and are refused once it has been replaced.
+pub fn decode<T: DeserializeOwned>(token: &str) -> Result<T, Error> {
+    let header = jsonwebtoken::decode_header(token)?;
+    let now = chrono::Utc::now().naive_utc();
+    let data = match header.kid {
+        Some(kid) => {
+            let key = KEYS
+                .keys
+                .iter()
+                .find(|k| k.kid == kid && KEYS.accepts(k, now))
+                .ok_or_else(|| Error::from(ErrorKind::InvalidSignature))?;
+            jsonwebtoken::decode::<T>(token, &key.decoding, &Validation::new(key.algorithm))?
+        }
+        None => {
+            let secret = KEYS
+                .secret
+                .as_ref()
+                .filter(|_| KEYS.accepts_secret(now))
+                .ok_or_else(|| Error::from(ErrorKind::InvalidSignature))?;
+            jsonwebtoken::decode::<T>(
+                token,
+                &DecodingKey::from_secret(secret.as_bytes()),
+                &Validation::default(),
+            )?
+        }
+    };
+    Ok(data.claims)
+}
+
+/// The public keys tokens are signed with, so other services can check them.
+/// Keys which haven't started signing yet are included, so they are already known when they do.
+/// This is a JWK Set as JWT libraries expect it, so unlike other routes it isn't wrapped in `data`.
+#[get("/api/.well-known/jwks.json")]
+async fn get_jwks() -> Json<Jwks> {
+    let now = chrono::Utc::now().naive_utc();
+    let keys = KEYS
+        .keys
+        .iter()
+        .filter(|k| KEYS.accepts(k, now))
+        .map(|k| k.jwk.clone())
+        .collect();
+    Json(Jwks { keys })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    /// A key which signs with a shared secret, the algorithm doesn't matter when choosing between keys
+    fn key(kid: &str, not_before: &str, retire_at: Option<&str>) -> Key {
+        Key {
+            kid: kid.into(),
+            algorithm: Algorithm::HS256,
+            encoding: EncodingKey::from_secret(kid.as_bytes()),
+            decoding: DecodingKey::from_secret(kid.as_bytes()),
+            jwk: Jwk {
+                kty: "oct",
+                kid: kid.into(),
+                alg: "HS256",
+                usage: "sig",
+                n: None,
+                e: None,
+                crv: None,
+                x: None,
+            },
+            not_before: at(not_before),
+            retire_at: retire_at.map(at),
+        }
+    }
+
+    fn ring(keys: Vec<Key>, secret: Option<&str>) -> KeyRing {
+        KeyRing {
+            keys,
+            secret: secret.map(|s| s.into()),
+            grace: chrono::Duration::hours(24),
+        }
+    }
+
+    #[test]
+    fn the_newest_started_key_signs() {
+        let ring = ring(
+            vec![
+                key("first", "2021-01-01 00:00", Some("2021-06-01 00:00")),
+                key("second", "2021-05-01 00:00", None),
+                key("third", "2021-09-01 00:00", None),
+            ],
+            None,
+        );
+        let signer = |now| ring.signing_key(at(now)).map(|k| k.kid.as_str());
+        assert_eq!(signer("2020-12-31 00:00"), None);
+        assert_eq!(signer("2021-02-01 00:00"), Some("first"));
+        assert_eq!(signer("2021-05-15 00:00"), Some("second"));
+        assert_eq!(signer("2021-08-01 00:00"), Some("second"));
+        assert_eq!(signer("2021-09-01 00:00"), Some("third"));
+    }
+
+    #[test]
+    fn retired_keys_are_accepted_for_the_grace_period() {
+        let ring = ring(vec![key("old", "2021-01-01 00:00", Some("2021-06-01 00:00"))], None);
+        let old = &ring.keys[0];
+        assert!(ring.accepts(old, at("2021-06-01 12:00")));
+        assert!(!ring.accepts(old, at("2021-06-02 00:00")));
+    }
+
+    #[test]
+    fn the_secret_is_accepted_until_the_grace_period_after_the_first_key() {
+        let without_keys = ring(vec![], Some("secret"));
+        assert!(without_keys.accepts_secret(at("2030-01-01 00:00")));
+
+        let rotated = ring(
+            vec![
+                key("second", "2021-05-01 00:00", None),
+                key("first", "2021-01-01 00:00", None),
+            ],
+            Some("secret"),
+        );
+        assert!(rotated.accepts_secret(at("2021-01-01 23:00")));
+        assert!(!rotated.accepts_secret(at("2021-01-02 00:00")));
+
+        let unset = ring(vec![], None);
+        assert!(!unset.accepts_secret(at("2021-01-01 00:00")));
+    }
+
+    #[test]
+    fn ed25519_keys_are_read_from_a_pem() {
+        let mut der = ED25519_SPKI_PREFIX.to_vec();
+        der.extend_from_slice(&[7u8; 32]);
+        let pem = format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            base64::encode(&der)
+        );
+        assert_eq!(ed25519_public_key(&pem), Some(vec![7u8; 32]));
+
+        let rsa = format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            base64::encode([0u8; 44])
+        );
+        assert_eq!(ed25519_public_key(&rsa), None);
+    }
+}
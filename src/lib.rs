@@ -0,0 +1,2871 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use models::ResponseBuilder;
+use openapi::{Auth, Operation};
+use rocket::fs::NamedFile;
+
+#[macro_use]
+extern crate rocket;
+#[macro_use]
+extern crate diesel;
+
+pub mod achievements;
+mod admin;
+mod api_keys;
+mod audit;
+mod cards;
+mod classroom;
+pub mod common;
+mod error;
+mod export;
+mod jobs;
+mod keys;
+mod login_links;
+mod logging;
+mod mailer;
+mod metrics;
+mod mfa;
+pub mod models;
+mod moderation;
+mod oidc;
+mod openapi;
+mod reset;
+mod sessions;
+#[rustfmt::skip]
+pub mod schema;
+mod telemetry;
+mod throttle;
+pub mod validation;
+
+use diesel::prelude::*;
+use lazy_static::lazy_static;
+use rocket::http::Status;
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::State;
+use std::env::var;
+use std::net::IpAddr;
+use std::sync::Arc;
+use toml::value::Table;
+use tracing::Instrument;
+/// Database connection, straight from the pool
+#[rocket_sync_db_pools::database("postgres_database")]
+pub struct PooledConn(diesel::PgConnection);
+
+/// Database connection, which traces waiting for the pool and everything run on it
+pub struct UsersDbConn(PooledConn);
+
+impl UsersDbConn {
+    pub fn fairing() -> impl rocket::fairing::Fairing {
+        PooledConn::fairing()
+    }
+
+    pub async fn get_one<P: rocket::Phase>(rocket: &rocket::Rocket<P>) -> Option<UsersDbConn> {
+        PooledConn::get_one(rocket).await.map(UsersDbConn::taken)
+    }
+
+    /// Wrap a connection fresh from the pool, counting it as in use until it is dropped
+    fn taken(conn: PooledConn) -> UsersDbConn {
+        metrics::connection_taken();
+        UsersDbConn(conn)
+    }
+
+    /// Run queries on the connection, in a span so slow queries can be told apart from everything else
+    pub async fn run<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut diesel::PgConnection) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let span = tracing::info_span!("db.query", otel.kind = "client", db.system = "postgresql");
+        let inner = span.clone();
+        self.0.run(move |c| inner.in_scope(|| f(c))).instrument(span).await
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for UsersDbConn {
+    type Error = ();
+    async fn from_request(req: &'r rocket::Request<'_>) -> rocket::request::Outcome<Self, ()> {
+        //Waiting for a free connection has its own span, so a busy pool can be told apart from slow queries
+        let span = tracing::info_span!("db.pool");
+        let start = std::time::Instant::now();
+        let outcome = req.guard::<PooledConn>().instrument(span).await;
+        metrics::pool_wait(start.elapsed(), outcome.is_success());
+        outcome.map(UsersDbConn::taken)
+    }
+}
+
+impl Drop for UsersDbConn {
+    fn drop(&mut self) {
+        metrics::connection_returned();
+    }
+}
+
+// TODO General todos
+// Move common DB requests (such as looking up a user) into a framework under common.rs to avoid duplicate code.
+// Modify get requests to support 500 server-failure errors if the db is unable to be accessed, rather than the current option-based solution
+// Move token boilerplate into a macro
+
+lazy_static! {
+    static ref JWT_EXPIRY_TIME_HOURS: usize =
+        var("JWT_EXPIRY_TIME_HOURS").expect("Env var JWT_EXPIRY_TIME_HOURS not set!").parse().unwrap();
+    static ref BROWSER_BASE_URL: String = var("BROWSER_BASE_URL").expect("Env var BROWSER_BASE_URL not set!");
+    static ref PASSWORD_RESET_EXPIRY_MINUTES: i64 = common::env_or("PASSWORD_RESET_EXPIRY_MINUTES", 60);
+    static ref EMAIL_VERIFICATION_EXPIRY_HOURS: i64 = common::env_or("EMAIL_VERIFICATION_EXPIRY_HOURS", 48);
+    static ref ACCOUNT_DELETION_GRACE_DAYS: i64 = common::env_or("ACCOUNT_DELETION_GRACE_DAYS", 30);
+    static ref RESET_UNDO_WINDOW_HOURS: i64 = common::env_or("RESET_UNDO_WINDOW_HOURS", 24);
+    static ref LOGIN_LINK_EXPIRY_DAYS: i64 = common::env_or("LOGIN_LINK_EXPIRY_DAYS", 14);
+    static ref MAX_STARS_PER_GAME: i32 = common::env_or("MAX_STARS_PER_GAME", 10);
+    pub static ref COSTUMES: HashMap<String, models::Costume> = {
+        //Load data from file, and parse as toml
+        let data = std::fs::read_to_string("./costume.toml").expect("Unable to find `./costume.toml`");
+        let f = data.parse::<toml::Value>().expect("Unable to parse `./costume.toml`");
+
+        let costumes: &Table = f.get("costume")
+            .expect("Unable to parse `./costume.toml`, no costumes provided!")
+            .as_table()
+            .expect("costume tag is not a table in `./costume.toml`");
+
+        //Parse each costume into hashmap
+        let mut map: HashMap<String, models::Costume> = HashMap::default();
+        let keys: Vec<&String> = costumes.keys().into_iter().collect();
+        for key in keys {
+            let costume = costumes
+                .get(key)
+                .expect(&format!("Unable to parse costume {} from `./costume.toml`, is it correctly formatted?", key))
+                .as_table()
+                .expect(&format!("Unable to parse {} as table from `./costume.toml`", key));
+            let display_name: String = costume
+                .get("name")
+                .expect(&format!("Unable to parse name for costume {} from `./costume.toml`", key))
+                .as_str()
+                .expect(&format!("Unable to parse name for costume {} from `./costume.toml`", key))
+                .to_owned();
+            let description: String = costume
+                .get("description")
+                .expect(&format!("Unable to parse description for costume {} from `./costume.toml`", key))
+                .as_str()
+                .expect(&format!("Unable to parse name for costume {} from `./costume.toml`", key))
+                .to_owned();
+            let price: usize = costume
+                .get("price")
+                .expect(&format!("Unable to parse price for costume {} from `./costume.toml`", key))
+                .as_integer()
+                .expect(&format!("Unable to parse description for costume {} from `./costume.toml`", key)) as usize;
+            map.insert(key.clone(), models::Costume {
+                name: key.clone(),
+                display_name,
+                description,
+                price,
+            });
+        }
+
+        return map;
+    };
+    pub static ref ACHIEVEMENTS: HashMap<String, models::Achievement> = {
+        let data = std::fs::read_to_string("./achievement.toml").expect("Unable to find `./achievement.toml`");
+        let f = data.parse::<toml::Value>().expect("Unable to parse `./achievement.toml`");
+
+        let achievements: &Table = f.get("achievement")
+            .expect("Unable to parse `./achievement.toml`, no achievements provided!")
+            .as_table()
+            .expect("achievement tag is not a table in `./achievement.toml`");
+
+        let mut map: HashMap<String, models::Achievement> = HashMap::default();
+        let keys: Vec<&String> = achievements.keys().into_iter().collect();
+        for key in keys {
+            let achievement = achievements
+                .get(key)
+                .expect(&format!("Unable to parse achievement {} from `./achievement.toml`, is it correctly formatted?", key))
+                .as_table()
+                .expect(&format!("Unable to parse {} as table from `./achievement.toml`", key));
+            let display_name: String = achievement
+                .get("display_name")
+                .expect(&format!("Unable to parse name for achievement {} from `./achievement.toml`", key))
+                .as_str()
+                .expect(&format!("Unable to parse name for achievement {} from `./achievement.toml`", key))
+                .to_owned();
+            let description: String = achievement
+                .get("description")
+                .expect(&format!("Unable to parse description for achievement {} from `./achievement.toml`", key))
+                .as_str()
+                .expect(&format!("Unable to parse name for achievement {} from `./achievement.toml`", key))
+                .to_owned();
+            //Achievements without requirements can only be unlocked by the game itself
+            let requirements: Option<models::Requirements> = achievement
+                .get("requirements")
+                .filter(|r| r.is_table())
+                .map(|r| r.clone().try_into().expect(&format!("Unable to parse requirements for achievement {} from `./achievement.toml`", key)));
+            map.insert(key.clone(), models::Achievement {
+                name: key.clone(),
+                display_name,
+                description,
+                requirements,
+            });
+        }
+        return map;
+    };
+}
+
+/// Return information about the student
+#[get("/api/v1/student")]
+async fn get_student(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+    //Load the item from the db, if it exists
+    use crate::schema::users::dsl::*;
+    let r: Option<crate::models::User> = conn
+        .run(move |c| {
+            let r = users
+                .filter(id.eq(token.sub))
+                .limit(1)
+                .load::<crate::models::User>(c);
+            if let Ok(mut v) = r {
+                if v.is_empty() {
+                    return None;
+                }
+                return Some(v.remove(0));
+            }
+            return None;
+        })
+        .await;
+
+    // Format and return
+    if let Some(user) = r {
+        return models::ResponseBuilder {
+            data: user,
+            status: Status::Ok,
+        }
+        .build();
+    }
+    models::ResponseBuilder {
+        data: "User Not Found",
+        status: Status::BadRequest,
+    }
+    .build()
+}
+
+/// Attempt to login as a student
+#[post(
+    "/api/v1/student/login",
+    data = "<login_information>",
+    format = "application/json"
+)]
+async fn login_student(
+    conn: UsersDbConn,
+    login_information: Json<models::UserCredentials>,
+    ip: Option<IpAddr>,
+    device: sessions::Device,
+    throttle: &State<throttle::LoginThrottle>,
+) -> models::Response {
+    let login_information = login_information.into_inner();
+    let ip = ip.map(|i| i.to_string());
+
+    //Refuse to check the password at all if there have been too many failures recently
+    if let Err(wait) = throttle.check(&login_information.usr, ip.as_deref()) {
+        return throttle::too_many_attempts(wait);
+    }
+
+    //Check if the user exists in the db
+    use crate::schema::users::dsl::*;
+    let name = login_information.usr.clone();
+    let r: Option<crate::models::User> = conn
+        .run(move |c| {
+            let r = users
+                .filter(common::lower(usr).eq(common::lower(name)))
+                .limit(1)
+                .load::<crate::models::User>(c);
+            if let Ok(mut v) = r {
+                if v.is_empty() {
+                    return None;
+                }
+                return Some(v.remove(0));
+            }
+            return None;
+        })
+        .await;
+
+    //Guests don't have a password, so can never log in this way
+    if r.as_ref().map(|u| u.is_guest).unwrap_or(true) {
+        throttle.record_failure(&login_information.usr, ip.as_deref());
+        metrics::login("password", false);
+        return models::ResponseBuilder {
+            data: "Incorrect Password or Username",
+            status: Status::BadRequest,
+        }
+        .build();
+    }
+    let r = r.unwrap();
+    //Check that their password hash matches
+    let hash_valid = match common::compare_hashed_strings(login_information.pwd, r.pwd.clone()) {
+        Ok(h) => h,
+        Err(e) => {
+            return models::ResponseBuilder {
+                data: format!("Failed to compare hashes {}", e.to_string()),
+                status: Status::InternalServerError,
+            }
+            .build()
+        }
+    };
+
+    if !hash_valid {
+        throttle.record_failure(&login_information.usr, ip.as_deref());
+        metrics::login("password", false);
+        return models::ResponseBuilder {
+            data: "Incorrect Password or Username",
+            status: Status::BadRequest,
+        }
+        .build();
+    }
+    throttle.record_success(&login_information.usr);
+    metrics::login("password", true);
+
+    //Deleted accounts have to be restored before they can be used again
+    if r.deleted_at.is_some() {
+        return models::ResponseBuilder {
+            data: "This account has been deleted, it can be restored at /api/v1/student/restore",
+            status: Status::Forbidden,
+        }
+        .build();
+    }
+
+    return mfa::login_response(&conn, device, &r).await;
+}
+
+/// Log in with a one-time link, such as the QR code on a printed login card
+#[post("/api/v1/student/login/link", data = "<link>", format = "application/json")]
+async fn login_with_link(
+    conn: UsersDbConn,
+    link: Json<models::LoginLinkToken>,
+    ip: Option<IpAddr>,
+    device: sessions::Device,
+    throttle: &State<throttle::LoginThrottle>,
+) -> models::Response {
+    let link = link.into_inner();
+    let ip = ip.map(|i| i.to_string());
+
+    //Guesses are counted against the link, usernames can't contain ':' so this never clashes with one
+    let key = match common::split_token(&link.token) {
+        Some((link_id, _)) => format!("link:{}", link_id),
+        None => "link:".to_owned(),
+    };
+    if let Err(wait) = throttle.check(&key, ip.as_deref()) {
+        return throttle::too_many_attempts(wait);
+    }
+
+    let r = conn.run(move |c| login_links::redeem(c, &link.token)).await;
+    match r {
+        Ok(Some(user)) if !user.is_guest && user.deleted_at.is_none() => {
+            throttle.record_success(&key);
+            metrics::login("link", true);
+            mfa::login_response(&conn, device, &user).await
+        }
+        Ok(_) => {
+            throttle.record_failure(&key, ip.as_deref());
+            metrics::login("link", false);
+            models::ResponseBuilder {
+                data: "Invalid or Expired Login Link",
+                status: Status::BadRequest,
+            }
+            .build()
+        }
+        Err(e) => e.into(),
+    }
+}
+
+/// Attempt to login as a student with a picture password, for those too young to type one
+#[post(
+    "/api/v1/student/login/picture",
+    data = "<login_information>",
+    format = "application/json"
+)]
+async fn login_picture(
+    conn: UsersDbConn,
+    login_information: Json<models::PictureCredentials>,
+    ip: Option<IpAddr>,
+    device: sessions::Device,
+    throttle: &State<throttle::LoginThrottle>,
+) -> models::Response {
+    let login_information = login_information.into_inner();
+    let ip = ip.map(|i| i.to_string());
+
+    //There are few enough picture passwords that they need the same lockout as typed ones
+    if let Err(wait) = throttle.check(&login_information.usr, ip.as_deref()) {
+        return throttle::too_many_attempts(wait);
+    }
+
+    let name = login_information.usr.clone();
+    let r = conn.run(move |c| common::find_user_by_name(c, name)).await;
+    let user = match r {
+        Ok(u) => u,
+        Err(e) => {
+            return models::Response::database_error(e)
+        }
+    };
+
+    //Only accounts a teacher has given a picture password to can log in this way
+    let user = user.filter(|u| !u.is_guest && u.picture_password.is_some());
+    let hash_valid = match &user {
+        Some(u) => common::compare_hashed_strings(
+            login_information.pictures.join(","),
+            u.picture_password.clone().unwrap(),
+        ),
+        None => Ok(false),
+    };
+    let hash_valid = match hash_valid {
+        Ok(h) => h,
+        Err(e) => {
+            return models::ResponseBuilder {
+                data: format!("Failed to compare hashes {}", e.to_string()),
+                status: Status::InternalServerError,
+            }
+            .build()
+        }
+    };
+    if !hash_valid {
+        throttle.record_failure(&login_information.usr, ip.as_deref());
+        metrics::login("picture", false);
+        return models::ResponseBuilder {
+            data: "Incorrect Pictures or Username",
+            status: Status::BadRequest,
+        }
+        .build();
+    }
+    throttle.record_success(&login_information.usr);
+    metrics::login("picture", true);
+    let user = user.unwrap();
+
+    if user.deleted_at.is_some() {
+        return models::ResponseBuilder {
+            data: "This account has been deleted, ask your teacher to restore it",
+            status: Status::Forbidden,
+        }
+        .build();
+    }
+
+    return mfa::login_response(&conn, device, &user).await;
+}
+
+/// Attempt to login as a student by scanning their QR badge
+#[post("/api/v1/student/login/qr", data = "<badge>", format = "application/json")]
+async fn login_qr(
+    conn: UsersDbConn,
+    badge: Json<models::BadgeToken>,
+    ip: Option<IpAddr>,
+    device: sessions::Device,
+    throttle: &State<throttle::LoginThrottle>,
+) -> models::Response {
+    let badge = badge.into_inner();
+    let ip = ip.map(|i| i.to_string());
+
+    //Guesses are counted against the student's badge, usernames can't contain ':' so this never clashes with one
+    let key = match common::split_token(&badge.token) {
+        Some((subject, _)) => format!("badge:{}", subject),
+        None => "badge:".to_owned(),
+    };
+    if let Err(wait) = throttle.check(&key, ip.as_deref()) {
+        return throttle::too_many_attempts(wait);
+    }
+
+    let (subject, secret) = match common::split_token(&badge.token) {
+        Some((subject, secret)) => (subject, secret.to_owned()),
+        None => {
+            throttle.record_failure(&key, ip.as_deref());
+            return models::ResponseBuilder {
+                data: "Invalid Badge",
+                status: Status::BadRequest,
+            }
+            .build();
+        }
+    };
+
+    let r = conn.run(move |c| common::find_user(c, subject)).await;
+    let user = match r {
+        Ok(u) => u.filter(|u| !u.is_guest && u.deleted_at.is_none() && u.badge_secret.is_some()),
+        Err(e) => {
+            return models::Response::database_error(e)
+        }
+    };
+    let hash_valid = match &user {
+        Some(u) => common::compare_hashed_strings(secret, u.badge_secret.clone().unwrap()),
+        None => Ok(false),
+    };
+    match hash_valid {
+        Ok(true) => {
+            throttle.record_success(&key);
+            metrics::login("qr", true);
+            mfa::login_response(&conn, device, &user.unwrap()).await
+        }
+        Ok(false) => {
+            throttle.record_failure(&key, ip.as_deref());
+            metrics::login("qr", false);
+            models::ResponseBuilder {
+                data: "Invalid Badge",
+                status: Status::BadRequest,
+            }
+            .build()
+        }
+        Err(e) => models::ResponseBuilder {
+            data: format!("Failed to compare hashes {}", e.to_string()),
+            status: Status::InternalServerError,
+        }
+        .build(),
+    }
+}
+
+/// Give a student a picture password, only available to teachers and admins
+#[put(
+    "/api/v1/student/<student_id>/picture-password",
+    data = "<picture_password>",
+    format = "application/json"
+)]
+async fn set_picture_password(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    student_id: i32,
+    picture_password: Json<models::PicturePassword>,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+    let pictures = picture_password.into_inner().pictures;
+    let mut errors = validation::Errors::default();
+    errors.check("pictures", validation::validate_picture_password(&pictures));
+    if let Err(e) = errors.into_result() {
+        return e;
+    }
+
+    let (staff, student) = match load_managed_student(&conn, token.sub, student_id).await {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+    let hashed = match common::hash_string_with_salt(pictures.join(",")) {
+        Ok(h) => h,
+        Err(e) => {
+            return models::ResponseBuilder {
+                data: format!("Unable to hash picture password {}", e.to_string()),
+                status: Status::InternalServerError,
+            }
+            .build()
+        }
+    };
+
+    let event = recorder.event(Some(staff.id), Some(student.id), "student.picture_password");
+    let r: Result<(), diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                use crate::schema::users::dsl::*;
+                diesel::update(users.filter(id.eq(student.id)))
+                    .set(picture_password.eq(Some(hashed)))
+                    .execute(c)?;
+                event.save(c)
+            })
+        })
+        .await;
+
+    match r {
+        Ok(_) => models::ResponseBuilder {
+            data: "Picture password set",
+            status: Status::Ok,
+        }
+        .build(),
+        Err(e) => models::Response::database_error(e),
+    }
+}
+
+/// Give a student a new QR badge, so any badge printed before stops working. Only available to teachers and admins.
+/// The token is returned once, to be printed as a QR code, as only its hash is kept.
+#[post("/api/v1/student/<student_id>/badge")]
+async fn regenerate_badge(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    student_id: i32,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+    let (staff, student) = match load_managed_student(&conn, token.sub, student_id).await {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+
+    let secret = common::generate_code(24);
+    let hashed = match common::hash_string_with_salt(secret.clone()) {
+        Ok(h) => h,
+        Err(e) => {
+            return models::ResponseBuilder {
+                data: format!("Unable to hash badge {}", e.to_string()),
+                status: Status::InternalServerError,
+            }
+            .build()
+        }
+    };
+
+    let event = recorder.event(Some(staff.id), Some(student.id), "student.badge");
+    let r: Result<(), diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                use crate::schema::users::dsl::*;
+                diesel::update(users.filter(id.eq(student.id)))
+                    .set(badge_secret.eq(Some(hashed)))
+                    .execute(c)?;
+                event.save(c)
+            })
+        })
+        .await;
+
+    match r {
+        Ok(_) => models::ResponseBuilder {
+            data: models::BadgeToken {
+                token: format!("{}.{}", student_id, secret),
+            },
+            status: Status::Ok,
+        }
+        .build(),
+        Err(e) => models::Response::database_error(e),
+    }
+}
+
+/// Remove the login lock from a student who has had too many failed attempts, only available to teachers and admins
+#[post("/api/v1/student/<student_id>/unlock")]
+async fn unlock_student(
+    token: Result<models::Claims, models::Response>,
+    student_id: i32,
+    conn: UsersDbConn,
+    throttle: &State<throttle::LoginThrottle>,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+
+    let (_, student) = match load_managed_student(&conn, token.sub, student_id).await {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+
+    throttle.unlock(&student.usr);
+    return models::ResponseBuilder {
+        data: format!("Account {} unlocked", student.usr),
+        status: Status::Ok,
+    }
+    .build();
+}
+
+/// Create a new student
+#[post(
+    "/api/v1/student/create",
+    data = "<new_user>",
+    format = "application/json"
+)]
+async fn create_student(
+    conn: UsersDbConn,
+    new_user: Json<models::NewUser>,
+    recorder: audit::Recorder,
+    device: sessions::Device,
+) -> models::Response {
+    //Check their details meet the requirements in `./policy.toml`
+    let mut new_user = new_user.into_inner();
+    new_user.nickname = new_user.nickname.trim().to_owned();
+    let mut errors = validation::Errors::default();
+    errors.check("usr", validation::validate_username(&new_user.usr));
+    errors.check("pwd", validation::validate_password(&new_user.pwd, &new_user.usr));
+    errors.check("nickname", validation::validate_nickname(&new_user.nickname));
+
+    //Check their names against the blocklists in `./moderation.toml`
+    if moderation::check(&new_user.usr) != moderation::Verdict::Allowed {
+        errors.add("usr", "This username isn't allowed".into());
+    }
+    let nickname_verdict = moderation::check(&new_user.nickname);
+    if nickname_verdict == moderation::Verdict::Rejected {
+        errors.add("nickname", "This nickname isn't allowed".into());
+    }
+    if let Err(e) = errors.into_result() {
+        return e;
+    }
+
+    //Check that the username isnt't taken
+    use crate::schema::users::dsl::*;
+    let name = new_user.usr.clone();
+    let r: Option<crate::models::User> = conn
+        .run(move |c| {
+            let r = users
+                .filter(common::lower(usr).eq(common::lower(name)))
+                .limit(1)
+                .load::<crate::models::User>(c);
+            if let Ok(mut v) = r {
+                if v.is_empty() {
+                    return None;
+                }
+                return Some(v.remove(0));
+            }
+            return None;
+        })
+        .await;
+
+    if r.is_some() {
+        return models::ResponseBuilder {
+            data: "Username Taken",
+            status: Status::BadRequest,
+        }
+        .build();
+    }
+
+    //Hash password
+    let hashed_password = match common::hash_string_with_salt(new_user.pwd) {
+        Ok(p) => p,
+        Err(e) => {
+            return models::ResponseBuilder {
+                data: format!("Unable to hash password {}", e.to_string()),
+                status: Status::InternalServerError,
+            }
+            .build()
+        }
+    };
+
+    //Nicknames that need reviewing are hidden behind a placeholder until a teacher approves them
+    let (display_nickname, pending) = match nickname_verdict {
+        moderation::Verdict::Review => (moderation::placeholder_nickname(), Some(new_user.nickname)),
+        _ => (new_user.nickname, None),
+    };
+    let new_user = models::NewUser {
+        usr: new_user.usr,
+        pwd: hashed_password,
+        nickname: display_nickname,
+        pending_nickname: pending,
+        is_guest: false,
+        current_costume: "default".into(),
+        costumes: vec!["default".into()],
+        achievements: vec![],
+    };
+
+    //Save account in db
+    use schema::users;
+    let r: Result<models::User, diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                let r: models::User = diesel::insert_into(users::table)
+                    .values(new_user)
+                    .get_result(c)?;
+                recorder
+                    .event(Some(r.id), Some(r.id), "student.create")
+                    .after(&r)
+                    .save(c)?;
+                Ok(r)
+            })
+        })
+        .await;
+
+    if let Err(diesel::result::Error::DatabaseError(
+        diesel::result::DatabaseErrorKind::UniqueViolation,
+        _,
+    )) = r
+    {
+        return models::ResponseBuilder {
+            data: "Username Taken",
+            status: Status::BadRequest,
+        }
+        .build();
+    }
+    if let Err(e) = r {
+        return models::Response::database_error(e);
+    }
+
+    metrics::account_created("student", 1);
+    return sessions::issue(&conn, &r.unwrap(), device, Status::Created).await;
+}
+
+/// Create a guest account, which can play straight away without choosing a username or password
+#[post("/api/v1/student/guest")]
+async fn create_guest(conn: UsersDbConn, device: sessions::Device) -> models::Response {
+    let new_user = models::NewUser {
+        usr: format!("guest_{}", common::generate_code(10).to_lowercase()),
+        pwd: String::new(),
+        nickname: moderation::placeholder_nickname(),
+        pending_nickname: None,
+        is_guest: true,
+        current_costume: "default".into(),
+        costumes: vec!["default".into()],
+        achievements: vec![],
+    };
+
+    use schema::users;
+    let r: Result<models::User, diesel::result::Error> = conn
+        .run(move |c| {
+            diesel::insert_into(users::table)
+                .values(new_user)
+                .get_result(c)
+        })
+        .await;
+
+    if let Err(e) = r {
+        return models::Response::database_error(e);
+    }
+
+    metrics::account_created("guest", 1);
+    return sessions::issue(&conn, &r.unwrap(), device, Status::Created).await;
+}
+
+/// Turn a guest account into a full account, keeping all of its scores, costumes and achievements
+#[post(
+    "/api/v1/student/upgrade",
+    data = "<details>",
+    format = "application/json"
+)]
+async fn upgrade_guest(
+    token: Result<models::Claims, models::Response>,
+    details: Json<models::UpgradeGuest>,
+    conn: UsersDbConn,
+    device: sessions::Device,
+    recorder: audit::Recorder,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+    if !token.guest {
+        return models::ResponseBuilder {
+            data: "This account has already been upgraded",
+            status: Status::BadRequest,
+        }
+        .build();
+    }
+
+    //Hold the new details to the same standard as a brand new account
+    let mut details = details.into_inner();
+    details.nickname = details.nickname.trim().to_owned();
+    let mut errors = validation::Errors::default();
+    errors.check("usr", validation::validate_username(&details.usr));
+    errors.check("pwd", validation::validate_password(&details.pwd, &details.usr));
+    errors.check("nickname", validation::validate_nickname(&details.nickname));
+    if moderation::check(&details.usr) != moderation::Verdict::Allowed {
+        errors.add("usr", "This username isn't allowed".into());
+    }
+    let nickname_verdict = moderation::check(&details.nickname);
+    if nickname_verdict == moderation::Verdict::Rejected {
+        errors.add("nickname", "This nickname isn't allowed".into());
+    }
+    if let Err(e) = errors.into_result() {
+        return e;
+    }
+
+    let hashed_password = match common::hash_string_with_salt(details.pwd.clone()) {
+        Ok(p) => p,
+        Err(e) => {
+            return models::ResponseBuilder {
+                data: format!("Unable to hash password {}", e.to_string()),
+                status: Status::InternalServerError,
+            }
+            .build()
+        }
+    };
+
+    //The guest keeps their placeholder nickname until a teacher approves the new one
+    let held = nickname_verdict == moderation::Verdict::Review;
+    let subject = token.sub;
+    let event = recorder.event(Some(subject), Some(subject), "student.upgrade");
+    let r: Result<models::User, diesel::result::Error> = conn
+        .run(move |c| {
+            use crate::schema::users::dsl::*;
+            c.transaction(|| {
+                let existing = common::find_user(c, subject)?.ok_or(diesel::result::Error::NotFound)?;
+                let (new_nickname, new_pending) = if held {
+                    (existing.nickname.clone(), Some(details.nickname))
+                } else {
+                    (details.nickname, None)
+                };
+                let updated: models::User = diesel::update(users.filter(id.eq(subject)).filter(is_guest.eq(true)))
+                    .set((
+                        usr.eq(details.usr),
+                        pwd.eq(hashed_password),
+                        nickname.eq(new_nickname),
+                        pending_nickname.eq(new_pending),
+                        is_guest.eq(false),
+                        //The guest token is revoked, they are given a full token below
+                        tokens_valid_after.eq(chrono::Utc::now().naive_utc()),
+                    ))
+                    .get_result(c)?;
+                event.before(&existing).after(&updated).save(c)?;
+                Ok(updated)
+            })
+        })
+        .await;
+
+    match r {
+        Ok(u) => sessions::issue(&conn, &u, device, Status::Ok).await,
+        Err(diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            _,
+        )) => models::ResponseBuilder {
+            data: "Username Taken",
+            status: Status::BadRequest,
+        }
+        .build(),
+        Err(diesel::result::Error::NotFound) => models::ResponseBuilder {
+            data: "User Not Found",
+            status: Status::BadRequest,
+        }
+        .build(),
+        Err(e) => models::Response::database_error(e),
+    }
+}
+
+#[delete("/api/v1/student")]
+async fn delete_student(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    outbox: &State<Arc<dyn mailer::Mailer>>,
+    recorder: audit::Recorder,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+    //Check the user exists
+    use crate::schema::users::dsl::*;
+    let usr_id = token.sub.clone();
+    let r: Option<crate::models::User> = conn
+        .run(move |c| {
+            let r = users
+                .filter(id.eq(usr_id))
+                .limit(1)
+                .load::<crate::models::User>(c);
+            if let Ok(mut v) = r {
+                if v.is_empty() {
+                    return None;
+                }
+                return Some(v.remove(0));
+            }
+            return None;
+        })
+        .await;
+
+    if r.is_none() {
+        return models::ResponseBuilder {
+            data: "User Not Found",
+            status: Status::BadRequest,
+        }
+        .build();
+    }
+    let existing = r.unwrap();
+
+    //Mark the account as deleted and revoke every token, it is purged by a background job once the grace period is over
+    let event = recorder
+        .event(Some(token.sub), Some(token.sub), "student.delete")
+        .before(&existing);
+    let r: Result<crate::models::User, diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                let now = chrono::Utc::now().naive_utc();
+                let r = diesel::update(users.filter(id.eq(token.sub)))
+                    .set((deleted_at.eq(Some(now)), tokens_valid_after.eq(now)))
+                    .get_result(c)?;
+                event.after(&serde_json::json!({ "deleted_at": now })).save(c)?;
+                Ok(r)
+            })
+        })
+        .await;
+
+    if let Err(e) = r {
+        return models::Response::database_error(e);
+    }
+
+    //Let the owner know their account is gone, failing to send this shouldn't fail the deletion
+    if let (Some(address), true) = (existing.email, existing.email_verified) {
+        let _ = mailer::deliver(
+            outbox.inner().clone(),
+            mailer::Email::account_deleted(address, existing.usr, *ACCOUNT_DELETION_GRACE_DAYS),
+        )
+        .await;
+    }
+
+    return models::ResponseBuilder {
+        data: format!(
+            "Account {} deleted, it can be restored within {} days",
+            r.unwrap().usr,
+            *ACCOUNT_DELETION_GRACE_DAYS
+        ),
+        status: Status::Ok,
+    }
+    .build();
+}
+
+/// Restore an account that was deleted within the grace period, signing the user back in
+#[post(
+    "/api/v1/student/restore",
+    data = "<login_information>",
+    format = "application/json"
+)]
+async fn restore_student(
+    conn: UsersDbConn,
+    login_information: Json<models::UserCredentials>,
+    ip: Option<IpAddr>,
+    device: sessions::Device,
+    throttle: &State<throttle::LoginThrottle>,
+    recorder: audit::Recorder,
+) -> models::Response {
+    let login_information = login_information.into_inner();
+    let ip = ip.map(|i| i.to_string());
+
+    //Restoring checks the password, so it is throttled just like logging in
+    if let Err(wait) = throttle.check(&login_information.usr, ip.as_deref()) {
+        return throttle::too_many_attempts(wait);
+    }
+
+    use crate::schema::users::dsl::*;
+    let name = login_information.usr.clone();
+    let r: Result<Option<crate::models::User>, diesel::result::Error> = conn
+        .run(move |c| {
+            let cutoff =
+                chrono::Utc::now().naive_utc() - chrono::Duration::days(*ACCOUNT_DELETION_GRACE_DAYS);
+            //Guests have no password to check, so they can't be restored
+            users
+                .filter(common::lower(usr).eq(common::lower(name)))
+                .filter(is_guest.eq(false))
+                .filter(deleted_at.gt(cutoff))
+                .first::<crate::models::User>(c)
+                .optional()
+        })
+        .await;
+
+    let r = match r {
+        Ok(Some(u)) => u,
+        Ok(None) => {
+            throttle.record_failure(&login_information.usr, ip.as_deref());
+            return models::ResponseBuilder {
+                data: "No deleted account could be found with that Username and Password",
+                status: Status::BadRequest,
+            }
+            .build();
+        }
+        Err(e) => {
+            return models::Response::database_error(e)
+        }
+    };
+
+    let hash_valid = match common::compare_hashed_strings(login_information.pwd, r.pwd.clone()) {
+        Ok(h) => h,
+        Err(e) => {
+            return models::ResponseBuilder {
+                data: format!("Failed to compare hashes {}", e.to_string()),
+                status: Status::InternalServerError,
+            }
+            .build()
+        }
+    };
+    if !hash_valid {
+        throttle.record_failure(&login_information.usr, ip.as_deref());
+        return models::ResponseBuilder {
+            data: "No deleted account could be found with that Username and Password",
+            status: Status::BadRequest,
+        }
+        .build();
+    }
+    throttle.record_success(&login_information.usr);
+
+    let restored_id = r.id;
+    let event = recorder
+        .event(Some(restored_id), Some(restored_id), "student.restore")
+        .before(&serde_json::json!({ "deleted_at": r.deleted_at }));
+    let restored: Result<(), diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                diesel::update(users.filter(id.eq(restored_id)))
+                    .set(deleted_at.eq(None::<chrono::NaiveDateTime>))
+                    .execute(c)?;
+                event.after(&serde_json::json!({ "deleted_at": None::<chrono::NaiveDateTime> })).save(c)
+            })
+        })
+        .await;
+
+    if let Err(e) = restored {
+        return models::Response::database_error(e);
+    }
+
+    return mfa::login_response(&conn, device, &r).await;
+}
+
+#[get("/api/v1/scores?<offset>&<limit>&<usr>&<id>")]
+async fn get_scores(
+    conn: UsersDbConn,
+    offset: Option<i64>,
+    limit: Option<i64>,
+    usr: Option<String>,
+    mut id: Option<i32>,
+) -> models::Response {
+    //Set the defaults for these values, and ensure non-negative
+    let offset: i64 = offset.unwrap_or(0).abs();
+    let limit: i64 = limit.unwrap_or(100).abs();
+
+    if id.is_none() && usr.is_some() {
+        //Load the id of the user suggested, deleted accounts have no scores to show
+        let r: Option<crate::models::User> = conn
+            .run(move |c| common::find_user_by_name(c, usr.unwrap()))
+            .await
+            .ok()
+            .flatten()
+            .filter(|u| u.deleted_at.is_none());
+        if let Some(found_user) = r {
+            id = Some(found_user.id);
+        } else {
+            let data: Vec<()> = vec![];
+            return models::ResponseBuilder {
+                data,
+                status: Status::Ok,
+            }
+            .build();
+        }
+    }
+
+    use crate::schema::scores::dsl::usr_id as struct_id;
+    let r: Result<Vec<models::Score>, diesel::result::Error> = conn
+        .run(move |c| {
+            //Scores belonging to deleted accounts are hidden until they are restored, flagged ones until they are approved
+            let active_users = crate::schema::users::table
+                .filter(crate::schema::users::deleted_at.is_null())
+                .select(crate::schema::users::id);
+            let mut db_request = crate::schema::scores::table
+                .filter(struct_id.eq_any(active_users))
+                .filter(crate::schema::scores::voided.eq(false))
+                .filter(crate::schema::scores::flagged.eq(false))
+                .into_boxed();
+            if let Some(usr_id) = id {
+                db_request = db_request.filter(struct_id.eq(usr_id));
+            }
+            db_request
+                .limit(limit)
+                .offset(offset)
+                .load::<crate::models::Score>(c)
+        })
+        .await;
+
+    if let Err(e) = r {
+        return models::Response::database_error(e);
+    }
+
+    let r = r.unwrap();
+    if r.is_empty() {
+        let data: Vec<()> = vec![];
+        return models::ResponseBuilder {
+            data,
+            status: Status::Ok,
+        }
+        .build();
+    }
+    models::ResponseBuilder {
+        data: r,
+        status: Status::Ok,
+    }
+    .build()
+}
+
+#[post("/api/v1/scores", data = "<new_score>", format = "application/json")]
+async fn add_score(
+    token: Result<models::Claims, models::Response>,
+    new_score: Json<models::NewScore>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+    let new_score = new_score.into_inner();
+    //Assign the user id, and flag anything that couldn't have come from a real game for an admin to check
+    let flagged = new_score.score < 0
+        || new_score.num_stars < 0
+        || new_score.num_stars > *MAX_STARS_PER_GAME;
+    let new_score = models::InsertableScore {
+        usr_id: token.sub,
+        num_stars: new_score.num_stars,
+        score: new_score.score,
+        flagged,
+    };
+
+    use schema::scores;
+    let stars = new_score.num_stars;
+    let event = recorder.event(Some(token.sub), Some(token.sub), "score.add");
+    let r: Result<(), diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                let score: models::Score = diesel::insert_into(scores::table)
+                    .values(new_score)
+                    .get_result(c)?;
+                event.after(&score).save(c)
+            })
+        })
+        .await;
+
+    if let Err(e) = r {
+        return models::Response::database_error(e);
+    }
+    metrics::score_submitted(stars);
+
+    return models::ResponseBuilder {
+        data: "",
+        status: Status::Created,
+    }
+    .build();
+}
+
+#[get("/api/v1/student/costumes")]
+async fn get_costumes(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+    //Load the user requested
+    let search_id = token.sub;
+    use crate::schema::users::dsl::*;
+    let r: Result<Option<crate::models::User>, diesel::result::Error> = conn
+        .run(move |c| {
+            let r = users
+                .filter(id.eq(search_id))
+                .limit(1)
+                .load::<crate::models::User>(c);
+            return match r {
+                Ok(mut v) => {
+                    if v.is_empty() {
+                        return Ok(None);
+                    }
+                    Ok(Some(v.remove(0)))
+                }
+                Err(e) => Err(e),
+            };
+        })
+        .await;
+
+    //Check request is ok
+    if let Err(e) = r {
+        return models::Response::database_error(e);
+    }
+    let r = r.unwrap();
+
+    //Check user exists
+    if r.is_none() {
+        return models::ResponseBuilder {
+            data: "User not found in database!",
+            status: Status::NotFound,
+        }
+        .build();
+    }
+
+    //Return value
+    return models::ResponseBuilder {
+        data: r.unwrap().costumes,
+        status: Status::Ok,
+    }
+    .build();
+}
+
+#[post("/api/v1/student/<costume>")]
+async fn set_user_costume(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    costume: String,
+    recorder: audit::Recorder,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+    let costume = match common::find_costume(&costume) {
+        Some(c) => c,
+        None => {
+            return models::ResponseBuilder {
+                data: "Costume does not exist",
+                status: Status::BadRequest,
+            }
+            .build()
+        }
+    };
+
+    //Load the user requested
+    let search_id = token.sub;
+    let event = recorder.event(Some(token.sub), Some(token.sub), "costume.set");
+    use crate::schema::users::dsl::*;
+    let r: Result<crate::models::User, diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                let before = users.filter(id.eq(search_id)).first::<crate::models::User>(c)?;
+                let r: crate::models::User = diesel::update(users.filter(id.eq(search_id)))
+                    .set(current_costume.eq(&costume.name))
+                    .get_result(c)?;
+                event.before(&before).after(&r).save(c)?;
+                Ok(r)
+            })
+        })
+        .await;
+
+    //Check request is ok
+    if let Err(e) = r {
+        return models::Response::database_error(e);
+    }
+    let r = r.unwrap();
+
+    //Return value
+    return models::ResponseBuilder {
+        data: r,
+        status: Status::Ok,
+    }
+    .build();
+}
+
+#[post(
+    "/api/v1/student/costumes",
+    data = "<costume>",
+    format = "application/json"
+)]
+async fn unlock_costume(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    costume: Json<models::UnlockCostume>,
+    recorder: audit::Recorder,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+    let costume = costume.into_inner();
+    //Check requested costume exists
+    let costume = match common::find_costume(&costume.name) {
+        Some(c) => c,
+        None => {
+            return models::ResponseBuilder {
+                data: "Costume does not exist",
+                status: Status::BadRequest,
+            }
+            .build()
+        }
+    };
+
+    //Tally the stars from all of this user's scores to get their total score
+    let search_id = token.sub;
+    let r: Result<i64, diesel::result::Error> = conn
+        .run(move |c| common::star_balance(c, search_id))
+        .await;
+
+    //Check request is ok
+    if let Err(e) = r {
+        return models::Response::database_error(e);
+    }
+
+    //Validate that they have enough stars
+    if r.unwrap() < costume.price as i64 {
+        return models::ResponseBuilder {
+            data: "Costume is too expensive",
+            status: Status::BadRequest,
+        }
+        .build();
+    }
+
+    //Modify that user with their new costume!
+    let event = recorder.event(Some(token.sub), Some(token.sub), "costume.unlock");
+    let r = conn
+        .run(move |c| {
+            c.transaction::<_, diesel::result::Error, _>(|| {
+                let before = common::find_user(c, token.sub)?;
+                //HACK currently diesel does not support this sort of array manipulation, but it will come eventually!
+                let cmd = format!("UPDATE users SET costumes = (select array_agg(distinct e) from unnest(costumes || '{{{}}}') e) WHERE id={} RETURNING *;", &costume.name, &token.sub);
+                let r = diesel::sql_query(&cmd).load::<crate::models::User>(c)?;
+                common::record_unlock(c, token.sub, models::UNLOCK_COSTUME, &costume.name)?;
+                if let Some(after) = r.first() {
+                    event.before(&before).after(after).save(c)?;
+                }
+                Ok(r)
+            })
+        })
+        .await;
+
+    if let Err(e) = r {
+        return models::Response::database_error(e);
+    }
+
+    let r = r.unwrap();
+    if r.is_empty() {
+        return models::ResponseBuilder {
+            data: format!("Failed to query the server due to being unable to find user! Was it deleted while this query was running?"),
+            status: Status::InternalServerError,
+        }
+        .build();
+    }
+    metrics::costume_unlocked(&costume.name);
+
+    return models::ResponseBuilder {
+        data: r.get(0),
+        status: Status::Ok,
+    }
+    .build();
+}
+
+#[post(
+    "/api/v1/student/achievement",
+    data = "<achievement>",
+    format = "application/json"
+)]
+async fn unlock_achievement(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    achievement: Json<models::UnlockAchievement>,
+    recorder: audit::Recorder,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+    //Check relevant achievement exists
+    let achievement = achievement.into_inner();
+    if common::find_achievement(&achievement.name).is_none() {
+        return models::ResponseBuilder {
+            data: "Achievement does not exist",
+            status: Status::BadRequest,
+        }
+        .build();
+    }
+
+    //Modify that user with new achievement!
+    let event = recorder.event(Some(token.sub), Some(token.sub), "achievement.unlock");
+    let r = conn
+        .run(move |c| {
+            c.transaction::<_, diesel::result::Error, _>(|| {
+                let before = common::find_user(c, token.sub)?;
+                //HACK currently diesel does not support this sort of array manipulation, but it will come eventually!
+                let cmd = format!("UPDATE users SET achievements = (select array_agg(distinct e) from unnest(achievements || '{{{}}}') e) WHERE id={} RETURNING *;", &achievement.name, &token.sub);
+                let r = diesel::sql_query(&cmd).load::<crate::models::User>(c)?;
+                common::record_unlock(c, token.sub, models::UNLOCK_ACHIEVEMENT, &achievement.name)?;
+                if let Some(after) = r.first() {
+                    event.before(&before).after(after).save(c)?;
+                }
+                Ok(r)
+            })
+        })
+        .await;
+
+    if let Err(e) = r {
+        return models::Response::database_error(e);
+    }
+
+    let r = r.unwrap();
+    if r.is_empty() {
+        return models::ResponseBuilder {
+            data: format!("Failed to query the server due to being unable to find user! Was it deleted while this query was running?"),
+            status: Status::InternalServerError,
+        }
+        .build();
+    }
+
+    return models::ResponseBuilder {
+        data: r.get(0),
+        status: Status::Ok,
+    }
+    .build();
+}
+
+#[post("/api/v1/student/username", data = "<new_username>", format = "application/json")]
+async fn change_username(token: Result<models::Claims, models::Response>, new_username: Json<models::UnlockCostume>, conn: UsersDbConn, recorder: audit::Recorder) -> models::Response {
+    let new_username = new_username.into_inner();
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+    if let Err(e) = token.deny_guest() {
+        return e;
+    }
+
+    let mut errors = validation::Errors::default();
+    errors.check("name", validation::validate_username(&new_username.name));
+    if moderation::check(&new_username.name) != moderation::Verdict::Allowed {
+        errors.add("name", "This username isn't allowed".into());
+    }
+    if let Err(e) = errors.into_result() {
+        return e;
+    }
+
+    //Check that the username isn't taken
+    use crate::schema::users::dsl::*;
+    let name = new_username.name.clone();
+    let r: Option<crate::models::User> = conn
+        .run(move |c| {
+            let r = users
+                .filter(common::lower(usr).eq(common::lower(name)))
+                .limit(1)
+                .load::<crate::models::User>(c);
+            if let Ok(mut v) = r {
+                if v.is_empty() {
+                    return None;
+                }
+                return Some(v.remove(0));
+            }
+            return None;
+        })
+        .await;
+
+    if r.is_some() {
+        let unwrapped = r.unwrap();
+        if unwrapped.id == token.sub {
+            return models::ResponseBuilder {
+                data: unwrapped,
+                status: Status::Ok,
+            }.build()
+        }
+        return models::ResponseBuilder {
+            data: "Username Taken",
+            status: Status::BadRequest,
+        }
+        .build();
+    }
+
+    //Modify that user with their new username!
+    let event = recorder.event(Some(token.sub), Some(token.sub), "student.username");
+    let r: Result<crate::models::User, diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                let before = users.filter(id.eq(token.sub)).first::<crate::models::User>(c)?;
+                let r: crate::models::User = diesel::update(users.filter(id.eq(token.sub)))
+                    .set(usr.eq(&new_username.name))
+                    .get_result(c)?;
+                event.before(&before).after(&r).save(c)?;
+                Ok(r)
+            })
+        })
+        .await;
+
+    if let Err(diesel::result::Error::DatabaseError(
+        diesel::result::DatabaseErrorKind::UniqueViolation,
+        _,
+    )) = r
+    {
+        return models::ResponseBuilder {
+            data: "Username Taken",
+            status: Status::BadRequest,
+        }
+        .build();
+    }
+    if let Err(e) = r {
+        return models::Response::database_error(e);
+    }
+
+    return models::ResponseBuilder {
+        data: r.unwrap(),
+        status: Status::Ok,
+    }
+    .build();
+}
+
+#[post("/api/v1/student/nickname", data = "<new_nickname>", format = "application/json")]
+async fn change_nickname(token: Result<models::Claims, models::Response>, new_nickname: Json<models::UnlockCostume>, conn: UsersDbConn, recorder: audit::Recorder) -> models::Response {
+    let mut new_nickname = new_nickname.into_inner();
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+    if let Err(e) = token.deny_guest() {
+        return e;
+    }
+
+    new_nickname.name = new_nickname.name.trim().to_owned();
+    let mut errors = validation::Errors::default();
+    errors.check("name", validation::validate_nickname(&new_nickname.name));
+    let verdict = moderation::check(&new_nickname.name);
+    if verdict == moderation::Verdict::Rejected {
+        errors.add("name", "This nickname isn't allowed".into());
+    }
+    if let Err(e) = errors.into_result() {
+        return e;
+    }
+
+    //Modify that user with their new nickname! If it needs reviewing their old one is kept until a teacher approves it
+    use crate::schema::users::dsl::*;
+    let held = verdict == moderation::Verdict::Review;
+    let event = recorder.event(Some(token.sub), Some(token.sub), "student.nickname");
+    let r: Result<crate::models::User, diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                let before = users.filter(id.eq(token.sub)).first::<crate::models::User>(c)?;
+                let r: crate::models::User = if held {
+                    diesel::update(users.filter(id.eq(token.sub)))
+                        .set(pending_nickname.eq(Some(new_nickname.name.clone())))
+                        .get_result(c)?
+                } else {
+                    diesel::update(users.filter(id.eq(token.sub)))
+                        .set((nickname.eq(&new_nickname.name), pending_nickname.eq(None::<String>)))
+                        .get_result(c)?
+                };
+                event.before(&before).after(&r).save(c)?;
+                Ok(r)
+            })
+        })
+        .await;
+
+    if let Err(e) = r {
+        return models::Response::database_error(e);
+    }
+
+    return models::ResponseBuilder {
+        data: r.unwrap(),
+        status: if held { Status::Accepted } else { Status::Ok },
+    }
+    .build();
+}
+
+#[post("/api/v1/student/reset")]
+async fn reset_statistics(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+
+    //Save a snapshot so the reset can be undone, then clear everything in one go
+    let subject = token.sub;
+    let event = recorder.event(Some(subject), Some(subject), "student.reset");
+    let r: Result<Option<crate::models::User>, diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                let before = common::find_user(c, subject)?;
+                let after = reset::reset_with_snapshot(c, subject)?;
+                if let Some(user) = &after {
+                    event.before(&before).after(user).save(c)?;
+                }
+                Ok(after)
+            })
+        })
+        .await;
+
+    match r {
+        Ok(Some(user)) => models::ResponseBuilder {
+            data: user,
+            status: Status::Ok,
+        }
+        .build(),
+        Ok(None) => models::ResponseBuilder {
+            data: "User Not Found",
+            status: Status::BadRequest,
+        }
+        .build(),
+        Err(e) => models::Response::database_error(e),
+    }
+}
+
+/// Undo the most recent reset, as long as it happened within the undo window
+#[post("/api/v1/student/reset/undo")]
+async fn undo_reset_statistics(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+
+    let subject = token.sub;
+    let event = recorder.event(Some(subject), Some(subject), "student.reset_undo");
+    let r: Result<Option<crate::models::User>, diesel::result::Error> = conn
+        .run(move |c| {
+            let not_before = chrono::Utc::now().naive_utc() - chrono::Duration::hours(*RESET_UNDO_WINDOW_HOURS);
+            c.transaction(|| {
+                let before = common::find_user(c, subject)?;
+                let after = reset::undo_reset(c, subject, not_before)?;
+                if let Some(user) = &after {
+                    event.before(&before).after(user).save(c)?;
+                }
+                Ok(after)
+            })
+        })
+        .await;
+
+    match r {
+        Ok(Some(user)) => models::ResponseBuilder {
+            data: user,
+            status: Status::Ok,
+        }
+        .build(),
+        Ok(None) => models::ResponseBuilder {
+            data: format!("There is no reset from the last {} hours to undo", *RESET_UNDO_WINDOW_HOURS),
+            status: Status::NotFound,
+        }
+        .build(),
+        Err(e) => models::Response::database_error(e),
+    }
+}
+
+/// Download a copy of everything held about the student
+#[get("/api/v1/student/export")]
+async fn export_student(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+) -> Result<export::ExportArchive, models::Response> {
+    let token = token?;
+    let subject = token.sub;
+    let r = conn.run(move |c| common::find_user(c, subject)).await;
+    let user = match r {
+        Ok(Some(u)) => u,
+        Ok(None) => {
+            return Err(models::ResponseBuilder {
+                data: "User Not Found",
+                status: Status::BadRequest,
+            }
+            .build())
+        }
+        Err(e) => {
+            return Err(models::Response::database_error(e))
+        }
+    };
+    build_export(&conn, token.sub, user, recorder).await
+}
+
+/// Download a copy of everything held about a student, only available to teachers and admins
+#[get("/api/v1/student/<student_id>/export")]
+async fn export_managed_student(
+    token: Result<models::Claims, models::Response>,
+    student_id: i32,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+) -> Result<export::ExportArchive, models::Response> {
+    let token = token?;
+    let (_, student) = load_managed_student(&conn, token.sub, student_id).await?;
+    build_export(&conn, token.sub, student, recorder).await
+}
+
+/// Write the export archive for a user, recording that it was taken in the audit log
+async fn build_export(
+    conn: &UsersDbConn,
+    actor: i32,
+    user: models::User,
+    recorder: audit::Recorder,
+) -> Result<export::ExportArchive, models::Response> {
+    let filename = format!(
+        "kemukupu-{}-{}.zip",
+        user.usr,
+        chrono::Utc::now().format("%Y-%m-%d")
+    );
+    let event = recorder.event(Some(actor), Some(user.id), "student.export");
+    let r = conn
+        .run(move |c| -> Result<std::fs::File, export::ExportError> {
+            let file = export::write_archive(c, user)?;
+            event.save(c)?;
+            Ok(file)
+        })
+        .await;
+
+    match r {
+        Ok(file) => Ok(export::ExportArchive {
+            file: rocket::tokio::fs::File::from_std(file),
+            filename,
+        }),
+        //Whatever the archive failed on is logged rather than sent back, as it may come from the database
+        Err(e) => Err(match e.downcast::<diesel::result::Error>() {
+            Ok(e) => models::Response::database_error(*e),
+            Err(e) => models::ResponseBuilder {
+                data: "Failed to export account",
+                status: Status::InternalServerError,
+            }
+            .build()
+            .with_error(format!("Export error {}", e)),
+        }),
+    }
+}
+
+/// Change the password of the student, revoking any tokens issued before the change
+#[post("/api/v1/student/password", data = "<passwords>", format = "application/json")]
+async fn change_password(
+    token: Result<models::Claims, models::Response>,
+    passwords: Json<models::ChangePassword>,
+    conn: UsersDbConn,
+    device: sessions::Device,
+    recorder: audit::Recorder,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+    if let Err(e) = token.deny_guest() {
+        return e;
+    }
+    let passwords = passwords.into_inner();
+
+    let subject = token.sub;
+    let r = conn.run(move |c| common::find_user(c, subject)).await;
+    let user = match r {
+        Ok(Some(u)) => u,
+        Ok(None) => {
+            return models::ResponseBuilder {
+                data: "User Not Found",
+                status: Status::BadRequest,
+            }
+            .build()
+        }
+        Err(e) => {
+            return models::Response::database_error(e)
+        }
+    };
+
+    //Check their old password is correct
+    let hash_valid = match common::compare_hashed_strings(passwords.old_pwd, user.pwd) {
+        Ok(h) => h,
+        Err(e) => {
+            return models::ResponseBuilder {
+                data: format!("Failed to compare hashes {}", e.to_string()),
+                status: Status::InternalServerError,
+            }
+            .build()
+        }
+    };
+    if !hash_valid {
+        return models::ResponseBuilder {
+            data: "Incorrect Password",
+            status: Status::BadRequest,
+        }
+        .build();
+    }
+
+    let mut errors = validation::Errors::default();
+    errors.check("new_pwd", validation::validate_password(&passwords.new_pwd, &user.usr));
+    if let Err(e) = errors.into_result() {
+        return e;
+    }
+
+    let hashed_password = match common::hash_string_with_salt(passwords.new_pwd) {
+        Ok(p) => p,
+        Err(e) => {
+            return models::ResponseBuilder {
+                data: format!("Unable to hash password {}", e.to_string()),
+                status: Status::InternalServerError,
+            }
+            .build()
+        }
+    };
+
+    //Save the new password, and revoke all previously issued tokens
+    use crate::schema::users::dsl::*;
+    let event = recorder.event(Some(subject), Some(subject), "student.password");
+    let r: Result<models::User, diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                let updated = diesel::update(users.filter(id.eq(subject)))
+                    .set((
+                        pwd.eq(hashed_password),
+                        tokens_valid_after.eq(chrono::Utc::now().naive_utc()),
+                    ))
+                    .get_result(c)?;
+                event.save(c)?;
+                Ok(updated)
+            })
+        })
+        .await;
+
+    if let Err(e) = r {
+        return models::Response::database_error(e);
+    }
+
+    return sessions::issue(&conn, &r.unwrap(), device, Status::Ok).await;
+}
+
+/// Issue a one-time password reset code for a student, only available to teachers and admins
+#[post("/api/v1/student/<student_id>/password/reset")]
+async fn issue_password_reset(
+    token: Result<models::Claims, models::Response>,
+    student_id: i32,
+    conn: UsersDbConn,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+
+    let (staff, student) = match load_managed_student(&conn, token.sub, student_id).await {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+
+    let (code, expires_at) = match create_password_reset(&conn, student.id, Some(staff.id)).await {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+
+    return models::ResponseBuilder {
+        data: models::IssuedPasswordReset {
+            usr: student.usr,
+            code,
+            expires_at,
+        },
+        status: Status::Created,
+    }
+    .build();
+}
+
+/// Load a staff member and a student they wish to manage, checking they are allowed to do so
+async fn load_managed_student(
+    conn: &UsersDbConn,
+    staff_id: i32,
+    student_id: i32,
+) -> Result<(models::User, models::User), models::Response> {
+    let r = conn
+        .run(move |c| -> Result<_, diesel::result::Error> {
+            match (common::find_user(c, staff_id)?, common::find_user(c, student_id)?) {
+                (Some(staff), Some(student)) => {
+                    let allowed = staff.can_manage(c, &student)?;
+                    Ok(Some((staff, student, allowed)))
+                }
+                _ => Ok(None),
+            }
+        })
+        .await;
+    let (staff, student, allowed) = match r {
+        Ok(Some(r)) => r,
+        Ok(None) => {
+            return Err(models::ResponseBuilder {
+                data: "User Not Found",
+                status: Status::NotFound,
+            }
+            .build())
+        }
+        Err(e) => {
+            return Err(models::Response::database_error(e))
+        }
+    };
+
+    if !allowed {
+        return Err(models::ResponseBuilder {
+            data: "You do not have permission to manage this account",
+            status: Status::Forbidden,
+        }
+        .build());
+    }
+    Ok((staff, student))
+}
+
+/// Generate a one-time password reset code for a user and store its hash.
+/// Returns the plaintext code, which must be passed on to the user as it cannot be recovered.
+async fn create_password_reset(
+    conn: &UsersDbConn,
+    usr_id: i32,
+    issued_by: Option<i32>,
+) -> Result<(String, chrono::NaiveDateTime), models::Response> {
+    let code = common::generate_code(8);
+    let hashed_code = common::hash_string_with_salt(code.clone()).map_err(|e| {
+        models::ResponseBuilder {
+            data: format!("Unable to hash reset code {}", e.to_string()),
+            status: Status::InternalServerError,
+        }
+        .build()
+    })?;
+    let expires_at =
+        chrono::Utc::now().naive_utc() + chrono::Duration::minutes(*PASSWORD_RESET_EXPIRY_MINUTES);
+    let reset = models::InsertablePasswordReset {
+        usr_id,
+        issued_by,
+        code: hashed_code,
+        expires_at,
+    };
+
+    use schema::password_resets;
+    conn.run(move |c| {
+        diesel::insert_into(password_resets::table)
+            .values(reset)
+            .execute(c)
+    })
+    .await
+    .map_err(|e| {
+        models::Response::database_error(e)
+    })?;
+
+    Ok((code, expires_at))
+}
+
+/// Redeem a one-time reset code, setting a new password for the student
+#[post("/api/v1/student/password/reset", data = "<reset>", format = "application/json")]
+async fn redeem_password_reset(
+    reset: Json<models::RedeemPasswordReset>,
+    conn: UsersDbConn,
+    ip: Option<IpAddr>,
+    device: sessions::Device,
+    throttle: &State<throttle::LoginThrottle>,
+) -> models::Response {
+    let reset = reset.into_inner();
+    let ip = ip.map(|i| i.to_string());
+
+    //Reset codes are as good as a password, so guessing them is throttled in the same way
+    if let Err(wait) = throttle.check(&reset.usr, ip.as_deref()) {
+        return throttle::too_many_attempts(wait);
+    }
+
+    let name = reset.usr.clone();
+    let r = conn.run(move |c| common::find_user_by_name(c, name)).await;
+    let user = match r {
+        Ok(Some(u)) => u,
+        Ok(None) => {
+            throttle.record_failure(&reset.usr, ip.as_deref());
+            return models::ResponseBuilder {
+                data: "Invalid Reset Code",
+                status: Status::BadRequest,
+            }
+            .build()
+        }
+        Err(e) => {
+            return models::Response::database_error(e)
+        }
+    };
+
+    //Load any outstanding codes for this user
+    let subject = user.id;
+    let r: Result<Vec<models::PasswordReset>, diesel::result::Error> = conn
+        .run(move |c| {
+            use crate::schema::password_resets::dsl::*;
+            password_resets
+                .filter(usr_id.eq(subject))
+                .filter(used_at.is_null())
+                .filter(expires_at.gt(chrono::Utc::now().naive_utc()))
+                .load::<models::PasswordReset>(c)
+        })
+        .await;
+    let outstanding = match r {
+        Ok(v) => v,
+        Err(e) => {
+            return models::Response::database_error(e)
+        }
+    };
+
+    //Find the code that matches, codes are case-insensitive to make life easier for students
+    let provided = reset.code.trim().to_uppercase();
+    let mut matched: Option<i32> = None;
+    for outstanding_reset in outstanding {
+        match common::compare_hashed_strings(provided.clone(), outstanding_reset.code) {
+            Ok(true) => {
+                matched = Some(outstanding_reset.id);
+                break;
+            }
+            Ok(false) => continue,
+            Err(e) => {
+                return models::ResponseBuilder {
+                    data: format!("Failed to compare hashes {}", e.to_string()),
+                    status: Status::InternalServerError,
+                }
+                .build()
+            }
+        }
+    }
+    if matched.is_none() {
+        throttle.record_failure(&reset.usr, ip.as_deref());
+        return models::ResponseBuilder {
+            data: "Invalid Reset Code",
+            status: Status::BadRequest,
+        }
+        .build();
+    }
+    let reset_id = matched.unwrap();
+
+    let mut errors = validation::Errors::default();
+    errors.check("pwd", validation::validate_password(&reset.pwd, &user.usr));
+    if let Err(e) = errors.into_result() {
+        return e;
+    }
+
+    let hashed_password = match common::hash_string_with_salt(reset.pwd) {
+        Ok(p) => p,
+        Err(e) => {
+            return models::ResponseBuilder {
+                data: format!("Unable to hash password {}", e.to_string()),
+                status: Status::InternalServerError,
+            }
+            .build()
+        }
+    };
+
+    //Mark the code as used and update the password together, so a code can never be used twice
+    let r: Result<models::User, diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                let now = chrono::Utc::now().naive_utc();
+                {
+                    use crate::schema::password_resets::dsl::*;
+                    let updated = diesel::update(
+                        password_resets.filter(id.eq(reset_id)).filter(used_at.is_null()),
+                    )
+                    .set(used_at.eq(now))
+                    .execute(c)?;
+                    if updated == 0 {
+                        return Err(diesel::result::Error::NotFound);
+                    }
+                }
+                {
+                    use crate::schema::users::dsl::*;
+                    diesel::update(users.filter(id.eq(subject)))
+                        .set((pwd.eq(hashed_password), tokens_valid_after.eq(now)))
+                        .get_result(c)
+                }
+            })
+        })
+        .await;
+
+    match r {
+        Ok(u) => mfa::login_response(&conn, device, &u).await,
+        Err(diesel::result::Error::NotFound) => models::ResponseBuilder {
+            data: "Invalid Reset Code",
+            status: Status::BadRequest,
+        }
+        .build(),
+        Err(e) => models::Response::database_error(e),
+    }
+}
+
+/// Add an email to the student's account, sending a link to verify they own it
+#[post("/api/v1/student/email", data = "<new_email>", format = "application/json")]
+async fn set_email(
+    token: Result<models::Claims, models::Response>,
+    new_email: Json<models::EmailAddress>,
+    conn: UsersDbConn,
+    outbox: &State<Arc<dyn mailer::Mailer>>,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+    if let Err(e) = token.deny_guest() {
+        return e;
+    }
+    let new_email = new_email.into_inner().email.trim().to_lowercase();
+    if !mailer::is_valid_address(&new_email) {
+        return models::ResponseBuilder {
+            data: "Invalid Email Address",
+            status: Status::BadRequest,
+        }
+        .build();
+    }
+
+    //Generate the verification token, the selector is used to find it again and only the verifier's hash is kept
+    let selector = common::generate_code(12);
+    let verifier = common::generate_code(24);
+    let hashed_verifier = match common::hash_string_with_salt(verifier.clone()) {
+        Ok(h) => h,
+        Err(e) => {
+            return models::ResponseBuilder {
+                data: format!("Unable to hash verification token {}", e.to_string()),
+                status: Status::InternalServerError,
+            }
+            .build()
+        }
+    };
+    let verification = models::InsertableEmailVerification {
+        usr_id: token.sub,
+        email: new_email.clone(),
+        selector: selector.clone(),
+        verifier: hashed_verifier,
+        expires_at: chrono::Utc::now().naive_utc()
+            + chrono::Duration::hours(*EMAIL_VERIFICATION_EXPIRY_HOURS),
+    };
+
+    //Store the unverified email against the user
+    let address = new_email.clone();
+    let r: Result<models::User, diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                diesel::insert_into(schema::email_verifications::table)
+                    .values(verification)
+                    .execute(c)?;
+                use crate::schema::users::dsl::*;
+                diesel::update(users.filter(id.eq(token.sub)))
+                    .set((email.eq(Some(address)), email_verified.eq(false)))
+                    .get_result(c)
+            })
+        })
+        .await;
+
+    if let Err(e) = r {
+        return models::Response::database_error(e);
+    }
+
+    let link = format!(
+        "{}/verify-email?token={}.{}",
+        *BROWSER_BASE_URL, selector, verifier
+    );
+    let email = mailer::Email::verification(new_email, link);
+    if let Err(e) = mailer::deliver(outbox.inner().clone(), email).await {
+        return models::ResponseBuilder {
+            data: format!("Unable to send verification email {}", e.to_string()),
+            status: Status::InternalServerError,
+        }
+        .build();
+    }
+
+    return models::ResponseBuilder {
+        data: r.unwrap(),
+        status: Status::Ok,
+    }
+    .build();
+}
+
+/// Verify an email using the token sent by `set_email`
+#[post("/api/v1/student/email/verify", data = "<verification>", format = "application/json")]
+async fn verify_email(
+    verification: Json<models::VerifyEmail>,
+    conn: UsersDbConn,
+) -> models::Response {
+    let verification = verification.into_inner();
+    let mut parts = verification.token.trim().splitn(2, '.');
+    let (provided_selector, provided_verifier) = match (parts.next(), parts.next()) {
+        (Some(s), Some(v)) => (s.to_owned(), v.to_owned()),
+        _ => {
+            return models::ResponseBuilder {
+                data: "Invalid Verification Token",
+                status: Status::BadRequest,
+            }
+            .build()
+        }
+    };
+
+    let r: Result<Option<models::EmailVerification>, diesel::result::Error> = conn
+        .run(move |c| {
+            use crate::schema::email_verifications::dsl::*;
+            email_verifications
+                .filter(selector.eq(provided_selector))
+                .filter(used_at.is_null())
+                .filter(expires_at.gt(chrono::Utc::now().naive_utc()))
+                .first::<models::EmailVerification>(c)
+                .optional()
+        })
+        .await;
+    let pending = match r {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            return models::ResponseBuilder {
+                data: "Invalid Verification Token",
+                status: Status::BadRequest,
+            }
+            .build()
+        }
+        Err(e) => {
+            return models::Response::database_error(e)
+        }
+    };
+
+    match common::compare_hashed_strings(provided_verifier, pending.verifier.clone()) {
+        Ok(true) => {}
+        Ok(false) => {
+            return models::ResponseBuilder {
+                data: "Invalid Verification Token",
+                status: Status::BadRequest,
+            }
+            .build()
+        }
+        Err(e) => {
+            return models::ResponseBuilder {
+                data: format!("Failed to compare hashes {}", e.to_string()),
+                status: Status::InternalServerError,
+            }
+            .build()
+        }
+    }
+
+    //Only verify the email if it is still the one on the account, the user may have changed it since
+    let r: Result<usize, diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                {
+                    use crate::schema::email_verifications::dsl::*;
+                    diesel::update(email_verifications.filter(id.eq(pending.id)))
+                        .set(used_at.eq(chrono::Utc::now().naive_utc()))
+                        .execute(c)?;
+                }
+                use crate::schema::users::dsl::*;
+                diesel::update(
+                    users
+                        .filter(id.eq(pending.usr_id))
+                        .filter(email.eq(Some(pending.email))),
+                )
+                .set(email_verified.eq(true))
+                .execute(c)
+            })
+        })
+        .await;
+
+    match r {
+        Ok(0) => models::ResponseBuilder {
+            data: "Invalid Verification Token",
+            status: Status::BadRequest,
+        }
+        .build(),
+        Ok(_) => models::ResponseBuilder {
+            data: "Email Verified",
+            status: Status::Ok,
+        }
+        .build(),
+        Err(diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            _,
+        )) => models::ResponseBuilder {
+            data: "Email is already in use by another account",
+            status: Status::BadRequest,
+        }
+        .build(),
+        Err(e) => models::Response::database_error(e),
+    }
+}
+
+/// Email a password reset code to the owner of a verified email.
+/// Always succeeds, so that it can't be used to discover which emails have accounts.
+#[post("/api/v1/student/recover", data = "<address>", format = "application/json")]
+async fn recover_account(
+    address: Json<models::EmailAddress>,
+    conn: UsersDbConn,
+    outbox: &State<Arc<dyn mailer::Mailer>>,
+    ip: Option<IpAddr>,
+    throttle: &State<throttle::LoginThrottle>,
+) -> models::Response {
+    let address = address.into_inner().email.trim().to_lowercase();
+    let ip = ip.map(|i| i.to_string());
+    if let Err(wait) = throttle.check_email(&address, ip.as_deref()) {
+        return throttle::too_many_emails(wait);
+    }
+
+    let search = address.clone();
+    let r: Result<Option<models::User>, diesel::result::Error> = conn
+        .run(move |c| {
+            use crate::schema::users::dsl::*;
+            users
+                .filter(email.eq(Some(search)))
+                .filter(email_verified.eq(true))
+                .first::<models::User>(c)
+                .optional()
+        })
+        .await;
+
+    //Every outcome gets the same response, anything that went wrong is only logged
+    let sent = models::ResponseBuilder {
+        data: "If that email belongs to a verified account, a reset code has been sent to it",
+        status: Status::Ok,
+    }
+    .build();
+    let user = match r {
+        Ok(Some(user)) => user,
+        Ok(None) => return sent,
+        Err(e) => return sent.with_error(format!("Database error {}", e)),
+    };
+    let code = match create_password_reset(&conn, user.id, None).await {
+        Ok((code, _)) => code,
+        Err(e) => {
+            let error = e.error().unwrap_or("Unable to create a reset code").to_owned();
+            return sent.with_error(error);
+        }
+    };
+    let email = mailer::Email::password_reset(address, user.usr, code, *PASSWORD_RESET_EXPIRY_MINUTES);
+    match mailer::deliver(outbox.inner().clone(), email).await {
+        Ok(()) => sent,
+        Err(e) => sent.with_error(format!("Unable to send recovery email {}", e)),
+    }
+}
+
+/// List the nicknames waiting for review, only available to teachers and admins
+#[get("/api/v1/moderation/nicknames")]
+async fn get_pending_nicknames(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+
+    let subject = token.sub;
+    let r: Result<(Option<models::User>, Vec<models::User>), diesel::result::Error> = conn
+        .run(move |c| {
+            use crate::schema::users::dsl::*;
+            let staff = match common::find_user(c, subject)? {
+                Some(s) => s,
+                None => return Ok((None, vec![])),
+            };
+            let mut pending = vec![];
+            for student in users
+                .filter(pending_nickname.is_not_null())
+                .filter(deleted_at.is_null())
+                .order(id.asc())
+                .load::<models::User>(c)?
+            {
+                if staff.can_manage(c, &student)? {
+                    pending.push(student);
+                }
+            }
+            Ok((Some(staff), pending))
+        })
+        .await;
+
+    let (staff, pending) = match r {
+        Ok((Some(staff), pending)) => (staff, pending),
+        Ok((None, _)) => {
+            return models::ResponseBuilder {
+                data: "User Not Found",
+                status: Status::BadRequest,
+            }
+            .build()
+        }
+        Err(e) => {
+            return models::Response::database_error(e)
+        }
+    };
+
+    if !staff.is_staff() {
+        return models::ResponseBuilder {
+            data: "You do not have permission to moderate nicknames",
+            status: Status::Forbidden,
+        }
+        .build();
+    }
+
+    let data: Vec<models::PendingNickname> = pending
+        .into_iter()
+        .map(models::PendingNickname::from)
+        .collect();
+    return models::ResponseBuilder {
+        data,
+        status: Status::Ok,
+    }
+    .build();
+}
+
+/// Approve or reject a student's pending nickname, only available to teachers and admins
+#[post("/api/v1/moderation/nicknames/<student_id>/<decision>")]
+async fn review_nickname(
+    token: Result<models::Claims, models::Response>,
+    student_id: i32,
+    decision: String,
+    conn: UsersDbConn,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+    let approve = match decision.as_str() {
+        "approve" => true,
+        "reject" => false,
+        _ => {
+            return models::ResponseBuilder {
+                data: "Decision must be either approve or reject",
+                status: Status::BadRequest,
+            }
+            .build()
+        }
+    };
+
+    let (_, student) = match load_managed_student(&conn, token.sub, student_id).await {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+    let requested = match student.pending_nickname {
+        Some(n) => n,
+        None => {
+            return models::ResponseBuilder {
+                data: "This student has no nickname waiting for review",
+                status: Status::BadRequest,
+            }
+            .build()
+        }
+    };
+
+    use crate::schema::users::dsl::*;
+    let r: Result<models::User, diesel::result::Error> = conn
+        .run(move |c| {
+            let student = users.filter(id.eq(student_id));
+            if approve {
+                diesel::update(student)
+                    .set((nickname.eq(requested), pending_nickname.eq(None::<String>)))
+                    .get_result(c)
+            } else {
+                diesel::update(student)
+                    .set(pending_nickname.eq(None::<String>))
+                    .get_result(c)
+            }
+        })
+        .await;
+
+    if let Err(e) = r {
+        return models::Response::database_error(e);
+    }
+
+    return models::ResponseBuilder {
+        data: models::PendingNickname::from(r.unwrap()),
+        status: Status::Ok,
+    }
+    .build();
+}
+
+#[get("/api/v1/costume")]
+fn get_costume_information() -> models::Response {
+    let data: Vec<models::Costume> = COSTUMES.values().cloned().collect();
+    return models::ResponseBuilder {
+        data,
+        status: Status::Ok,
+    }
+    .build();
+}
+
+#[get("/api/v1/costume/image/<costume_id>")]
+async fn get_costume_image(costume_id: String) -> Option<NamedFile> {
+    NamedFile::open(Path::new(&format!("static/costume/{}", costume_id)))
+        .await
+        .ok()
+}
+
+/// Serve docs about the api
+#[get("/api/docs")]
+async fn docs() -> NamedFile {
+    NamedFile::open(Path::new("static/docs/index.html"))
+        .await
+        .ok()
+        .unwrap()
+}
+
+/// Returns the current health status of the database
+#[get("/api/health")]
+fn health() -> models::Response {
+    //TODO
+    ResponseBuilder {
+        data: "Online",
+        status: Status::Ok,
+    }
+    .build()
+}
+
+/// Handle the serving of any static resources for various pages
+/// SAFETY: Rocket has a neat implementation preventing a path from getting outside of /static - keeping our host safe.
+#[get("/api/static/<file..>")]
+async fn website_resource(file: PathBuf) -> Option<NamedFile> {
+    NamedFile::open(Path::new("static/").join(file)).await.ok()
+}
+
+/// Endpoint mostly used during development, is a final catch-all to prevent infinite loops.
+#[get("/notfound")]
+fn not_found_stop_point() -> &'static str {
+    "Route Not Found"
+}
+
+/// Handle any 404's
+#[catch(404)]
+async fn not_found() -> Redirect {
+    Redirect::to("/notfound")
+}
+
+/// How the routes mounted in `rocket` are described in `/api/openapi.json`
+fn operations() -> Vec<Operation> {
+    vec![
+        Operation::new("docs", "These docs").returns_raw(
+            200,
+            "text/html",
+            "Swagger UI for `/api/openapi.json`",
+        ),
+        Operation::new("get_student", "View your account")
+            .auth(Auth::Token)
+            .returns::<models::User>(200, "Your account")
+            .errors(&[400]),
+        Operation::new("login_student", "Log in with a username and password")
+            .body::<models::UserCredentials>()
+            .returns::<String>(200, "A token")
+            .returns::<models::MfaChallenge>(
+                202,
+                "A second factor is needed, see `/api/v1/mfa/verify`",
+            )
+            .errors(&[400, 403, 429]),
+        Operation::new(
+            "login_with_link",
+            "Log in with a one-time link from a login card",
+        )
+        .body::<models::LoginLinkToken>()
+        .returns::<String>(200, "A token")
+        .returns::<models::MfaChallenge>(202, "A second factor is needed, see `/api/v1/mfa/verify`")
+        .errors(&[400, 429]),
+        Operation::new("login_picture", "Log in with a picture password")
+            .body::<models::PictureCredentials>()
+            .returns::<String>(200, "A token")
+            .returns::<models::MfaChallenge>(
+                202,
+                "A second factor is needed, see `/api/v1/mfa/verify`",
+            )
+            .errors(&[400, 403, 429]),
+        Operation::new("login_qr", "Log in with a QR badge")
+            .body::<models::BadgeToken>()
+            .returns::<String>(200, "A token")
+            .returns::<models::MfaChallenge>(
+                202,
+                "A second factor is needed, see `/api/v1/mfa/verify`",
+            )
+            .errors(&[400, 429]),
+        Operation::new("set_picture_password", "Set a student's picture password")
+            .auth(Auth::Token)
+            .param::<i32>("student_id")
+            .body::<models::PicturePassword>()
+            .returns::<String>(200, "Confirmation it was set")
+            .errors(&[400, 403, 404]),
+        Operation::new("regenerate_badge", "Print a new QR badge for a student")
+            .auth(Auth::Token)
+            .param::<i32>("student_id")
+            .returns::<models::BadgeToken>(200, "The token to put on the badge")
+            .errors(&[403, 404]),
+        Operation::new(
+            "unlock_student",
+            "Let a student log in again after too many failed attempts",
+        )
+        .auth(Auth::Token)
+        .param::<i32>("student_id")
+        .returns::<String>(200, "Confirmation the account was unlocked")
+        .errors(&[403, 404]),
+        Operation::new("create_student", "Create an account")
+            .body::<models::NewUser>()
+            .returns::<String>(201, "A token for the new account")
+            .errors(&[400]),
+        Operation::new("create_guest", "Create a guest account")
+            .returns::<String>(201, "A token for the new account"),
+        Operation::new("upgrade_guest", "Turn a guest account into a full account")
+            .auth(Auth::Token)
+            .body::<models::UpgradeGuest>()
+            .returns::<String>(200, "A token for the upgraded account")
+            .errors(&[400]),
+        Operation::new("delete_student", "Delete your account")
+            .auth(Auth::Token)
+            .returns::<String>(200, "How long the account can be restored for")
+            .errors(&[400]),
+        Operation::new("restore_student", "Restore a deleted account")
+            .body::<models::UserCredentials>()
+            .returns::<String>(200, "A token")
+            .returns::<models::MfaChallenge>(
+                202,
+                "A second factor is needed, see `/api/v1/mfa/verify`",
+            )
+            .errors(&[400, 429]),
+        Operation::new(
+            "reset_statistics",
+            "Reset your scores, costumes and achievements",
+        )
+        .auth(Auth::Token)
+        .returns::<models::User>(200, "Your account after the reset")
+        .errors(&[400]),
+        Operation::new("undo_reset_statistics", "Undo a recent reset")
+            .auth(Auth::Token)
+            .returns::<models::User>(200, "Your account as it was before the reset")
+            .errors(&[404]),
+        Operation::new("change_username", "Change your username")
+            .auth(Auth::Token)
+            .body::<models::UnlockCostume>()
+            .returns::<models::User>(200, "Your account")
+            .errors(&[400, 403]),
+        Operation::new("change_nickname", "Change your nickname")
+            .auth(Auth::Token)
+            .body::<models::UnlockCostume>()
+            .returns::<models::User>(200, "Your account")
+            .returns::<models::User>(
+                202,
+                "Your account, with the nickname held for a teacher to approve",
+            )
+            .errors(&[400, 403]),
+        Operation::new(
+            "change_password",
+            "Change your password, signing out everywhere else",
+        )
+        .auth(Auth::Token)
+        .body::<models::ChangePassword>()
+        .returns::<String>(200, "A new token")
+        .errors(&[400, 403]),
+        Operation::new(
+            "issue_password_reset",
+            "Give a student a password reset code",
+        )
+        .auth(Auth::Token)
+        .param::<i32>("student_id")
+        .returns::<models::IssuedPasswordReset>(201, "The code, which is only shown this once")
+        .errors(&[403, 404]),
+        Operation::new(
+            "redeem_password_reset",
+            "Set a new password with a reset code",
+        )
+        .body::<models::RedeemPasswordReset>()
+        .returns::<String>(200, "A token")
+        .returns::<models::MfaChallenge>(202, "A second factor is needed, see `/api/v1/mfa/verify`")
+        .errors(&[400, 429]),
+        Operation::new("set_email", "Add an email address to your account")
+            .auth(Auth::Token)
+            .body::<models::EmailAddress>()
+            .returns::<models::User>(
+                200,
+                "Your account, the email is verified once the link sent to it is followed",
+            )
+            .errors(&[400, 403]),
+        Operation::new("verify_email", "Verify an email address")
+            .body::<models::VerifyEmail>()
+            .returns::<String>(200, "Confirmation the email was verified")
+            .errors(&[400]),
+        Operation::new(
+            "recover_account",
+            "Email a reset code to a verified address",
+        )
+        .body::<models::EmailAddress>()
+        .returns::<String>(200, "Sent whether or not the address belongs to an account")
+        .errors(&[429]),
+        Operation::new(
+            "get_pending_nicknames",
+            "List nicknames waiting for approval",
+        )
+        .auth(Auth::Token)
+        .returns::<Vec<models::PendingNickname>>(200, "Students with a held nickname")
+        .errors(&[400, 403]),
+        Operation::new(
+            "export_student",
+            "Download everything stored about your account",
+        )
+        .auth(Auth::Token)
+        .returns_raw(200, "application/zip", "The export")
+        .errors(&[400]),
+        Operation::new(
+            "export_managed_student",
+            "Download everything stored about a student",
+        )
+        .auth(Auth::Token)
+        .param::<i32>("student_id")
+        .returns_raw(200, "application/zip", "The export")
+        .errors(&[403, 404]),
+        Operation::new("review_nickname", "Approve or reject a held nickname")
+            .auth(Auth::Token)
+            .param::<i32>("student_id")
+            .returns::<models::PendingNickname>(200, "The student after the review")
+            .errors(&[400, 403, 404]),
+        Operation::new("set_user_costume", "Wear a costume")
+            .auth(Auth::Token)
+            .returns::<models::User>(200, "Your account")
+            .errors(&[400]),
+        Operation::new("get_scores", "List scores, optionally for one user")
+            .param::<i64>("offset")
+            .param::<i64>("limit")
+            .param::<i32>("id")
+            .returns::<Vec<models::Score>>(200, "Scores which haven't been voided or flagged"),
+        Operation::new("add_score", "Submit the score from a game")
+            .auth(Auth::Token)
+            .body::<models::NewScore>()
+            .returns::<String>(201, "The score was saved"),
+        Operation::new("unlock_costume", "Buy a costume with stars")
+            .auth(Auth::Token)
+            .body::<models::UnlockCostume>()
+            .returns::<models::User>(200, "Your account")
+            .errors(&[400]),
+        Operation::new("get_costumes", "List your costumes")
+            .auth(Auth::Token)
+            .returns::<Vec<models::Costume>>(200, "The costumes you own")
+            .errors(&[404]),
+        Operation::new("get_costume_information", "List every costume")
+            .returns::<Vec<models::Costume>>(200, "Every costume"),
+        Operation::new("get_costume_image", "A costume's picture")
+            .returns_raw(200, "image/png", "The picture")
+            .redirects("To `/notfound` when there is no such picture"),
+        Operation::new("unlock_achievement", "Unlock an achievement")
+            .auth(Auth::Token)
+            .body::<models::UnlockAchievement>()
+            .returns::<models::User>(200, "Your account")
+            .errors(&[400]),
+        Operation::new("website_resource", "A static file")
+            .returns_raw(200, "*/*", "The file, of whichever type it is")
+            .redirects("To `/notfound` when there is no such file"),
+        Operation::new("health", "Whether the api is up").returns::<String>(200, "Online"),
+        Operation::new(
+            "not_found_stop_point",
+            "Where unknown paths are redirected to",
+        )
+        .returns_raw(200, "text/plain", "Route Not Found"),
+    ]
+}
+
+/// Build the api, ready to be launched
+pub fn rocket() -> rocket::Rocket<rocket::Build> {
+    logging::initialize();
+    //Initalize all globals
+    lazy_static::initialize(&JWT_EXPIRY_TIME_HOURS);
+    keys::initialize();
+    lazy_static::initialize(&BROWSER_BASE_URL);
+    lazy_static::initialize(&PASSWORD_RESET_EXPIRY_MINUTES);
+    lazy_static::initialize(&EMAIL_VERIFICATION_EXPIRY_HOURS);
+    lazy_static::initialize(&ACCOUNT_DELETION_GRACE_DAYS);
+    lazy_static::initialize(&RESET_UNDO_WINDOW_HOURS);
+    lazy_static::initialize(&MAX_STARS_PER_GAME);
+    lazy_static::initialize(&COSTUMES);
+    lazy_static::initialize(&ACHIEVEMENTS);
+    validation::initialize();
+    moderation::initialize();
+    oidc::initialize();
+    //Launch rocket
+    rocket::build()
+        .register("/", catchers![not_found])
+        .mount("/", telemetry::traced(admin::routes()))
+        .mount("/", telemetry::traced(classroom::routes()))
+        .mount("/", telemetry::traced(oidc::routes()))
+        .mount("/", telemetry::traced(mfa::routes()))
+        .mount("/", telemetry::traced(keys::routes()))
+        .mount("/", telemetry::traced(api_keys::routes()))
+        .mount("/", telemetry::traced(sessions::routes()))
+        .mount("/", telemetry::traced(metrics::routes()))
+        .mount("/", telemetry::traced(openapi::routes()))
+        .mount(
+            "/",
+            telemetry::traced(routes![
+                docs,
+                get_student,
+                login_student,
+                login_with_link,
+                login_picture,
+                login_qr,
+                set_picture_password,
+                regenerate_badge,
+                unlock_student,
+                create_student,
+                create_guest,
+                upgrade_guest,
+                delete_student,
+                restore_student,
+                reset_statistics,
+                undo_reset_statistics,
+                change_username,
+                change_nickname,
+                change_password,
+                issue_password_reset,
+                redeem_password_reset,
+                set_email,
+                verify_email,
+                recover_account,
+                get_pending_nicknames,
+                export_student,
+                export_managed_student,
+                review_nickname,
+                set_user_costume,
+                get_scores,
+                add_score,
+                unlock_costume,
+                get_costumes,
+                get_costume_information,
+                get_costume_image,
+                unlock_achievement,
+                website_resource,
+                health,
+                not_found_stop_point,
+            ]),
+        )
+        .attach(logging::RequestLogger)
+        .attach(UsersDbConn::fairing())
+        .attach(metrics::Metrics)
+        .attach(openapi::fairing())
+        .attach(jobs::fairing())
+        .manage(mailer::from_env())
+        .manage(throttle::LoginThrottle::from_env())
+        .manage(oidc::Cache::from_env())
+}
@@ -0,0 +1,136 @@
+use crate::{common, telemetry};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::{Data, Request, Response};
+use std::time::Instant;
+use tracing::{field, Span};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, EnvFilter};
+
+/// The longest request id accepted from a client, anything else gets a new one
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+/// Set up logging. `LOG_FORMAT` is either `json` for log collectors or `pretty` for reading in a terminal,
+/// defaulting to json in release builds. `RUST_LOG` picks what is logged, defaulting to `info`.
+/// Traces are exported too when set up, see `telemetry::layer`.
+pub fn initialize() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let default_format = if cfg!(debug_assertions) { "pretty" } else { "json" };
+    let json = common::env_or("LOG_FORMAT", default_format.to_owned()) == "json";
+    let r = tracing_subscriber::registry()
+        .with(filter)
+        .with(json.then(|| {
+            fmt::layer()
+                .json()
+                .flatten_event(true)
+                .with_current_span(true)
+                .with_span_list(false)
+        }))
+        .with((!json).then(|| fmt::layer().pretty()))
+        .with(telemetry::layer())
+        .try_init();
+    //Logging only needs setting up once, even if the server is built more than once
+    if r.is_err() {
+        tracing::debug!("Logging is already set up");
+    }
+}
+
+/// The span every log for a request is recorded in, kept in the request's local cache
+struct RequestContext {
+    id: String,
+    span: Span,
+    start: Instant,
+}
+
+/// The user who made a request, set once their token has been checked
+struct RequestUser(Option<i32>);
+
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn request_context<'r>(req: &'r Request<'_>) -> &'r RequestContext {
+    req.local_cache(|| {
+        //Load balancers and clients can pass their own id, so their logs can be matched up with ours
+        let id = req
+            .headers()
+            .get_one("X-Request-Id")
+            .filter(|id| valid_request_id(id))
+            .map(|id| id.to_owned())
+            .unwrap_or_else(|| common::generate_code(20));
+        let span = tracing::info_span!(
+            "request",
+            request_id = %id,
+            method = %req.method(),
+            path = %req.uri().path(),
+            route = field::Empty,
+            user_id = field::Empty,
+            otel.kind = "server",
+            otel.name = field::Empty,
+            otel.status_code = field::Empty,
+            http.status_code = field::Empty,
+        );
+        telemetry::set_parent(&span, req.headers());
+        RequestContext {
+            id,
+            span,
+            start: Instant::now(),
+        }
+    })
+}
+
+/// The span to log anything to do with a request in
+pub fn context<'r>(req: &'r Request<'_>) -> &'r Span {
+    &request_context(req).span
+}
+
+/// Record who made a request, once their token has been checked
+pub fn set_user(req: &Request<'_>, user_id: i32) {
+    req.local_cache(|| RequestUser(Some(user_id)));
+}
+
+/// Gives every request an id, echoed back in the `X-Request-Id` header, and logs each one once it is done
+pub struct RequestLogger;
+
+#[rocket::async_trait]
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Logger",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        request_context(req);
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let context = request_context(req);
+        if let Some(route) = req.route().and_then(|r| r.name.as_deref()) {
+            context.span.record("route", &route);
+            context
+                .span
+                .record("otel.name", &format!("{} {}", req.method(), route).as_str());
+        }
+        if let Some(user_id) = req.local_cache(|| RequestUser(None)).0 {
+            context.span.record("user_id", &user_id);
+        }
+        let status = res.status().code;
+        context.span.record("http.status_code", &status);
+        if status >= 500 {
+            context.span.record("otel.status_code", &"ERROR");
+        }
+        let latency_ms = context.start.elapsed().as_millis() as u64;
+        context.span.in_scope(|| {
+            if status >= 500 {
+                tracing::error!(status, latency_ms, "Request finished");
+            } else {
+                tracing::info!(status, latency_ms, "Request finished");
+            }
+        });
+        res.set_header(Header::new("X-Request-Id", context.id.clone()));
+    }
+}
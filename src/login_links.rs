@@ -0,0 +1,86 @@
+use crate::{common, models};
+use rocket::http::Status;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+
+pub enum LinkError {
+    Hash(argon2::password_hash::Error),
+    Database(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for LinkError {
+    fn from(e: diesel::result::Error) -> LinkError {
+        LinkError::Database(e)
+    }
+}
+
+impl From<argon2::password_hash::Error> for LinkError {
+    fn from(e: argon2::password_hash::Error) -> LinkError {
+        LinkError::Hash(e)
+    }
+}
+
+impl From<LinkError> for models::Response {
+    fn from(e: LinkError) -> models::Response {
+        match e {
+            LinkError::Hash(e) => models::ResponseBuilder {
+                data: format!("Unable to hash login link {}", e),
+                status: Status::InternalServerError,
+            }
+            .build(),
+            LinkError::Database(e) => models::Response::database_error(e),
+        }
+    }
+}
+
+/// Create a one-time login link for a user, returning the token to put in it.
+/// Only a hash of the secret is stored, so the token can't be recovered later.
+pub fn create(
+    c: &diesel::PgConnection,
+    usr_id: i32,
+    created_by: Option<i32>,
+    expires_at: NaiveDateTime,
+) -> Result<String, LinkError> {
+    let secret = common::generate_code(24);
+    let link: models::LoginLink = diesel::insert_into(crate::schema::login_links::table)
+        .values(models::InsertableLoginLink {
+            usr_id,
+            secret: common::hash_string_with_salt(secret.clone())?,
+            created_by,
+            expires_at,
+        })
+        .get_result(c)?;
+    Ok(format!("{}.{}", link.id, secret))
+}
+
+/// Use up a login link, returning the user it logs in as.
+/// Returns `None` if the link doesn't exist, has expired or has already been used.
+pub fn redeem(c: &diesel::PgConnection, token: &str) -> Result<Option<models::User>, LinkError> {
+    use crate::schema::login_links::dsl::*;
+    let (link_id, given) = match common::split_token(token) {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+    let now = chrono::Utc::now().naive_utc();
+    let link = login_links
+        .filter(id.eq(link_id))
+        .filter(used_at.is_null())
+        .filter(expires_at.gt(now))
+        .first::<models::LoginLink>(c)
+        .optional()?;
+    let link = match link {
+        Some(l) => l,
+        None => return Ok(None),
+    };
+    if !common::compare_hashed_strings(given.to_owned(), link.secret)? {
+        return Ok(None);
+    }
+    //Only mark the link as used if nobody else got there first
+    let updated = diesel::update(login_links.filter(id.eq(link.id)).filter(used_at.is_null()))
+        .set(used_at.eq(Some(now)))
+        .execute(c)?;
+    if updated == 0 {
+        return Ok(None);
+    }
+    Ok(common::find_user(c, link.usr_id)?)
+}
@@ -0,0 +1,269 @@
+use crate::common::env_opt;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// An email waiting to be sent
+#[derive(Clone, Debug)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Email {
+    /// Sent when a user adds an email to their account, so we know they own it.
+    pub fn verification(to: String, link: String) -> Email {
+        Email {
+            to,
+            subject: "Verify your Kemu Kupu email".into(),
+            body: format!(
+                "Kia ora!\n\nPlease confirm this is your email address by opening the link below.\n\n{}\n\nIf you didn't add this email to a Kemu Kupu account you can ignore this message.",
+                link
+            ),
+        }
+    }
+
+    /// Sent when a user asks to recover their account.
+    pub fn password_reset(to: String, usr: String, code: String, expiry_minutes: i64) -> Email {
+        Email {
+            to,
+            subject: "Reset your Kemu Kupu password".into(),
+            body: format!(
+                "Kia ora {}!\n\nYour password reset code is {}\n\nThis code will expire in {} minutes. If you didn't ask to reset your password you can ignore this message.",
+                usr, code, expiry_minutes
+            ),
+        }
+    }
+
+    /// Sent once an account has been deleted.
+    pub fn account_deleted(to: String, usr: String, grace_days: i64) -> Email {
+        Email {
+            to,
+            subject: "Your Kemu Kupu account has been deleted".into(),
+            body: format!(
+                "Kia ora {}!\n\nThis is a confirmation that your Kemu Kupu account has been deleted.\n\nIf this was a mistake you can restore it by signing in within the next {} days, after that it and all of its scores will be gone for good.\n\nIf you didn't do this, please contact your teacher.",
+                usr, grace_days
+            ),
+        }
+    }
+}
+
+/// Errors which may occur while sending an email
+#[derive(Debug)]
+pub enum MailerError {
+    /// The email address provided could not be parsed
+    InvalidAddress(String),
+    /// The message could not be built or delivered
+    Delivery(String),
+}
+
+impl std::fmt::Display for MailerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MailerError::InvalidAddress(e) => write!(f, "Invalid email address {}", e),
+            MailerError::Delivery(e) => write!(f, "Unable to deliver email {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MailerError {}
+
+/// Something that is able to deliver emails.
+/// Sending is blocking, use `deliver` to send from within a request handler.
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: Email) -> Result<(), MailerError>;
+}
+
+/// Check whether the provided string is a valid email address
+pub fn is_valid_address(address: &str) -> bool {
+    address.parse::<lettre::Address>().is_ok()
+}
+
+fn build_message(from: &Mailbox, email: Email) -> Result<Message, MailerError> {
+    let to = email
+        .to
+        .parse::<Mailbox>()
+        .map_err(|e| MailerError::InvalidAddress(e.to_string()))?;
+    Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(email.subject)
+        .body(email.body)
+        .map_err(|e| MailerError::Delivery(e.to_string()))
+}
+
+/// Delivers mail through an SMTP relay
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: SmtpTransport,
+}
+
+impl SmtpMailer {
+    /// Connect to `host` over TLS, signing in when both a username and password are given
+    pub fn new(
+        from: Mailbox,
+        host: &str,
+        port: Option<u16>,
+        credentials: Option<(String, String)>,
+    ) -> Result<SmtpMailer, MailerError> {
+        let mut builder =
+            SmtpTransport::relay(host).map_err(|e| MailerError::Delivery(e.to_string()))?;
+        if let Some(port) = port {
+            builder = builder.port(port);
+        }
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+        Ok(SmtpMailer {
+            from,
+            transport: builder.build(),
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: Email) -> Result<(), MailerError> {
+        let message = build_message(&self.from, email)?;
+        self.transport
+            .send(&message)
+            .map_err(|e| MailerError::Delivery(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Writes every email to a directory as an `.eml` file, useful during development
+pub struct FileMailer {
+    from: Mailbox,
+    dir: PathBuf,
+    count: AtomicUsize,
+}
+
+impl FileMailer {
+    pub fn new(from: Mailbox, dir: PathBuf) -> Result<FileMailer, MailerError> {
+        std::fs::create_dir_all(&dir).map_err(|e| MailerError::Delivery(e.to_string()))?;
+        Ok(FileMailer {
+            from,
+            dir,
+            count: AtomicUsize::new(0),
+        })
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: Email) -> Result<(), MailerError> {
+        let message = build_message(&self.from, email)?;
+        let file_name = format!(
+            "{}-{}.eml",
+            chrono::Utc::now().format("%Y%m%d%H%M%S%f"),
+            self.count.fetch_add(1, Ordering::SeqCst)
+        );
+        std::fs::write(self.dir.join(file_name), message.formatted())
+            .map_err(|e| MailerError::Delivery(e.to_string()))
+    }
+}
+
+/// Keeps every email in memory, so that they can be inspected in tests
+#[derive(Default)]
+pub struct MemoryMailer {
+    sent: Mutex<Vec<Email>>,
+}
+
+#[cfg(test)]
+impl MemoryMailer {
+    /// All the emails sent so far
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send(&self, email: Email) -> Result<(), MailerError> {
+        if !is_valid_address(&email.to) {
+            return Err(MailerError::InvalidAddress(email.to));
+        }
+        self.sent.lock().unwrap().push(email);
+        Ok(())
+    }
+}
+
+/// Create the mailer described by the `MAILER` env var, one of `smtp`, `file` or `memory`.
+/// There is no default, so a deployment that forgets it fails to start rather than quietly not sending mail.
+pub fn from_env() -> Arc<dyn Mailer> {
+    let from: Mailbox = env_opt("MAIL_FROM")
+        .unwrap_or_else(|| "Kemu Kupu <no-reply@kemukupu.com>".into())
+        .parse()
+        .expect("Env var MAIL_FROM is not a valid mailbox!");
+    match env_opt("MAILER").expect("Env var MAILER not set!").as_str() {
+        "smtp" => {
+            let host = env_opt("SMTP_HOST").expect("Env var SMTP_HOST not set!");
+            let port = env_opt("SMTP_PORT")
+                .map(|p| p.parse().expect("Env var SMTP_PORT is not a valid port!"));
+            let credentials = match (env_opt("SMTP_USERNAME"), env_opt("SMTP_PASSWORD")) {
+                (Some(username), Some(password)) => Some((username, password)),
+                _ => None,
+            };
+            Arc::new(
+                SmtpMailer::new(from, &host, port, credentials)
+                    .expect("Unable to configure the SMTP mailer"),
+            )
+        }
+        "file" => {
+            let dir = env_opt("MAIL_DROP_DIR").unwrap_or_else(|| "./mail".into());
+            Arc::new(
+                FileMailer::new(from, PathBuf::from(dir))
+                    .expect("Unable to create the directory given by MAIL_DROP_DIR"),
+            )
+        }
+        "memory" => Arc::new(MemoryMailer::default()),
+        other => panic!("Env var MAILER has unknown value {}, expected smtp, file or memory", other),
+    }
+}
+
+/// Send an email without blocking the async runtime.
+pub async fn deliver(mailer: Arc<dyn Mailer>, email: Email) -> Result<(), MailerError> {
+    rocket::tokio::task::spawn_blocking(move || mailer.send(email))
+        .await
+        .map_err(|e| MailerError::Delivery(e.to_string()))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email(to: &str) -> Email {
+        Email::verification(to.into(), "https://kemukupu.com/verify?token=abc".into())
+    }
+
+    #[test]
+    fn memory_mailer_keeps_what_was_sent_in_order() {
+        let mailer = MemoryMailer::default();
+        mailer.send(email("aroha@example.com")).unwrap();
+        mailer.send(email("tama@example.com")).unwrap();
+        let sent: Vec<String> = mailer.sent().into_iter().map(|e| e.to).collect();
+        assert_eq!(sent, vec!["aroha@example.com", "tama@example.com"]);
+    }
+
+    #[test]
+    fn memory_mailer_refuses_invalid_addresses() {
+        let mailer = MemoryMailer::default();
+        assert!(matches!(
+            mailer.send(email("not an address")),
+            Err(MailerError::InvalidAddress(_))
+        ));
+        assert!(mailer.sent().is_empty());
+    }
+
+    #[test]
+    fn messages_are_built_from_the_email() {
+        let from: Mailbox = "Kemu Kupu <no-reply@kemukupu.com>".parse().unwrap();
+        let message = build_message(&from, email("aroha@example.com")).unwrap();
+        let formatted = String::from_utf8(message.formatted()).unwrap();
+        assert!(formatted.contains("To: aroha@example.com"));
+        assert!(formatted.contains("Subject: Verify your Kemu Kupu email"));
+        assert!(build_message(&from, email("aroha@")).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use crate::openapi::{Auth, Operation};
+use crate::{common, models};
+use lazy_static::lazy_static;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Request};
+use sha2::{Digest, Sha256};
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new_custom(Some("kemu".into()), None)
+        .expect("Unable to create the metrics registry");
+    static ref HTTP_REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("http_requests_total", "Requests handled, by route and status"),
+        &["method", "route", "status"],
+    ));
+    static ref HTTP_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("http_request_duration_seconds", "How long requests took, by route and status"),
+        &["method", "route", "status"],
+    ));
+    static ref DB_POOL_SIZE: IntGauge = register(IntGauge::new(
+        "db_pool_size",
+        "The most database connections the pool will open"
+    ));
+    static ref DB_POOL_IN_USE: IntGauge = register(IntGauge::new(
+        "db_pool_connections_in_use",
+        "Database connections currently taken from the pool by requests and background jobs"
+    ));
+    static ref DB_POOL_WAIT: Histogram = register(Histogram::with_opts(HistogramOpts::new(
+        "db_pool_wait_seconds",
+        "How long requests waited for a database connection from the pool"
+    )));
+    static ref DB_POOL_TIMEOUTS: IntCounter = register(IntCounter::new(
+        "db_pool_timeouts_total",
+        "Requests which gave up waiting for a free database connection"
+    ));
+    static ref LOGINS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("logins_total", "Login attempts, by how they logged in and whether it worked"),
+        &["method", "outcome"],
+    ));
+    static ref ACCOUNTS_CREATED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("accounts_created_total", "Accounts created, by how they were created"),
+        &["kind"],
+    ));
+    static ref SCORES_SUBMITTED: IntCounter = register(IntCounter::new(
+        "scores_submitted_total",
+        "Scores submitted at the end of a game"
+    ));
+    static ref STARS_EARNED: IntCounter = register(IntCounter::new(
+        "stars_earned_total",
+        "Stars earned from submitted scores"
+    ));
+    static ref COSTUMES_UNLOCKED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("costumes_unlocked_total", "Costumes unlocked, by costume"),
+        &["costume"],
+    ));
+    /// Scrapes must send this as a bearer token in the `Authorization` header when it is set
+    static ref METRICS_TOKEN: Option<String> = common::env_opt("METRICS_TOKEN");
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![get_metrics]
+}
+
+pub fn operations() -> Vec<Operation> {
+    vec![Operation::new("get_metrics", "Metrics for Prometheus")
+        .auth(Auth::MetricsToken)
+        .returns_raw(
+            200,
+            "text/plain",
+            "Every metric in the Prometheus text format",
+        )]
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(
+    collector: Result<T, prometheus::Error>,
+) -> T {
+    let collector = collector.expect("Invalid metric");
+    REGISTRY
+        .register(Box::new(collector.clone()))
+        .expect("Metric registered twice");
+    collector
+}
+
+/// Record a login attempt, `method` is how they logged in such as `password` or `qr`
+pub fn login(method: &str, success: bool) {
+    let outcome = if success { "success" } else { "failure" };
+    LOGINS.with_label_values(&[method, outcome]).inc();
+}
+
+/// Record a new account, `kind` is how it was created such as `student` or `guest`
+pub fn account_created(kind: &str, count: u64) {
+    ACCOUNTS_CREATED.with_label_values(&[kind]).inc_by(count);
+}
+
+/// Record a score submitted at the end of a game
+pub fn score_submitted(stars: i32) {
+    SCORES_SUBMITTED.inc();
+    if stars > 0 {
+        STARS_EARNED.inc_by(stars as u64);
+    }
+}
+
+/// Record how long a request waited for a database connection, and whether it got one
+pub fn pool_wait(waited: Duration, acquired: bool) {
+    if acquired {
+        DB_POOL_WAIT.observe(waited.as_secs_f64());
+    } else {
+        DB_POOL_TIMEOUTS.inc();
+    }
+}
+
+/// Record a database connection being taken from the pool
+pub fn connection_taken() {
+    DB_POOL_IN_USE.inc();
+}
+
+/// Record a database connection being given back to the pool
+pub fn connection_returned() {
+    DB_POOL_IN_USE.dec();
+}
+
+/// Record a costume being unlocked
+pub fn costume_unlocked(costume: &str) {
+    COSTUMES_UNLOCKED.with_label_values(&[costume]).inc();
+}
+
+/// When a request started, kept in the request's local cache
+struct RequestStart(Option<Instant>);
+
+/// Records the count and latency of every request by route and status
+pub struct Metrics;
+
+#[rocket::async_trait]
+impl Fairing for Metrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut rocket::Data<'_>) {
+        req.local_cache(|| RequestStart(Some(Instant::now())));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut rocket::Response<'r>) {
+        //Routes are labelled by their handler so that ids in paths don't create a series each
+        let route = req
+            .route()
+            .and_then(|r| r.name.as_deref())
+            .unwrap_or("unmatched");
+        let method = req.method().as_str();
+        let status = res.status().code.to_string();
+        let labels = [method, route, status.as_str()];
+        HTTP_REQUESTS.with_label_values(&labels).inc();
+        if let Some(start) = req.local_cache(|| RequestStart(None)).0 {
+            HTTP_DURATION
+                .with_label_values(&labels)
+                .observe(start.elapsed().as_secs_f64());
+        }
+    }
+}
+
+/// Checks the bearer token when `METRICS_TOKEN` is set.
+/// This uses the standard `Authorization` header rather than `Authorisation` like other routes, as that is what Prometheus sends.
+struct MetricsAuth;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetricsAuth {
+    type Error = models::Response;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, models::Response> {
+        let expected = match &*METRICS_TOKEN {
+            Some(t) => t,
+            None => return request::Outcome::Success(MetricsAuth),
+        };
+        let given = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "));
+        //Compare hashes in constant time, so the time taken doesn't give away how much of the token was right
+        if let Some(given) = given {
+            let given = Sha256::digest(given.as_bytes());
+            let expected = Sha256::digest(expected.as_bytes());
+            if bool::from(given.as_slice().ct_eq(expected.as_slice())) {
+                return request::Outcome::Success(MetricsAuth);
+            }
+        }
+        request::Outcome::Failure((
+            Status::Unauthorized,
+            models::ResponseBuilder {
+                data: "Invalid Metrics Token",
+                status: Status::Unauthorized,
+            }
+            .build(),
+        ))
+    }
+}
+
+/// The server, so the size of the database pool can be read when metrics are scraped
+struct Server<'r>(&'r rocket::Rocket<rocket::Orbit>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Server<'r> {
+    type Error = ();
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        request::Outcome::Success(Server(req.rocket()))
+    }
+}
+
+impl Server<'_> {
+    fn record_pool_size(&self) {
+        //Read the same way rocket_sync_db_pools does, which defaults to four connections per worker
+        let pool_size = self
+            .0
+            .figment()
+            .extract_inner::<u32>("databases.postgres_database.pool_size")
+            .unwrap_or(self.0.config().workers as u32 * 4);
+        DB_POOL_SIZE.set(pool_size as i64);
+    }
+}
+
+/// Every metric in the Prometheus text format
+#[get("/api/metrics")]
+async fn get_metrics(
+    auth: Result<MetricsAuth, models::Response>,
+    server: Server<'_>,
+) -> Result<(ContentType, String), models::Response> {
+    auth?;
+    server.record_pool_size();
+    let mut buffer = vec![];
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&REGISTRY.gather(), &mut buffer) {
+        return Err(models::ResponseBuilder {
+            data: format!("Unable to encode metrics {}", e),
+            status: Status::InternalServerError,
+        }
+        .build());
+    }
+    Ok((
+        ContentType::with_params("text", "plain", ("version", "0.0.4")),
+        String::from_utf8(buffer).unwrap_or_default(),
+    ))
+}
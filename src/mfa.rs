@@ -0,0 +1,635 @@
+use crate::openapi::{Auth, Operation};
+use crate::{audit, common, metrics, models, sessions, throttle, UsersDbConn};
+use diesel::prelude::*;
+use hmac::{Hmac, Mac, NewMac};
+use rand_core::{OsRng, RngCore};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use sha1::Sha1;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Shown in authenticator apps next to the code
+const ISSUER: &str = "Kemu Kupu";
+const STEP_SECONDS: u64 = 30;
+const DIGITS: u32 = 6;
+const RECOVERY_CODES: usize = 10;
+const BASE32: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![
+        start_enrolment,
+        confirm_enrolment,
+        verify,
+        disable,
+        regenerate_recovery_codes
+    ]
+}
+
+pub fn operations() -> Vec<Operation> {
+    vec![
+        Operation::new(
+            "start_enrolment",
+            "Start turning on two-factor authentication, staff can use the token from logging in",
+        )
+        .auth(Auth::Token)
+        .returns::<models::MfaEnrolment>(200, "A secret to add to an authenticator app")
+        .errors(&[403, 409]),
+        Operation::new(
+            "confirm_enrolment",
+            "Finish turning on two-factor authentication with a code from the authenticator",
+        )
+        .auth(Auth::Token)
+        .body::<models::MfaCode>()
+        .returns::<models::RecoveryCodes>(200, "Recovery codes, and a token if logging in")
+        .errors(&[400, 403, 409]),
+        Operation::new("verify", "Finish logging in with a second factor")
+            .auth(Auth::MfaPending)
+            .body::<models::MfaCode>()
+            .returns::<String>(200, "A token")
+            .errors(&[400, 429]),
+        Operation::new("disable", "Turn off two-factor authentication")
+            .auth(Auth::Token)
+            .body::<models::MfaCode>()
+            .returns::<String>(200, "Confirmation it was turned off")
+            .errors(&[400, 403, 429]),
+        Operation::new("regenerate_recovery_codes", "Replace your recovery codes")
+            .auth(Auth::Token)
+            .body::<models::MfaCode>()
+            .returns::<models::RecoveryCodes>(200, "The new recovery codes")
+            .errors(&[400, 429]),
+    ]
+}
+
+pub enum MfaError {
+    Hash(String),
+    Database(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for MfaError {
+    fn from(e: diesel::result::Error) -> MfaError {
+        MfaError::Database(e)
+    }
+}
+
+impl From<MfaError> for models::Response {
+    fn from(e: MfaError) -> models::Response {
+        match e {
+            MfaError::Hash(e) => models::ResponseBuilder {
+                data: format!("Unable to hash recovery code {}", e),
+                status: Status::InternalServerError,
+            }
+            .build(),
+            MfaError::Database(e) => models::Response::database_error(e),
+        }
+    }
+}
+
+/// The response to someone who has proven who they are with a password or similar.
+/// Staff, and students who have turned on two-factor authentication, are given a short lived token to
+/// exchange at `/api/v1/mfa/verify` rather than a full one. Staff who haven't enrolled yet use it to enrol.
+pub async fn login_response(
+    conn: &UsersDbConn,
+    device: sessions::Device,
+    user: &models::User,
+) -> models::Response {
+    if user.is_staff() || user.totp_enabled_at.is_some() {
+        return models::ResponseBuilder {
+            data: models::MfaChallenge {
+                mfa_token: models::Claims::new_mfa_pending_token(user),
+                enrolled: user.totp_enabled_at.is_some(),
+            },
+            status: Status::Accepted,
+        }
+        .build();
+    }
+    sessions::issue(conn, user, device, Status::Ok).await
+}
+
+/// Generate a new secret, base32 encoded as authenticator apps expect
+fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    OsRng.fill_bytes(&mut bytes);
+    base32::encode(BASE32, &bytes)
+}
+
+fn current_step() -> i64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards!")
+        .as_secs();
+    (now / STEP_SECONDS) as i64
+}
+
+/// The code for a time step, as described in RFC 6238
+fn code_at(key: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    (value & 0x7fff_ffff) % 10u32.pow(DIGITS)
+}
+
+/// Check a code against a secret, allowing for a step of clock drift either way.
+/// Returns the step the code is for, which has to be after `last_step` so a code can't be used twice.
+fn check_code(secret: &str, code: &str, last_step: i64) -> Option<i64> {
+    let key = base32::decode(BASE32, secret)?;
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let now = current_step();
+    (now - 1..=now + 1).find(|step| *step > last_step && code_at(&key, *step) == code)
+}
+
+/// Percent encode a string for an otpauth uri
+fn encode_component(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// The uri authenticator apps read from the QR code, see https://github.com/google/google-authenticator/wiki/Key-Uri-Format
+fn otpauth_uri(usr: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        encode_component(ISSUER),
+        encode_component(usr),
+        secret,
+        encode_component(ISSUER),
+        DIGITS,
+        STEP_SECONDS
+    )
+}
+
+/// Mark a step as used, returning false if it, or a later one, already has been
+fn claim_step(c: &diesel::PgConnection, user_id: i32, step: i64) -> Result<bool, diesel::result::Error> {
+    use crate::schema::users::dsl::*;
+    let updated = diesel::update(users.filter(id.eq(user_id)).filter(totp_last_step.lt(step)))
+        .set(totp_last_step.eq(step))
+        .execute(c)?;
+    Ok(updated > 0)
+}
+
+/// Check a code from the authenticator app given by someone already signed in, claiming its step so it can't be
+/// used again. Six digits can be guessed, so failures share the lockout with passwords just like `verify`.
+async fn check_signed_in_code(
+    conn: &UsersDbConn,
+    throttle: &throttle::LoginThrottle,
+    ip: Option<IpAddr>,
+    user: &models::User,
+    secret: String,
+    code: String,
+) -> Result<(), models::Response> {
+    let ip = ip.map(|i| i.to_string());
+    if let Err(wait) = throttle.check(&user.usr, ip.as_deref()) {
+        return Err(throttle::too_many_attempts(wait));
+    }
+    let user_id = user.id;
+    let claimed = match check_code(&secret, &code, user.totp_last_step) {
+        Some(step) => conn
+            .run(move |c| claim_step(c, user_id, step))
+            .await
+            .map_err(MfaError::Database)?,
+        None => false,
+    };
+    if !claimed {
+        throttle.record_failure(&user.usr, ip.as_deref());
+        return Err(models::ResponseBuilder {
+            data: "Incorrect Code",
+            status: Status::BadRequest,
+        }
+        .build());
+    }
+    throttle.record_success(&user.usr);
+    Ok(())
+}
+
+/// Replace every recovery code a user has, returning the new ones. Only their hashes are kept.
+fn replace_recovery_codes(c: &diesel::PgConnection, user_id: i32) -> Result<Vec<String>, MfaError> {
+    use crate::schema::recovery_codes::dsl::*;
+    diesel::delete(recovery_codes.filter(usr_id.eq(user_id))).execute(c)?;
+    let mut created = vec![];
+    for _ in 0..RECOVERY_CODES {
+        let plain = format!("{}-{}", common::generate_code(5), common::generate_code(5));
+        let hashed = common::hash_string_with_salt(plain.clone()).map_err(|e| MfaError::Hash(e.to_string()))?;
+        diesel::insert_into(recovery_codes)
+            .values(models::InsertableRecoveryCode {
+                usr_id: user_id,
+                code: hashed,
+            })
+            .execute(c)?;
+        created.push(plain);
+    }
+    Ok(created)
+}
+
+/// Use up a recovery code, returning whether it was valid
+fn use_recovery_code(c: &diesel::PgConnection, user_id: i32, given: &str) -> Result<bool, MfaError> {
+    use crate::schema::recovery_codes::dsl::*;
+    let given = given.trim().to_uppercase();
+    let unused: Vec<(i32, String)> = recovery_codes
+        .filter(usr_id.eq(user_id))
+        .filter(used_at.is_null())
+        .select((id, code))
+        .load(c)?;
+    for (code_id, hashed) in unused {
+        let matches = common::compare_hashed_strings(given.clone(), hashed).map_err(|e| MfaError::Hash(e.to_string()))?;
+        if matches {
+            diesel::update(recovery_codes.filter(id.eq(code_id)))
+                .set(used_at.eq(Some(chrono::Utc::now().naive_utc())))
+                .execute(c)?;
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+async fn load_user(conn: &UsersDbConn, user_id: i32) -> Result<models::User, models::Response> {
+    match conn.run(move |c| common::find_user(c, user_id)).await {
+        Ok(Some(u)) => Ok(u),
+        Ok(None) => Err(models::ResponseBuilder {
+            data: "User Not Found",
+            status: Status::NotFound,
+        }
+        .build()),
+        Err(e) => Err(MfaError::Database(e).into()),
+    }
+}
+
+/// Work out who is enrolling, from either a full token or one from the first step of logging in.
+/// Returns their id and whether they are part way through logging in.
+fn enrolling_user(
+    token: Result<models::Claims, models::Response>,
+    pending: Option<models::MfaPending>,
+) -> Result<(i32, bool), models::Response> {
+    if let Some(pending) = pending {
+        return Ok((pending.0.sub, true));
+    }
+    let token = token?;
+    token.deny_guest()?;
+    Ok((token.sub, false))
+}
+
+/// Start turning on two-factor authentication, returning a secret to add to an authenticator app.
+/// Staff who haven't enrolled yet can use the token from the first step of logging in.
+#[post("/api/v1/mfa/enrol")]
+async fn start_enrolment(
+    token: Result<models::Claims, models::Response>,
+    pending: Option<models::MfaPending>,
+    conn: UsersDbConn,
+) -> Result<models::Response, models::Response> {
+    let (user_id, _) = enrolling_user(token, pending)?;
+    let user = load_user(&conn, user_id).await?;
+    if user.totp_enabled_at.is_some() {
+        return Err(models::ResponseBuilder {
+            data: "Two-factor authentication is already on",
+            status: Status::Conflict,
+        }
+        .build());
+    }
+
+    let secret = generate_secret();
+    let otpauth_uri = otpauth_uri(&user.usr, &secret);
+    let qr_svg = match qrcode::QrCode::new(otpauth_uri.as_bytes()) {
+        Ok(code) => code
+            .render::<qrcode::render::svg::Color>()
+            .min_dimensions(200, 200)
+            .build(),
+        Err(e) => {
+            return Err(models::ResponseBuilder {
+                data: format!("Unable to create QR code {}", e.to_string()),
+                status: Status::InternalServerError,
+            }
+            .build())
+        }
+    };
+    let stored = secret.clone();
+    conn.run(move |c| {
+        use crate::schema::users::dsl::*;
+        diesel::update(users.filter(id.eq(user_id)))
+            .set(totp_secret.eq(Some(stored)))
+            .execute(c)
+    })
+    .await
+    .map_err(MfaError::Database)?;
+
+    Ok(models::ResponseBuilder {
+        data: models::MfaEnrolment {
+            secret,
+            otpauth_uri,
+            qr_svg,
+        },
+        status: Status::Ok,
+    }
+    .build())
+}
+
+/// Finish turning on two-factor authentication with a code from the authenticator app, returning recovery codes.
+/// Also returns a full token if this was part of logging in.
+#[post("/api/v1/mfa/enrol/confirm", data = "<code>", format = "application/json")]
+async fn confirm_enrolment(
+    token: Result<models::Claims, models::Response>,
+    pending: Option<models::MfaPending>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    device: sessions::Device,
+    code: Json<models::MfaCode>,
+) -> Result<models::Response, models::Response> {
+    let (user_id, logging_in) = enrolling_user(token, pending)?;
+    let user = load_user(&conn, user_id).await?;
+    let secret = match (&user.totp_secret, user.totp_enabled_at) {
+        (Some(s), None) => s.clone(),
+        (_, Some(_)) => {
+            return Err(models::ResponseBuilder {
+                data: "Two-factor authentication is already on",
+                status: Status::Conflict,
+            }
+            .build())
+        }
+        (None, None) => {
+            return Err(models::ResponseBuilder {
+                data: "Start enrolling at /api/v1/mfa/enrol first",
+                status: Status::BadRequest,
+            }
+            .build())
+        }
+    };
+    let step = match check_code(&secret, &code.into_inner().code, user.totp_last_step) {
+        Some(s) => s,
+        None => {
+            return Err(models::ResponseBuilder {
+                data: "Incorrect Code",
+                status: Status::BadRequest,
+            }
+            .build())
+        }
+    };
+
+    let r: Result<(models::User, Vec<String>, Option<i32>), MfaError> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                use crate::schema::users::dsl::*;
+                let now = chrono::Utc::now().naive_utc();
+                let updated: models::User = diesel::update(users.filter(id.eq(user_id)))
+                    .set((totp_enabled_at.eq(Some(now)), totp_last_step.eq(step)))
+                    .get_result(c)?;
+                let codes = replace_recovery_codes(c, user_id)?;
+                recorder.event(Some(user_id), Some(user_id), "mfa.enable").save(c)?;
+                let session = if logging_in {
+                    Some(sessions::start(c, user_id, device)?)
+                } else {
+                    None
+                };
+                Ok((updated, codes, session))
+            })
+        })
+        .await;
+    let (user, codes, session) = r?;
+
+    Ok(models::ResponseBuilder {
+        data: models::RecoveryCodes {
+            recovery_codes: codes,
+            token: session.map(|sid| models::Claims::new_token(&user, sid)),
+        },
+        status: Status::Ok,
+    }
+    .build())
+}
+
+/// The second step of logging in, exchanging the token from the first step and a code for a full token.
+/// A recovery code can be given instead of one from the authenticator app.
+#[post("/api/v1/mfa/verify", data = "<code>", format = "application/json")]
+async fn verify(
+    pending: Result<models::MfaPending, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    code: Json<models::MfaCode>,
+    ip: Option<IpAddr>,
+    device: sessions::Device,
+    throttle: &State<throttle::LoginThrottle>,
+) -> Result<models::Response, models::Response> {
+    let pending = pending?.0;
+    let user = load_user(&conn, pending.sub).await?;
+    let ip = ip.map(|i| i.to_string());
+
+    //Six digits can be guessed, so these share the lockout with passwords
+    if let Err(wait) = throttle.check(&user.usr, ip.as_deref()) {
+        return Err(throttle::too_many_attempts(wait));
+    }
+    let secret = match (&user.totp_secret, user.totp_enabled_at) {
+        (Some(s), Some(_)) => s.clone(),
+        _ => {
+            return Err(models::ResponseBuilder {
+                data: "Two-factor authentication isn't on for this account, enrol at /api/v1/mfa/enrol",
+                status: Status::BadRequest,
+            }
+            .build())
+        }
+    };
+
+    let code = code.into_inner().code;
+    let user_id = user.id;
+    let last_step = user.totp_last_step;
+    let r: Result<bool, MfaError> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                if let Some(step) = check_code(&secret, &code, last_step) {
+                    return Ok(claim_step(c, user_id, step)?);
+                }
+                if use_recovery_code(c, user_id, &code)? {
+                    recorder.event(Some(user_id), Some(user_id), "mfa.recovery").save(c)?;
+                    return Ok(true);
+                }
+                Ok(false)
+            })
+        })
+        .await;
+
+    if !r? {
+        throttle.record_failure(&user.usr, ip.as_deref());
+        metrics::login("mfa", false);
+        return Err(models::ResponseBuilder {
+            data: "Incorrect Code",
+            status: Status::BadRequest,
+        }
+        .build());
+    }
+    throttle.record_success(&user.usr);
+    metrics::login("mfa", true);
+    Ok(sessions::issue(&conn, &user, device, Status::Ok).await)
+}
+
+/// Turn off two-factor authentication, which staff can't do as it is required for them
+#[delete("/api/v1/mfa", data = "<code>", format = "application/json")]
+async fn disable(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    code: Json<models::MfaCode>,
+    ip: Option<IpAddr>,
+    throttle: &State<throttle::LoginThrottle>,
+) -> Result<models::Response, models::Response> {
+    let token = token?;
+    let user = load_user(&conn, token.sub).await?;
+    if user.is_staff() {
+        return Err(models::ResponseBuilder {
+            data: "Two-factor authentication is required for staff accounts",
+            status: Status::Forbidden,
+        }
+        .build());
+    }
+    let secret = match (&user.totp_secret, user.totp_enabled_at) {
+        (Some(s), Some(_)) => s.clone(),
+        _ => {
+            return Err(models::ResponseBuilder {
+                data: "Two-factor authentication isn't on",
+                status: Status::BadRequest,
+            }
+            .build())
+        }
+    };
+    check_signed_in_code(&conn, throttle, ip, &user, secret, code.into_inner().code).await?;
+
+    let user_id = user.id;
+    let r: Result<(), diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                {
+                    use crate::schema::users::dsl::*;
+                    diesel::update(users.filter(id.eq(user_id)))
+                        .set((
+                            totp_secret.eq(None::<String>),
+                            totp_enabled_at.eq(None::<chrono::NaiveDateTime>),
+                            totp_last_step.eq(0),
+                        ))
+                        .execute(c)?;
+                }
+                {
+                    use crate::schema::recovery_codes::dsl::*;
+                    diesel::delete(recovery_codes.filter(usr_id.eq(user_id))).execute(c)?;
+                }
+                recorder.event(Some(user_id), Some(user_id), "mfa.disable").save(c)
+            })
+        })
+        .await;
+    r.map_err(MfaError::Database)?;
+
+    Ok(models::ResponseBuilder {
+        data: "Two-factor authentication turned off",
+        status: Status::Ok,
+    }
+    .build())
+}
+
+/// Replace all recovery codes with new ones, for when they have been used up or lost
+#[post("/api/v1/mfa/recovery-codes", data = "<code>", format = "application/json")]
+async fn regenerate_recovery_codes(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    code: Json<models::MfaCode>,
+    ip: Option<IpAddr>,
+    throttle: &State<throttle::LoginThrottle>,
+) -> Result<models::Response, models::Response> {
+    let token = token?;
+    let user = load_user(&conn, token.sub).await?;
+    let secret = match (&user.totp_secret, user.totp_enabled_at) {
+        (Some(s), Some(_)) => s.clone(),
+        _ => {
+            return Err(models::ResponseBuilder {
+                data: "Two-factor authentication isn't on",
+                status: Status::BadRequest,
+            }
+            .build())
+        }
+    };
+    check_signed_in_code(&conn, throttle, ip, &user, secret, code.into_inner().code).await?;
+
+    let user_id = user.id;
+    let r: Result<Vec<String>, MfaError> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                let codes = replace_recovery_codes(c, user_id)?;
+                recorder.event(Some(user_id), Some(user_id), "mfa.recovery_codes").save(c)?;
+                Ok(codes)
+            })
+        })
+        .await;
+
+    Ok(models::ResponseBuilder {
+        data: models::RecoveryCodes {
+            recovery_codes: r?,
+            token: None,
+        },
+        status: Status::Ok,
+    }
+    .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA1 secret from the test vectors in RFC 6238
+    const RFC_KEY: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn codes_match_the_rfc_test_vectors() {
+        //The RFC lists 8 digit codes, these are their last 6 digits
+        assert_eq!(code_at(RFC_KEY, 59 / 30), 287082);
+        assert_eq!(code_at(RFC_KEY, 1111111109 / 30), 81804);
+        assert_eq!(code_at(RFC_KEY, 1234567890 / 30), 5924);
+    }
+
+    #[test]
+    fn codes_are_accepted_with_a_step_of_drift() {
+        let secret = base32::encode(BASE32, RFC_KEY);
+        let now = current_step();
+        for step in &[now - 1, now, now + 1] {
+            let code = format!("{:06}", code_at(RFC_KEY, *step));
+            assert_eq!(check_code(&secret, &code, 0), Some(*step));
+        }
+        let stale = format!("{:06}", code_at(RFC_KEY, now - 5));
+        assert_eq!(check_code(&secret, &stale, 0), None);
+    }
+
+    #[test]
+    fn codes_for_used_steps_are_refused() {
+        let secret = base32::encode(BASE32, RFC_KEY);
+        let step = current_step() - 1;
+        let code = format!("{:06}", code_at(RFC_KEY, step));
+        assert_eq!(check_code(&secret, &code, step), None);
+    }
+
+    #[test]
+    fn badly_formed_codes_are_refused() {
+        let secret = base32::encode(BASE32, RFC_KEY);
+        let code = format!("{:06}", code_at(RFC_KEY, current_step()));
+        let spaced = format!("{} {}", &code[..3], &code[3..]);
+        assert!(check_code(&secret, &spaced, 0).is_some());
+        assert_eq!(check_code(&secret, "12345", 0), None);
+        assert_eq!(check_code(&secret, "abcdef", 0), None);
+        assert_eq!(check_code("not base32!", &code, 0), None);
+    }
+
+    #[test]
+    fn secrets_round_trip_through_base32() {
+        let secret = generate_secret();
+        assert_eq!(base32::decode(BASE32, &secret).map(|k| k.len()), Some(20));
+        assert_ne!(secret, generate_secret());
+    }
+
+    #[test]
+    fn otpauth_uri_escapes_the_username() {
+        let uri = otpauth_uri("mere anaru", "ABC");
+        assert!(uri.starts_with("otpauth://totp/Kemu%20Kupu:mere%20anaru?secret=ABC&issuer=Kemu%20Kupu"));
+        assert!(uri.ends_with("&digits=6&period=30"));
+    }
+}
@@ -1,28 +1,97 @@
 use crate::schema::*;
-use crate::{ACHIEVEMENTS, COSTUMES, JWT_EXPIRY_TIME_HOURS, JWT_SECRET};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use crate::{keys, logging, UsersDbConn, ACHIEVEMENTS, COSTUMES, JWT_EXPIRY_TIME_HOURS};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
 use rocket::http::{ContentType, Status};
 use rocket::request::{self, FromRequest, Request};
 use rocket::serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
 use std::io::Cursor;
+use std::marker::PhantomData;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 fn wrap(s: String) -> String {
     format!("{{\"data\": {}}}", s)
 }
 /// User credentials, to be used when logging in or creating a new account
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub struct UserCredentials {
     pub usr: String,
     pub pwd: String,
 }
 
-#[derive(Deserialize, Insertable)]
+/// Picture password credentials, for students too young to type a password
+#[derive(Deserialize, JsonSchema)]
+pub struct PictureCredentials {
+    pub usr: String,
+    /// Costume names, in order
+    pub pictures: Vec<String>,
+}
+
+/// A new picture password set by a teacher
+#[derive(Deserialize, JsonSchema)]
+pub struct PicturePassword {
+    pub pictures: Vec<String>,
+}
+
+/// A code from an authenticator app, or a recovery code
+#[derive(Deserialize, JsonSchema)]
+pub struct MfaCode {
+    pub code: String,
+}
+
+/// Returned by the first step of logging in when a second factor is needed
+#[derive(Serialize, JsonSchema)]
+pub struct MfaChallenge {
+    /// Exchanged for a full token at `/api/v1/mfa/verify`, or used to enrol if `enrolled` is false
+    pub mfa_token: String,
+    pub enrolled: bool,
+}
+
+/// A new TOTP secret, to be added to an authenticator app
+#[derive(Serialize, JsonSchema)]
+pub struct MfaEnrolment {
+    pub secret: String,
+    pub otpauth_uri: String,
+    /// The otpauth uri as a QR code
+    pub qr_svg: String,
+}
+
+/// Recovery codes, shown once when they are created
+#[derive(Serialize, JsonSchema)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+    /// A full token, when enrolment finishes logging in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+#[derive(Insertable)]
+#[table_name = "recovery_codes"]
+pub struct InsertableRecoveryCode {
+    pub usr_id: i32,
+    /// Hashed
+    pub code: String,
+}
+
+/// The token on a student's QR badge, of the form `<user id>.<secret>`
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct BadgeToken {
+    pub token: String,
+}
+
+#[derive(Deserialize, Insertable, JsonSchema)]
 #[table_name = "users"]
 pub struct NewUser {
     pub usr: String,
     pub pwd: String,
     pub nickname: String,
+    #[serde(skip_deserializing)]
+    #[schemars(skip)]
+    pub pending_nickname: Option<String>,
+    #[serde(skip_deserializing)]
+    #[schemars(skip)]
+    pub is_guest: bool,
     #[serde(default)]
     pub current_costume: String,
     #[serde(default)]
@@ -32,29 +101,505 @@ pub struct NewUser {
 }
 
 /// A user stored in the database
-#[derive(Queryable, Serialize, Clone, QueryableByName)]
+#[derive(Queryable, Serialize, Clone, QueryableByName, JsonSchema)]
 #[table_name = "users"]
 pub struct User {
     pub id: i32,
     pub usr: String,
     pub nickname: String,
     #[serde(skip_serializing)]
+    #[schemars(skip)]
     pub pwd: String, //Hashed
     pub current_costume: String,
     pub costumes: Vec<Costume>,
     pub achievements: Vec<Achievement>,
+    pub role: String,
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub tokens_valid_after: NaiveDateTime,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    /// A nickname waiting for a teacher to approve it
+    pub pending_nickname: Option<String>,
+    pub is_guest: bool,
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub last_active_at: NaiveDateTime,
+    /// When the owner deleted this account, it is purged once the grace period is over
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub deleted_at: Option<NaiveDateTime>,
+    /// Stars given or taken away by an admin, on top of those earned from scores
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub bonus_stars: i32,
+    pub classroom_id: Option<i32>,
+    /// The school year the student is in, if known
+    pub year_level: Option<i32>,
+    /// A sequence of costume names, hashed like `pwd`
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub picture_password: Option<String>,
+    /// The secret part of a QR badge token, hashed
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub badge_secret: Option<String>,
+    /// Stored as is, as it is needed to check codes
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub totp_secret: Option<String>,
+    /// When two-factor authentication was turned on, the secret is only used once this is set
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub totp_enabled_at: Option<NaiveDateTime>,
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub totp_last_step: i64,
+}
+
+impl User {
+    /// Whether this user is a teacher or admin, and so may manage student accounts.
+    pub fn is_staff(&self) -> bool {
+        self.role == ROLE_TEACHER || self.role == ROLE_ADMIN
+    }
+
+    /// Whether this user is allowed to manage the account of `other`.
+    /// Admins may manage anyone, teachers may only manage students in one of their own classrooms.
+    pub fn can_manage(
+        &self,
+        c: &diesel::PgConnection,
+        other: &User,
+    ) -> Result<bool, diesel::result::Error> {
+        match (self.role.as_str(), other.classroom_id) {
+            (ROLE_ADMIN, _) => Ok(true),
+            (ROLE_TEACHER, Some(classroom)) if other.role == ROLE_STUDENT => {
+                diesel::select(diesel::dsl::exists(
+                    classrooms::table
+                        .filter(classrooms::id.eq(classroom))
+                        .filter(classrooms::teacher_id.eq(self.id)),
+                ))
+                .get_result(c)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+pub const ROLE_STUDENT: &str = "student";
+pub const ROLE_TEACHER: &str = "teacher";
+pub const ROLE_ADMIN: &str = "admin";
+
+pub const SCOPE_SCORES_READ: &str = "scores:read";
+pub const SCOPE_CLASSROOM_READ: &str = "classroom:read";
+pub const SCOPE_ADMIN: &str = "admin";
+/// Every scope an API key can be given
+pub const SCOPES: &[&str] = &[SCOPE_SCORES_READ, SCOPE_CLASSROOM_READ, SCOPE_ADMIN];
+/// How long a token issued for an API key lasts
+pub const API_KEY_TOKEN_MINUTES: usize = 60;
+/// How often the last time a session was seen is updated, to avoid a write on every request
+const SESSION_SEEN_MINUTES: i64 = 5;
+/// How long someone has to enter their second factor after their password
+const MFA_PENDING_MINUTES: usize = 5;
+
+/// A class of students, managed by a teacher
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct Classroom {
+    pub id: i32,
+    pub name: String,
+    pub teacher_id: i32,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Deserialize, Insertable, JsonSchema)]
+#[table_name = "classrooms"]
+pub struct NewClassroom {
+    pub name: String,
+    #[serde(skip_deserializing)]
+    #[schemars(skip)]
+    pub teacher_id: i32,
+}
+
+/// A student created from a class roster
+#[derive(Insertable)]
+#[table_name = "users"]
+pub struct NewRosterStudent {
+    pub usr: String,
+    pub pwd: String,
+    pub nickname: String,
+    pub current_costume: String,
+    pub costumes: Vec<String>,
+    pub achievements: Vec<String>,
+    pub classroom_id: Option<i32>,
+    pub year_level: Option<i32>,
+}
+
+/// The login details of a student created from a class roster, to be handed out by their teacher
+#[derive(Serialize)]
+pub struct RosterCredentials {
+    pub id: i32,
+    pub nickname: String,
+    pub year_level: Option<i32>,
+    pub usr: String,
+    pub pwd: String,
+}
+
+/// A key an integration, such as a school management system, uses to get tokens limited to some scopes
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct ApiKey {
+    pub id: i32,
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub usr_id: i32,
+    pub name: String,
+    /// Hashed
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub secret: String,
+    pub scopes: Vec<String>,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "api_keys"]
+pub struct InsertableApiKey {
+    pub usr_id: i32,
+    pub name: String,
+    pub secret: String,
+    pub scopes: Vec<String>,
+}
+
+/// Sent to create an API key
+#[derive(Deserialize, JsonSchema)]
+pub struct NewApiKey {
+    pub name: String,
+    pub scopes: Vec<String>,
+}
+
+/// A newly created API key, the only time the key itself is shown
+#[derive(Serialize, JsonSchema)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+/// A token issued for an API key
+#[derive(Serialize, JsonSchema)]
+pub struct ApiKeyToken {
+    pub token: String,
+    /// Seconds until the token expires
+    pub expires_in: usize,
+}
+
+/// A device someone has logged in on
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct Session {
+    pub id: i32,
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub usr_id: i32,
+    /// A name for the device, such as the label on a classroom iPad
+    pub device_label: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "sessions"]
+pub struct InsertableSession {
+    pub usr_id: i32,
+    pub device_label: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// A one-time link which logs a student in, printed as a QR code on their login card
+#[derive(Queryable)]
+pub struct LoginLink {
+    pub id: i32,
+    pub usr_id: i32,
+    /// Hashed
+    pub secret: String,
+    pub created_by: Option<i32>,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "login_links"]
+pub struct InsertableLoginLink {
+    pub usr_id: i32,
+    pub secret: String,
+    pub created_by: Option<i32>,
+    pub expires_at: NaiveDateTime,
+}
+
+/// Sent when logging in with a one-time link
+#[derive(Deserialize, JsonSchema)]
+pub struct LoginLinkToken {
+    pub token: String,
+}
+
+/// An account at an OpenID Connect provider which can be used to sign in as a user
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct UserIdentity {
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub id: i32,
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub usr_id: i32,
+    pub provider: String,
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_login_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "user_identities"]
+pub struct InsertableUserIdentity {
+    pub usr_id: i32,
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+}
+
+/// A sign in which has been sent to an OpenID Connect provider, kept until the provider sends the user back
+#[derive(Queryable, Insertable)]
+#[table_name = "oidc_logins"]
+pub struct OidcLogin {
+    pub state: String,
+    pub provider: String,
+    pub code_verifier: String,
+    pub nonce: String,
+    /// Set when a signed in user is linking the identity to their account, rather than signing in
+    pub link_usr_id: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
+
+/// When a costume or achievement was unlocked by a user
+#[derive(Queryable, Insertable, Serialize, Deserialize)]
+#[table_name = "unlocks"]
+pub struct Unlock {
+    pub id: i32,
+    pub usr_id: i32,
+    pub kind: String,
+    pub name: String,
+    pub unlocked_at: NaiveDateTime,
+}
+
+pub const UNLOCK_COSTUME: &str = "costume";
+pub const UNLOCK_ACHIEVEMENT: &str = "achievement";
+
+#[derive(Insertable)]
+#[table_name = "unlocks"]
+pub struct InsertableUnlock {
+    pub usr_id: i32,
+    pub kind: String,
+    pub name: String,
+}
+
+/// A record of something that happened to an account
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct AuditEvent {
+    pub id: i32,
+    /// The user who did this, None if it was done by the system
+    pub actor_id: Option<i32>,
+    /// The user this was done to
+    pub target_id: Option<i32>,
+    pub action: String,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+/// Which audit events to return when searching the audit log
+pub struct AuditFilter {
+    /// Only events done by or to this user
+    pub usr_id: Option<i32>,
+    pub action: Option<String>,
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    pub offset: i64,
+    pub limit: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "audit_events"]
+pub struct InsertableAuditEvent {
+    pub actor_id: Option<i32>,
+    pub target_id: Option<i32>,
+    pub action: String,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// A copy of a user's statistics taken just before they were reset, so that the reset can be undone
+#[derive(Queryable)]
+pub struct ResetSnapshot {
+    pub id: i32,
+    pub usr_id: i32,
+    /// Every score the user had, as a JSON array of `Score`
+    pub scores: serde_json::Value,
+    /// Every unlock the user had, as a JSON array of `Unlock`
+    pub unlocks: serde_json::Value,
+    pub current_costume: String,
+    pub costumes: Vec<String>,
+    pub achievements: Vec<String>,
+    /// The total number of stars the user had earned
+    pub stars: i32,
+    pub created_at: NaiveDateTime,
+    pub restored_at: Option<NaiveDateTime>,
+    pub bonus_stars: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "reset_snapshots"]
+pub struct InsertableResetSnapshot {
+    pub usr_id: i32,
+    pub scores: serde_json::Value,
+    pub unlocks: serde_json::Value,
+    pub current_costume: String,
+    pub costumes: Vec<String>,
+    pub achievements: Vec<String>,
+    pub stars: i32,
+    pub bonus_stars: i32,
+}
+
+/// Sent by a guest who wants to turn their account into a full account
+#[derive(Deserialize, JsonSchema)]
+pub struct UpgradeGuest {
+    pub usr: String,
+    pub pwd: String,
+    pub nickname: String,
+}
+
+/// A nickname waiting for approval, shown to teachers in the moderation queue
+#[derive(Serialize, JsonSchema)]
+pub struct PendingNickname {
+    pub id: i32,
+    pub usr: String,
+    pub nickname: String,
+    pub pending_nickname: Option<String>,
+}
+
+impl From<User> for PendingNickname {
+    fn from(u: User) -> PendingNickname {
+        PendingNickname {
+            id: u.id,
+            usr: u.usr,
+            nickname: u.nickname,
+            pending_nickname: u.pending_nickname,
+        }
+    }
+}
+
+/// Sent by a student who wishes to change their password
+#[derive(Deserialize, JsonSchema)]
+pub struct ChangePassword {
+    pub old_pwd: String,
+    pub new_pwd: String,
+}
+
+/// A one-time password reset code stored in the database
+#[derive(Queryable)]
+pub struct PasswordReset {
+    pub id: i32,
+    pub usr_id: i32,
+    pub issued_by: Option<i32>,
+    pub code: String, //Hashed
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "password_resets"]
+pub struct InsertablePasswordReset {
+    pub usr_id: i32,
+    pub issued_by: Option<i32>,
+    pub code: String,
+    pub expires_at: NaiveDateTime,
+}
+
+/// Returned to a teacher once they have issued a reset code, the code is never shown again
+#[derive(Serialize, JsonSchema)]
+pub struct IssuedPasswordReset {
+    pub usr: String,
+    pub code: String,
+    pub expires_at: NaiveDateTime,
+}
+
+/// Sent when adding an email to an account, or when recovering an account by email
+#[derive(Deserialize, JsonSchema)]
+pub struct EmailAddress {
+    pub email: String,
+}
+
+/// A pending email verification stored in the database
+#[derive(Queryable)]
+pub struct EmailVerification {
+    pub id: i32,
+    pub usr_id: i32,
+    pub email: String,
+    pub selector: String,
+    pub verifier: String, //Hashed
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "email_verifications"]
+pub struct InsertableEmailVerification {
+    pub usr_id: i32,
+    pub email: String,
+    pub selector: String,
+    pub verifier: String,
+    pub expires_at: NaiveDateTime,
+}
+
+/// Sent by the user when following the link in their verification email
+#[derive(Deserialize, JsonSchema)]
+pub struct VerifyEmail {
+    pub token: String,
+}
+
+/// Sent by a student redeeming a reset code to set a new password
+#[derive(Deserialize, JsonSchema)]
+pub struct RedeemPasswordReset {
+    pub usr: String,
+    pub code: String,
+    pub pwd: String,
 }
 
 /// A score uploaded by a user
-#[derive(Queryable, Serialize)]
+#[derive(Queryable, Insertable, Serialize, Deserialize, JsonSchema)]
+#[table_name = "scores"]
 pub struct Score {
     pub id: i32,
     pub usr_id: i32,
     pub score: i32,
     pub num_stars: i32,
+    /// Voided scores have been removed by an admin, and don't count towards anything
+    #[serde(default)]
+    pub voided: bool,
+    /// Flagged scores look suspicious, and don't count towards anything until an admin approves them
+    #[serde(default)]
+    pub flagged: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub struct NewScore {
     pub score: i32,
     pub num_stars: i32,
@@ -66,10 +611,11 @@ pub struct InsertableScore {
     pub usr_id: i32,
     pub score: i32,
     pub num_stars: i32,
+    pub flagged: bool,
 }
 
 /// A costume that the user may equip once they reach a certain ranking.
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Clone, JsonSchema)]
 pub struct Costume {
     pub name: String,
     pub display_name: String,
@@ -77,7 +623,7 @@ pub struct Costume {
     pub price: usize,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub struct UnlockCostume {
     pub name: String,
 }
@@ -85,7 +631,7 @@ pub struct UnlockCostume {
 impl diesel::types::FromSql<diesel::sql_types::Text, diesel::pg::Pg> for Costume {
     fn from_sql(
         opt: std::option::Option<&<diesel::pg::Pg as diesel::backend::Backend>::RawValue>,
-    ) -> Result<Costume, Box<(dyn std::error::Error + Sync + std::marker::Send + 'static)>> {
+    ) -> Result<Costume, Box<dyn std::error::Error + Sync + std::marker::Send + 'static>> {
         if opt.is_none() {
             return Err(Box::new(diesel::result::Error::DeserializationError(
                 "Was asked to parse from empty type into costume! This shoudn't happen!".into(),
@@ -109,14 +655,30 @@ impl diesel::types::FromSql<diesel::sql_types::Text, diesel::pg::Pg> for Costume
     }
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Clone, JsonSchema)]
 pub struct Achievement {
     pub name: String,
     pub display_name: String,
     pub description: String,
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub requirements: Option<Requirements>,
+}
+
+/// What a user must have done to earn an achievement, every requirement given must be met
+#[derive(Deserialize, Clone, Default)]
+pub struct Requirements {
+    /// The number of games played
+    pub num_games: Option<i64>,
+    /// The highest score reached in a single game
+    pub high_score: Option<i32>,
+    /// The number of stars held
+    pub stars: Option<i64>,
+    /// Set to `"all"` to require every costume be unlocked
+    pub costumes: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub struct UnlockAchievement {
     pub name: String,
 }
@@ -124,7 +686,7 @@ pub struct UnlockAchievement {
 impl diesel::types::FromSql<diesel::sql_types::Text, diesel::pg::Pg> for Achievement {
     fn from_sql(
         opt: std::option::Option<&<diesel::pg::Pg as diesel::backend::Backend>::RawValue>,
-    ) -> Result<Achievement, Box<(dyn std::error::Error + Sync + std::marker::Send + 'static)>>
+    ) -> Result<Achievement, Box<dyn std::error::Error + Sync + std::marker::Send + 'static>>
     {
         if opt.is_none() {
             return Err(Box::new(diesel::result::Error::DeserializationError(
@@ -154,6 +716,38 @@ impl diesel::types::FromSql<diesel::sql_types::Text, diesel::pg::Pg> for Achieve
 pub struct Response {
     body: String,
     status: Status,
+    headers: Vec<rocket::http::Header<'static>>,
+    /// What went wrong, which is logged against the request rather than sent to the client
+    error: Option<String>,
+}
+
+impl Response {
+    /// Add an extra header to this response
+    pub fn with_header(mut self, name: &'static str, value: String) -> Response {
+        self.headers.push(rocket::http::Header::new(name, value));
+        self
+    }
+
+    /// Note what went wrong, which is logged against the request rather than sent to the client
+    pub fn with_error(mut self, error: String) -> Response {
+        self.error = Some(error);
+        self
+    }
+
+    /// What went wrong, if this response is for an error that will be logged
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// Respond to a database error without giving away any details, which are logged instead
+    pub fn database_error(e: diesel::result::Error) -> Response {
+        ResponseBuilder {
+            data: "Failed to query the server",
+            status: Status::InternalServerError,
+        }
+        .build()
+        .with_error(format!("Database error {}", e))
+    }
 }
 pub struct ResponseBuilder<T>
 where
@@ -177,12 +771,16 @@ where
                         e.to_string()
                     )),
                     status: Status::InternalServerError,
+                    headers: vec![],
+                    error: None,
                 }
             }
         };
         Response {
             body: wrap(body),
             status: self.status,
+            headers: vec![],
+            error: None,
         }
     }
 }
@@ -198,10 +796,18 @@ impl Default for ResponseBuilder<String> {
 
 #[rocket::async_trait]
 impl<'r> rocket::response::Responder<'r, 'static> for Response {
-    fn respond_to(self, _: &'r Request<'_>) -> rocket::response::Result<'static> {
-        rocket::response::Response::build()
+    fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
+        if let Some(e) = &self.error {
+            logging::context(req).in_scope(|| tracing::error!(error = %e, "Request failed"));
+        }
+        let mut response = rocket::response::Response::build();
+        response
             .header(ContentType::new("application", "json"))
-            .status(self.status)
+            .status(self.status);
+        for header in self.headers {
+            response.header(header);
+        }
+        response
             .sized_body(self.body.len(), Cursor::new(self.body))
             .ok()
     }
@@ -214,28 +820,256 @@ pub struct Claims {
     pub exp: usize,
     /// Issued at
     pub iat: usize,
+    /// Issued at in milliseconds, so a token from the same second as a password reset or sign out can be told apart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat_ms: Option<u64>,
     /// The id of the user who this is for
     pub sub: i32,
+    /// Whether this token belongs to a guest account, which can only play the game
+    #[serde(default)]
+    pub guest: bool,
+    /// The role of the user when this token was issued, tokens issued before roles were added have none
+    #[serde(default)]
+    pub role: String,
+    /// Set on the short lived token given after a password when a second factor is still needed
+    #[serde(default)]
+    pub mfa_pending: bool,
+    /// Space separated scopes this token is limited to, tokens from logging in aren't limited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// The API key this token was issued for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<i32>,
+    /// The session this token was issued for when logging in, tokens from before sessions were added have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<i32>,
 }
 
 impl Claims {
-    /// Create a new JWT, when provided with the id of the user.
-    pub fn new_token(sub: i32) -> String {
-        let curr_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards!")
-            .as_secs() as usize;
-        let c: Claims = Claims {
-            exp: curr_time + *JWT_EXPIRY_TIME_HOURS * 60 * 60,
-            iat: curr_time,
+    /// Create a new JWT for the user, for the session they have just started.
+    pub fn new_token(user: &User, sid: i32) -> String {
+        Claims::encode(user.id, false, user.role.clone(), false, Some(sid))
+    }
+
+    /// Create a new JWT for a guest account, when provided with the id of the user and their session.
+    pub fn new_guest_token(sub: i32, sid: i32) -> String {
+        Claims::encode(sub, true, ROLE_STUDENT.into(), false, Some(sid))
+    }
+
+    /// Create a short lived JWT which can only be used to finish logging in with a second factor
+    pub fn new_mfa_pending_token(user: &User) -> String {
+        Claims::encode(user.id, false, user.role.clone(), true, None)
+    }
+
+    /// Create a short lived JWT for an API key, limited to the scopes of the key
+    pub fn new_api_key_token(key: &ApiKey, role: String) -> String {
+        let c = Claims {
+            exp: 0,
+            iat: 0,
+            iat_ms: None,
+            sub: key.usr_id,
+            guest: false,
+            role,
+            mfa_pending: false,
+            scope: Some(key.scopes.join(" ")),
+            api_key: Some(key.id),
+            sid: None,
+        };
+        Claims::sign(c, API_KEY_TOKEN_MINUTES * 60)
+    }
+
+    fn encode(sub: i32, guest: bool, role: String, mfa_pending: bool, sid: Option<i32>) -> String {
+        let lifetime = if mfa_pending {
+            MFA_PENDING_MINUTES * 60
+        } else {
+            *JWT_EXPIRY_TIME_HOURS * 60 * 60
+        };
+        let c = Claims {
+            exp: 0,
+            iat: 0,
+            iat_ms: None,
             sub,
+            guest,
+            role,
+            mfa_pending,
+            scope: None,
+            api_key: None,
+            sid,
         };
-        encode(
-            &Header::default(),
-            &c,
-            &EncodingKey::from_secret((*JWT_SECRET).as_ref()),
-        )
-        .unwrap() // HACK this secret should be loaded in from env
+        Claims::sign(c, lifetime)
+    }
+
+    /// Set when the token was issued and when it expires, then sign it
+    fn sign(mut c: Claims, lifetime: usize) -> String {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards!");
+        let curr_time = since_epoch.as_secs() as usize;
+        c.iat = curr_time;
+        c.iat_ms = Some(since_epoch.as_millis() as u64);
+        c.exp = curr_time + lifetime;
+        keys::encode(&c)
+    }
+
+    /// When this token was issued in milliseconds, tokens from before that was recorded only have whole seconds
+    fn issued_at_millis(&self) -> i64 {
+        self.iat_ms
+            .map(|ms| ms as i64)
+            .unwrap_or(self.iat as i64 * 1000)
+    }
+
+    /// Whether this token may be used for something needing the scope
+    pub fn has_scope(&self, scope: &str) -> bool {
+        match &self.scope {
+            Some(s) => s.split_whitespace().any(|s| s == scope),
+            None => true,
+        }
+    }
+
+    /// Refuse the request if this token belongs to a guest, for anything beyond playing the game
+    pub fn deny_guest(&self) -> Result<(), Response> {
+        if self.guest {
+            return Err(ResponseBuilder {
+                data: "Guest accounts can't do this, upgrade to a full account first",
+                status: Status::Forbidden,
+            }
+            .build());
+        }
+        Ok(())
+    }
+}
+
+/// Check the token in the `Authorisation` header. Tokens waiting on a second factor are only accepted when `mfa_pending` is set,
+/// and tokens limited to some scopes only when `allow_scoped` is set.
+async fn authenticate(
+    req: &Request<'_>,
+    mfa_pending: bool,
+    allow_scoped: bool,
+) -> request::Outcome<Claims, Response> {
+    //TODO improve the headers here, so we will check for Authorization along with Authorisation.
+    //Should help the americanally challenged of us...
+    let auth_header = req.headers().get_one("Authorisation");
+    if auth_header.is_none() {
+        return request::Outcome::Failure((
+            Status::Unauthorized,
+            ResponseBuilder {
+                data: "Authorisation Header Not Present",
+                status: Status::Unauthorized,
+            }
+            .build(),
+        ));
+    }
+
+    match keys::decode::<Claims>(auth_header.unwrap()) {
+        Ok(claims) => {
+            //Check the user still exists and hasn't been deleted, and that this token hasn't been revoked since it was issued
+            let conn = match req.guard::<UsersDbConn>().await {
+                request::Outcome::Success(c) => c,
+                _ => {
+                    return request::Outcome::Failure((
+                        Status::ServiceUnavailable,
+                        ResponseBuilder {
+                            data: "Unable to connect to the database",
+                            status: Status::ServiceUnavailable,
+                        }
+                        .build(),
+                    ))
+                }
+            };
+            let subject = claims.sub;
+            let key_id = claims.api_key;
+            let session_id = claims.sid;
+            let r: Result<Option<(NaiveDateTime, String)>, diesel::result::Error> = conn
+                .run(move |c| {
+                    let now = chrono::Utc::now().naive_utc();
+                    //Tokens for an API key stop working as soon as the key is revoked
+                    if let Some(key_id) = key_id {
+                        let active: i64 = api_keys::table
+                            .filter(api_keys::id.eq(key_id))
+                            .filter(api_keys::usr_id.eq(subject))
+                            .filter(api_keys::revoked_at.is_null())
+                            .count()
+                            .get_result(c)?;
+                        if active == 0 {
+                            return Ok(None);
+                        }
+                    }
+
+                    //Tokens from logging in stop working once their session is signed out
+                    if let Some(session_id) = session_id {
+                        let active: i64 = sessions::table
+                            .filter(sessions::id.eq(session_id))
+                            .filter(sessions::usr_id.eq(subject))
+                            .filter(sessions::revoked_at.is_null())
+                            .count()
+                            .get_result(c)?;
+                        if active == 0 {
+                            return Ok(None);
+                        }
+                        diesel::update(
+                            sessions::table
+                                .filter(sessions::id.eq(session_id))
+                                .filter(
+                                    sessions::last_seen_at
+                                        .lt(now - chrono::Duration::minutes(SESSION_SEEN_MINUTES)),
+                                ),
+                        )
+                        .set(sessions::last_seen_at.eq(now))
+                        .execute(c)?;
+                    }
+
+                    //Keep track of when the user was last seen, at most once an hour to avoid a write on every request
+                    diesel::update(
+                        users::table
+                            .filter(users::id.eq(subject))
+                            .filter(users::last_active_at.lt(now - chrono::Duration::hours(1))),
+                    )
+                    .set(users::last_active_at.eq(now))
+                    .execute(c)?;
+
+                    users::table
+                        .filter(users::id.eq(subject))
+                        .filter(users::deleted_at.is_null())
+                        .select((users::tokens_valid_after, users::role))
+                        .first::<(NaiveDateTime, String)>(c)
+                        .optional()
+                })
+                .await;
+
+            //A change of role invalidates the token, tokens from before roles were added belong to students
+            match r {
+                Ok(Some((valid_after, role)))
+                    if claims.issued_at_millis() >= valid_after.timestamp_millis()
+                        && claims.mfa_pending == mfa_pending
+                        && (allow_scoped || claims.scope.is_none())
+                        && (claims.role == role
+                            || (claims.role.is_empty() && role == ROLE_STUDENT)) =>
+                {
+                    logging::set_user(req, claims.sub);
+                    request::Outcome::Success(claims)
+                }
+                Ok(_) => request::Outcome::Failure((
+                    Status::Unauthorized,
+                    ResponseBuilder {
+                        data: "Invalid Auth Token",
+                        status: Status::Unauthorized,
+                    }
+                    .build(),
+                )),
+                Err(e) => request::Outcome::Failure((
+                    Status::InternalServerError,
+                    Response::database_error(e),
+                )),
+            }
+        }
+        Err(_) => request::Outcome::Failure((
+            Status::Unauthorized,
+            ResponseBuilder {
+                data: "Invalid Auth Token",
+                status: Status::Unauthorized,
+            }
+            .build(),
+        )),
     }
 }
 
@@ -243,37 +1077,116 @@ impl Claims {
 impl<'r> FromRequest<'r> for Claims {
     type Error = Response;
     async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Response> {
-        //TODO improve the headers here, so we will check for Authorization along with Authorisation.
-        //Should help the americanally challenged of us...
-        let auth_header = req.headers().get_one("Authorisation");
-        if auth_header.is_none() {
-            return request::Outcome::Failure((
-                Status::Unauthorized,
+        authenticate(req, false, false).await
+    }
+}
+
+/// A token from the first step of logging in, only accepted by the `/api/v1/mfa` routes
+pub struct MfaPending(pub Claims);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MfaPending {
+    type Error = Response;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Response> {
+        authenticate(req, true, false).await.map(MfaPending)
+    }
+}
+
+/// A permission needed by a route, which tokens for an API key only have if it was given to the key
+pub trait Scope {
+    const NAME: &'static str;
+}
+
+pub struct ScoresRead;
+impl Scope for ScoresRead {
+    const NAME: &'static str = SCOPE_SCORES_READ;
+}
+
+pub struct ClassroomRead;
+impl Scope for ClassroomRead {
+    const NAME: &'static str = SCOPE_CLASSROOM_READ;
+}
+
+/// A token which may be used for something needing the scope `S`, either from logging in or for an API key
+pub struct Scoped<S: Scope>(pub Claims, PhantomData<S>);
+
+#[rocket::async_trait]
+impl<'r, S: Scope + Send> FromRequest<'r> for Scoped<S> {
+    type Error = Response;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Response> {
+        match authenticate(req, false, true).await {
+            request::Outcome::Success(claims) if claims.has_scope(S::NAME) => {
+                request::Outcome::Success(Scoped(claims, PhantomData))
+            }
+            request::Outcome::Success(_) => request::Outcome::Failure((
+                Status::Forbidden,
                 ResponseBuilder {
-                    data: "Authorisation Header Not Present",
-                    status: Status::Unauthorized,
+                    data: format!("This requires the scope {}", S::NAME),
+                    status: Status::Forbidden,
                 }
                 .build(),
-            ));
+            )),
+            request::Outcome::Failure(e) => request::Outcome::Failure(e),
+            request::Outcome::Forward(f) => request::Outcome::Forward(f),
         }
+    }
+}
 
-        match decode::<Claims>(
-            &auth_header.unwrap(),
-            &DecodingKey::from_secret((*JWT_SECRET).as_ref()),
-            &Validation::default(),
-        ) {
-            Ok(t) => {
-                //TODO validate the user hasn't been deleted (check db)
-                request::Outcome::Success(t.claims)
+/// A token belonging to an admin, used to guard the `/api/admin` routes
+pub struct Admin(pub Claims);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = Response;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Response> {
+        match authenticate(req, false, true).await {
+            request::Outcome::Success(claims)
+                if claims.role == ROLE_ADMIN && claims.has_scope(SCOPE_ADMIN) =>
+            {
+                request::Outcome::Success(Admin(claims))
             }
-            Err(_) => request::Outcome::Failure((
-                Status::Unauthorized,
+            request::Outcome::Success(_) => request::Outcome::Failure((
+                Status::Forbidden,
                 ResponseBuilder {
-                    data: "Invalid Auth Token",
-                    status: Status::Unauthorized,
+                    data: "This requires an admin account",
+                    status: Status::Forbidden,
                 }
                 .build(),
             )),
+            request::Outcome::Failure(e) => request::Outcome::Failure(e),
+            request::Outcome::Forward(f) => request::Outcome::Forward(f),
         }
     }
 }
+
+/// A user as seen by an admin, along with their star balance
+#[derive(Serialize, JsonSchema)]
+pub struct AdminUser {
+    #[serde(flatten)]
+    pub user: User,
+    pub bonus_stars: i32,
+    pub deleted_at: Option<NaiveDateTime>,
+    pub last_active_at: NaiveDateTime,
+    /// Stars from every score which hasn't been voided or flagged, plus any bonus stars
+    pub stars: i64,
+}
+
+/// Replace the costumes a user owns
+#[derive(Deserialize, JsonSchema)]
+pub struct SetCostumes {
+    pub costumes: Vec<String>,
+    /// Which costume they are wearing, left alone if not given
+    pub current_costume: Option<String>,
+}
+
+/// Replace the achievements a user has
+#[derive(Deserialize, JsonSchema)]
+pub struct SetAchievements {
+    pub achievements: Vec<String>,
+}
+
+/// Set a user's star balance, done by adjusting their bonus stars
+#[derive(Deserialize, JsonSchema)]
+pub struct SetStars {
+    pub stars: i64,
+}
@@ -0,0 +1,197 @@
+use crate::common::random_index;
+use lazy_static::lazy_static;
+use rocket::serde::Deserialize;
+use std::collections::HashMap;
+
+/// Rejected words shorter than this aren't looked for inside other words, as too many names contain them by chance
+const MIN_EMBEDDED_LEN: usize = 4;
+
+/// Words from a single language in `./moderation.toml`
+#[derive(Deserialize, Default)]
+pub struct Blocklist {
+    #[serde(default)]
+    pub reject: Vec<String>,
+    #[serde(default)]
+    pub review: Vec<String>,
+}
+
+lazy_static! {
+    /// Every language in `./moderation.toml`, with the words already normalised
+    static ref BLOCKLISTS: HashMap<String, Blocklist> = {
+        let data = std::fs::read_to_string("./moderation.toml").expect("Unable to find `./moderation.toml`");
+        let lists: HashMap<String, Blocklist> = toml::from_str(&data).expect("Unable to parse `./moderation.toml`");
+        lists
+            .into_iter()
+            .map(|(language, list)| {
+                let list = Blocklist {
+                    reject: list.reject.iter().map(|w| normalise(w)).collect(),
+                    review: list.review.iter().map(|w| normalise(w)).collect(),
+                };
+                (language, list)
+            })
+            .collect()
+    };
+}
+
+/// Load the blocklists, so that mistakes are found at startup
+pub fn initialize() {
+    lazy_static::initialize(&BLOCKLISTS);
+}
+
+/// The outcome of checking a name against the blocklists
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allowed,
+    /// The name might be fine, but a teacher needs to check it first
+    Review,
+    Rejected,
+}
+
+/// Lowercase, remove macrons and undo common leetspeak substitutions.
+/// Anything that isn't a letter becomes a space.
+fn normalise(s: &str) -> String {
+    s.to_lowercase()
+        .chars()
+        .map(|c| match c {
+            'ā' => 'a',
+            'ē' => 'e',
+            'ī' => 'i',
+            'ō' => 'o',
+            'ū' => 'u',
+            '4' | '@' => 'a',
+            '8' => 'b',
+            '3' => 'e',
+            '6' | '9' => 'g',
+            '1' | '!' | '|' => 'i',
+            '0' => 'o',
+            '5' | '$' => 's',
+            '7' | '+' => 't',
+            '2' => 'z',
+            c if c.is_ascii_lowercase() => c,
+            _ => ' ',
+        })
+        .collect()
+}
+
+/// Collapse runs of the same letter, so that `fuuuuck` is treated as `fuck`
+fn collapse(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut last = None;
+    for c in s.chars() {
+        if Some(c) != last {
+            out.push(c);
+        }
+        last = Some(c);
+    }
+    out
+}
+
+/// The words in a normalised name, along with any word spelled out one letter at a time (e.g. `c o c k`)
+fn name_words(normalised: &str) -> Vec<String> {
+    let mut words: Vec<String> = vec![];
+    let mut spelled = String::new();
+    for word in normalised.split_whitespace() {
+        if word.len() == 1 {
+            spelled.push_str(word);
+            continue;
+        }
+        if spelled.len() > 1 {
+            words.push(std::mem::take(&mut spelled));
+        }
+        spelled.clear();
+        words.push(word.to_owned());
+    }
+    if spelled.len() > 1 {
+        words.push(spelled);
+    }
+    words
+}
+
+/// Check a username or nickname against every blocklist.
+/// A rejected word is only refused as a whole word. Found inside a longer name it might be innocent, like
+/// Hancock or Scunthorpe, or not, like `bigdick`, so the name is held for review instead.
+pub fn check(name: &str) -> Verdict {
+    let words = name_words(&normalise(name));
+    let collapsed_words: Vec<String> = words.iter().map(|w| collapse(w)).collect();
+    let whole_word = |w: &String| words.contains(w) || collapsed_words.contains(w);
+    let joined = words.concat();
+    let collapsed_joined = collapse(&joined);
+    let embedded = |w: &String| {
+        w.len() >= MIN_EMBEDDED_LEN && (joined.contains(w.as_str()) || collapsed_joined.contains(w.as_str()))
+    };
+
+    let mut verdict = Verdict::Allowed;
+    for list in BLOCKLISTS.values() {
+        if list.reject.iter().any(whole_word) {
+            return Verdict::Rejected;
+        }
+        if list.review.iter().any(whole_word) || list.reject.iter().any(embedded) {
+            verdict = Verdict::Review;
+        }
+    }
+    verdict
+}
+
+const ADJECTIVES: &[&str] = &[
+    "Busy", "Buzzy", "Happy", "Sunny", "Clever", "Brave", "Speedy", "Jolly", "Bright", "Kind",
+];
+
+/// A friendly name shown in place of a nickname that is waiting for review, e.g. `Sunny Bee 42`
+pub fn placeholder_nickname() -> String {
+    let adjective = ADJECTIVES[random_index(ADJECTIVES.len())];
+    format!("{} Bee {}", adjective, random_index(100))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalise_undoes_leetspeak_and_macrons() {
+        assert_eq!(normalise("5h1t"), "shit");
+        assert_eq!(normalise("Mānuka"), "manuka");
+        assert_eq!(normalise("big_cock"), "big cock");
+    }
+
+    #[test]
+    fn letters_spelled_out_are_joined() {
+        assert_eq!(name_words("c o c k"), vec!["cock"]);
+        assert_eq!(name_words("i am f u c k"), vec!["am", "fuck"]);
+        assert_eq!(name_words("a b"), vec!["ab"]);
+    }
+
+    #[test]
+    fn whole_words_are_rejected() {
+        for name in &["fuck", "c o c k", "f.u.c.k", "fuuuck", "big_cock", "c0ck", "Big Dick"] {
+            assert_eq!(check(name), Verdict::Rejected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn words_inside_names_are_reviewed() {
+        for name in &["fuckyou", "shithead", "bigdick", "Scunthorpe", "Hancock", "Peacock", "xXfuuuckXx"] {
+            assert_eq!(check(name), Verdict::Review, "{}", name);
+        }
+    }
+
+    #[test]
+    fn short_words_inside_names_are_allowed() {
+        //`ure` and `fag` are rejected as words, but are part of far too many names to review them all
+        for name in &["Maureen", "Aurelia", "Fagan"] {
+            assert_eq!(check(name), Verdict::Allowed, "{}", name);
+        }
+    }
+
+    #[test]
+    fn review_words_are_only_matched_whole() {
+        assert_eq!(check("sexy"), Verdict::Review);
+        assert_eq!(check("Cassandra"), Verdict::Allowed);
+    }
+
+    #[test]
+    fn ordinary_names_are_allowed() {
+        for name in &["Aroha", "Mānuka Bee", "Tama 7", "Kahu Kowhai"] {
+            assert_eq!(check(name), Verdict::Allowed, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,771 @@
+use crate::openapi::{Auth, Operation};
+use crate::{audit, common, metrics, models, sessions, validation, UsersDbConn, BROWSER_BASE_URL};
+use diesel::prelude::*;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use lazy_static::lazy_static;
+use rocket::http::Status;
+use rocket::response::Redirect;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::sync::RwLock;
+use rocket::State;
+use schemars::JsonSchema;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long someone can take to sign in at their provider before they have to start again
+pub const LOGIN_EXPIRY_MINUTES: i64 = 10;
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![
+        list_providers,
+        start_login,
+        start_link,
+        callback,
+        list_identities,
+        unlink_identity
+    ]
+}
+
+pub fn operations() -> Vec<Operation> {
+    vec![
+        Operation::new(
+            "list_providers",
+            "List the providers that can be signed in with",
+        )
+        .returns::<Vec<ProviderSummary>>(200, "Every provider, by name"),
+        Operation::new("start_login", "Sign in with a provider")
+            .redirects("To the provider's sign in page")
+            .errors(&[404, 502]),
+        Operation::new("start_link", "Start linking a provider to your account")
+            .auth(Auth::Token)
+            .returns::<AuthorizationUrl>(200, "Where to send the user to sign in at the provider")
+            .errors(&[403, 404, 502]),
+        Operation::new(
+            "callback",
+            "Where providers send people back to after signing in",
+        )
+        .redirects("To the website, with a token in the fragment")
+        .errors(&[400, 403, 404, 502]),
+        Operation::new(
+            "list_identities",
+            "List the providers linked to your account",
+        )
+        .auth(Auth::Token)
+        .returns::<Vec<models::UserIdentity>>(200, "Every linked identity"),
+        Operation::new("unlink_identity", "Unlink a provider from your account")
+            .auth(Auth::Token)
+            .returns::<String>(200, "Confirmation the identity was unlinked")
+            .errors(&[404]),
+    ]
+}
+
+/// An OpenID Connect provider from `./oidc.toml`
+#[derive(Deserialize)]
+pub struct Provider {
+    pub display_name: String,
+    /// The discovery document is fetched from `<issuer>/.well-known/openid-configuration`
+    pub issuer: String,
+    pub client_id: String,
+    /// The env var holding the client secret, if the provider gave us one
+    #[serde(default)]
+    pub client_secret_env: Option<String>,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    /// Whether to create an account the first time someone signs in, rather than only allowing linked accounts
+    #[serde(default)]
+    pub create_accounts: bool,
+    /// The role given to accounts created by this provider
+    #[serde(default = "default_role")]
+    pub role: String,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec!["openid".into(), "email".into(), "profile".into()]
+}
+
+fn default_role() -> String {
+    models::ROLE_TEACHER.into()
+}
+
+#[derive(Deserialize, Default)]
+struct Config {
+    #[serde(default)]
+    provider: HashMap<String, Provider>,
+}
+
+lazy_static! {
+    /// Every provider in `./oidc.toml`, or the file at `OIDC_CONFIG_FILE`, which is optional as most deployments won't have any
+    static ref PROVIDERS: HashMap<String, Provider> = {
+        let path = common::env_or("OIDC_CONFIG_FILE", "./oidc.toml".to_owned());
+        let config: Config = match std::fs::read_to_string(&path) {
+            Ok(data) => toml::from_str(&data).unwrap_or_else(|e| panic!("Unable to parse `{}` {}", path, e)),
+            Err(_) => Config::default(),
+        };
+        for (name, provider) in &config.provider {
+            if provider.role != models::ROLE_TEACHER && provider.role != models::ROLE_STUDENT {
+                panic!("Provider {} in `{}` must give the role teacher or student", name, path);
+            }
+        }
+        config.provider
+    };
+}
+
+/// Load the providers, so that mistakes are found at startup
+pub fn initialize() {
+    lazy_static::initialize(&PROVIDERS);
+}
+
+/// The parts of a provider's discovery document we use
+#[derive(Deserialize, Clone)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+/// A public key a provider signs ID tokens with
+#[derive(Deserialize, Clone)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(default)]
+    n: Option<String>,
+    #[serde(default)]
+    e: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// The claims we use from an ID token, the standard ones are checked by `jsonwebtoken`
+#[derive(Deserialize)]
+struct IdClaims {
+    sub: String,
+    #[serde(default)]
+    nonce: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: Option<bool>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    preferred_username: Option<String>,
+}
+
+/// A provider as listed to the website, to show sign in buttons
+#[derive(Serialize, JsonSchema)]
+struct ProviderSummary {
+    name: String,
+    display_name: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct AuthorizationUrl {
+    url: String,
+}
+
+pub enum OidcError {
+    UnknownProvider,
+    /// The ID token was signed with a key the provider hasn't published
+    UnknownKey,
+    Expired,
+    Http(reqwest::Error),
+    Provider(String),
+    Token(jsonwebtoken::errors::Error),
+    Refused(&'static str),
+    Database(diesel::result::Error),
+}
+
+impl From<reqwest::Error> for OidcError {
+    fn from(e: reqwest::Error) -> OidcError {
+        OidcError::Http(e)
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for OidcError {
+    fn from(e: jsonwebtoken::errors::Error) -> OidcError {
+        OidcError::Token(e)
+    }
+}
+
+impl From<diesel::result::Error> for OidcError {
+    fn from(e: diesel::result::Error) -> OidcError {
+        OidcError::Database(e)
+    }
+}
+
+impl From<OidcError> for models::Response {
+    fn from(e: OidcError) -> models::Response {
+        let (data, status) = match e {
+            OidcError::UnknownProvider => ("Provider Not Found".to_owned(), Status::NotFound),
+            OidcError::UnknownKey => ("ID token was signed with an unknown key".to_owned(), Status::BadGateway),
+            OidcError::Expired => ("Sign in expired, please try again".to_owned(), Status::BadRequest),
+            OidcError::Http(e) => (format!("Unable to reach provider {}", e.to_string()), Status::BadGateway),
+            OidcError::Provider(e) => (format!("Provider returned an error {}", e), Status::BadGateway),
+            OidcError::Token(e) => (format!("Invalid ID token {}", e.to_string()), Status::BadRequest),
+            OidcError::Refused(e) => (e.to_owned(), Status::Forbidden),
+            OidcError::Database(e) => return models::Response::database_error(e),
+        };
+        models::ResponseBuilder { data, status }.build()
+    }
+}
+
+struct Cached {
+    metadata: Metadata,
+    keys: Vec<Jwk>,
+    fetched_at: Instant,
+}
+
+/// Discovery documents and signing keys from each provider, fetched when first needed and kept for a while
+pub struct Cache {
+    client: reqwest::Client,
+    ttl: Duration,
+    entries: RwLock<HashMap<String, Cached>>,
+}
+
+impl Cache {
+    /// Create an empty cache, keeping entries for `OIDC_CACHE_MINUTES`
+    pub fn from_env() -> Cache {
+        let minutes: u64 = common::env_or("OIDC_CACHE_MINUTES", 60);
+        Cache {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("Unable to create http client"),
+            ttl: Duration::from_secs(minutes * 60),
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Get the discovery document and keys of a provider, fetching them again if they are old or `refresh` is set
+    async fn get(
+        &self,
+        name: &str,
+        provider: &Provider,
+        refresh: bool,
+    ) -> Result<(Metadata, Vec<Jwk>), OidcError> {
+        if !refresh {
+            if let Some(c) = self.entries.read().await.get(name) {
+                if c.fetched_at.elapsed() < self.ttl {
+                    return Ok((c.metadata.clone(), c.keys.clone()));
+                }
+            }
+        }
+
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            provider.issuer.trim_end_matches('/')
+        );
+        let metadata: Metadata = self.client.get(&url).send().await?.error_for_status()?.json().await?;
+        if metadata.issuer != provider.issuer {
+            return Err(OidcError::Provider(format!(
+                "discovery document is for the issuer {}",
+                metadata.issuer
+            )));
+        }
+        let jwks: Jwks = self
+            .client
+            .get(&metadata.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        self.entries.write().await.insert(
+            name.to_owned(),
+            Cached {
+                metadata: metadata.clone(),
+                keys: jwks.keys.clone(),
+                fetched_at: Instant::now(),
+            },
+        );
+        Ok((metadata, jwks.keys))
+    }
+}
+
+fn find_provider(name: &str) -> Result<&'static Provider, OidcError> {
+    PROVIDERS.get(name).ok_or(OidcError::UnknownProvider)
+}
+
+fn client_secret(provider: &Provider) -> Option<String> {
+    provider.client_secret_env.as_deref().and_then(common::env_opt)
+}
+
+/// Where the provider sends people back to, which has to be registered with the provider
+fn redirect_uri(name: &str) -> String {
+    format!("{}/api/v1/oidc/{}/callback", *BROWSER_BASE_URL, name)
+}
+
+/// The PKCE code challenge for a verifier, using the S256 method
+fn code_challenge(verifier: &str) -> String {
+    base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/// Check the signature and standard claims of an ID token.
+/// RSA keys come from the provider's JWKS, HMAC tokens are signed with the client secret.
+fn verify_id_token(
+    token: &str,
+    provider: &Provider,
+    metadata: &Metadata,
+    keys: &[Jwk],
+) -> Result<IdClaims, OidcError> {
+    let header = jsonwebtoken::decode_header(token)?;
+    let secret = client_secret(provider);
+    let key = match header.alg {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => match &secret {
+            Some(s) => DecodingKey::from_secret(s.as_bytes()),
+            None => return Err(OidcError::UnknownKey),
+        },
+        Algorithm::RS256
+        | Algorithm::RS384
+        | Algorithm::RS512
+        | Algorithm::PS256
+        | Algorithm::PS384
+        | Algorithm::PS512 => {
+            let jwk = keys
+                .iter()
+                .filter(|k| k.kty == "RSA")
+                .find(|k| header.kid.is_none() || k.kid == header.kid)
+                .ok_or(OidcError::UnknownKey)?;
+            match (&jwk.n, &jwk.e) {
+                (Some(n), Some(e)) => DecodingKey::from_rsa_components(n, e)?,
+                _ => return Err(OidcError::UnknownKey),
+            }
+        }
+        alg => {
+            return Err(OidcError::Provider(format!(
+                "ID token uses the unsupported algorithm {:?}",
+                alg
+            )))
+        }
+    };
+    let mut validation = Validation::new(header.alg);
+    validation.leeway = 60;
+    validation.set_issuer(&[&metadata.issuer]);
+    validation.set_audience(&[&provider.client_id]);
+    Ok(jsonwebtoken::decode::<IdClaims>(token, &key, &validation)?.claims)
+}
+
+/// Store a new sign in and return the provider's authorization url to send the person to
+async fn begin(
+    conn: &UsersDbConn,
+    cache: &Cache,
+    name: String,
+    link_usr_id: Option<i32>,
+) -> Result<String, OidcError> {
+    let provider = find_provider(&name)?;
+    let (metadata, _) = cache.get(&name, provider, false).await?;
+    let login = models::OidcLogin {
+        state: common::generate_code(32),
+        provider: name.clone(),
+        code_verifier: common::generate_code(64),
+        nonce: common::generate_code(32),
+        link_usr_id,
+        created_at: chrono::Utc::now().naive_utc(),
+    };
+    let url = reqwest::Url::parse_with_params(
+        &metadata.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", &provider.client_id),
+            ("redirect_uri", &redirect_uri(&name)),
+            ("scope", &provider.scopes.join(" ")),
+            ("state", &login.state),
+            ("nonce", &login.nonce),
+            ("code_challenge", &code_challenge(&login.code_verifier)),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|e| OidcError::Provider(e.to_string()))?;
+    conn.run(move |c| {
+        diesel::insert_into(crate::schema::oidc_logins::table)
+            .values(login)
+            .execute(c)
+    })
+    .await?;
+    Ok(url.to_string())
+}
+
+/// Pick a free username for a new account, based on what the provider calls them
+fn new_username(c: &diesel::PgConnection, claims: &IdClaims) -> Result<String, OidcError> {
+    let preferred = claims
+        .preferred_username
+        .as_deref()
+        .or_else(|| claims.email.as_deref())
+        .unwrap_or("teacher");
+    let base: String = preferred
+        .split('@')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .take(16)
+        .collect::<String>()
+        .to_lowercase();
+    let base = if base.is_empty() { "teacher".to_owned() } else { base };
+    for n in 1..1000 {
+        let candidate = if n == 1 { base.clone() } else { format!("{}{}", base, n) };
+        if validation::validate_username(&candidate).is_ok()
+            && common::find_user_by_name(c, candidate.clone())?.is_none()
+        {
+            return Ok(candidate);
+        }
+    }
+    Err(OidcError::Refused("Unable to pick a username for this account"))
+}
+
+/// What a finished sign in did
+enum Outcome {
+    SignedIn(models::User),
+    Linked,
+}
+
+/// Sign in with, link, or create an account for a verified identity
+fn complete(
+    c: &diesel::PgConnection,
+    recorder: &audit::Recorder,
+    provider: &Provider,
+    login: &models::OidcLogin,
+    claims: IdClaims,
+) -> Result<Outcome, OidcError> {
+    use crate::schema::user_identities::dsl as identities;
+    let now = chrono::Utc::now().naive_utc();
+    //Some providers leave `email_verified` out while letting people set any address, so only an explicit yes is trusted
+    let email = claims.email.clone().filter(|_| claims.email_verified == Some(true));
+    let existing = identities::user_identities
+        .filter(identities::provider.eq(&login.provider))
+        .filter(identities::subject.eq(&claims.sub))
+        .first::<models::UserIdentity>(c)
+        .optional()?;
+
+    if let Some(link_usr_id) = login.link_usr_id {
+        match existing {
+            Some(i) if i.usr_id != link_usr_id => {
+                return Err(OidcError::Refused("This identity is already linked to another account"))
+            }
+            Some(_) => {}
+            None => {
+                diesel::insert_into(identities::user_identities)
+                    .values(models::InsertableUserIdentity {
+                        usr_id: link_usr_id,
+                        provider: login.provider.clone(),
+                        subject: claims.sub.clone(),
+                        email,
+                    })
+                    .execute(c)?;
+                recorder
+                    .event(Some(link_usr_id), Some(link_usr_id), "oidc.link")
+                    .after(&login.provider)
+                    .save(c)?;
+            }
+        }
+        return Ok(Outcome::Linked);
+    }
+
+    let user = match existing {
+        Some(i) => {
+            diesel::update(identities::user_identities.filter(identities::id.eq(i.id)))
+                .set((identities::last_login_at.eq(now), identities::email.eq(email)))
+                .execute(c)?;
+            common::find_user(c, i.usr_id)?.ok_or(diesel::result::Error::NotFound)?
+        }
+        None if provider.create_accounts => {
+            use crate::schema::users;
+            let usr = new_username(c, &claims)?;
+            let nickname = claims
+                .name
+                .clone()
+                .map(|n| n.trim().to_owned())
+                .filter(|n| validation::validate_nickname(n).is_ok())
+                .unwrap_or_else(|| usr.clone());
+            //The account can only be used through the provider until someone sets a password
+            let pwd = common::hash_string_with_salt(common::generate_code(32))
+                .map_err(|e| OidcError::Provider(e.to_string()))?;
+            let user: models::User = diesel::insert_into(users::table)
+                .values(models::NewUser {
+                    usr,
+                    pwd,
+                    nickname,
+                    pending_nickname: None,
+                    is_guest: false,
+                    current_costume: "default".into(),
+                    costumes: vec!["default".into()],
+                    achievements: vec![],
+                })
+                .get_result(c)?;
+            let user: models::User = diesel::update(users::table.filter(users::id.eq(user.id)))
+                .set((
+                    users::role.eq(&provider.role),
+                    users::email.eq(email.clone()),
+                    users::email_verified.eq(email.is_some()),
+                ))
+                .get_result(c)?;
+            diesel::insert_into(identities::user_identities)
+                .values(models::InsertableUserIdentity {
+                    usr_id: user.id,
+                    provider: login.provider.clone(),
+                    subject: claims.sub.clone(),
+                    email,
+                })
+                .execute(c)?;
+            recorder
+                .event(Some(user.id), Some(user.id), "oidc.create")
+                .after(&user)
+                .save(c)?;
+            metrics::account_created("oidc", 1);
+            user
+        }
+        None => {
+            return Err(OidcError::Refused(
+                "No account is linked to this identity, sign in and link it first",
+            ))
+        }
+    };
+    if user.is_guest || user.deleted_at.is_some() {
+        return Err(OidcError::Refused("This account can't be signed in to"));
+    }
+    Ok(Outcome::SignedIn(user))
+}
+
+/// The providers that can be signed in with
+#[get("/api/v1/oidc/providers")]
+async fn list_providers() -> models::Response {
+    let mut providers: Vec<ProviderSummary> = PROVIDERS
+        .iter()
+        .map(|(name, p)| ProviderSummary {
+            name: name.clone(),
+            display_name: p.display_name.clone(),
+        })
+        .collect();
+    providers.sort_by(|a, b| a.name.cmp(&b.name));
+    models::ResponseBuilder {
+        data: providers,
+        status: Status::Ok,
+    }
+    .build()
+}
+
+/// Sign in with a provider, redirecting to it
+#[get("/api/v1/oidc/<provider>/login")]
+async fn start_login(
+    conn: UsersDbConn,
+    cache: &State<Cache>,
+    provider: String,
+) -> Result<Redirect, models::Response> {
+    let url = begin(&conn, cache, provider, None).await?;
+    Ok(Redirect::to(url))
+}
+
+/// Start linking a provider to the signed in account, returning the url to send them to
+#[post("/api/v1/oidc/<provider>/link")]
+async fn start_link(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    cache: &State<Cache>,
+    provider: String,
+) -> Result<models::Response, models::Response> {
+    let token = token?;
+    token.deny_guest()?;
+    let url = begin(&conn, cache, provider, Some(token.sub)).await?;
+    Ok(models::ResponseBuilder {
+        data: AuthorizationUrl { url },
+        status: Status::Ok,
+    }
+    .build())
+}
+
+/// Where the provider sends people back to. Exchanges the code for an ID token, then sends them to
+/// the website with one of our tokens, or back to their account page if they were linking an identity.
+#[get("/api/v1/oidc/<provider>/callback?<code>&<state>&<error>")]
+async fn callback(
+    conn: UsersDbConn,
+    cache: &State<Cache>,
+    recorder: audit::Recorder,
+    device: sessions::Device,
+    provider: String,
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+) -> Result<Redirect, models::Response> {
+    let config = find_provider(&provider)?;
+    if let Some(e) = error {
+        return Err(OidcError::Provider(e).into());
+    }
+    let (code, state) = match (code, state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => return Err(OidcError::Expired.into()),
+    };
+
+    //Each sign in can only be completed once
+    let name = provider.clone();
+    let login = conn
+        .run(move |c| {
+            use crate::schema::oidc_logins::dsl;
+            diesel::delete(
+                dsl::oidc_logins
+                    .filter(dsl::state.eq(state))
+                    .filter(dsl::provider.eq(name)),
+            )
+            .get_result::<models::OidcLogin>(c)
+            .optional()
+        })
+        .await
+        .map_err(OidcError::from)?;
+    let cutoff = chrono::Utc::now().naive_utc() - chrono::Duration::minutes(LOGIN_EXPIRY_MINUTES);
+    let login = match login {
+        Some(l) if l.created_at > cutoff => l,
+        _ => return Err(OidcError::Expired.into()),
+    };
+
+    let (metadata, keys) = cache.get(&provider, config, false).await?;
+    let mut form = vec![
+        ("grant_type", "authorization_code".to_owned()),
+        ("code", code),
+        ("redirect_uri", redirect_uri(&provider)),
+        ("client_id", config.client_id.clone()),
+        ("code_verifier", login.code_verifier.clone()),
+    ];
+    if let Some(secret) = client_secret(config) {
+        form.push(("client_secret", secret));
+    }
+    let r = cache
+        .client
+        .post(&metadata.token_endpoint)
+        .form(&form)
+        .send()
+        .await
+        .map_err(OidcError::from)?;
+    if !r.status().is_success() {
+        let body = r.text().await.unwrap_or_default();
+        return Err(OidcError::Provider(body).into());
+    }
+    let tokens: TokenResponse = r.json().await.map_err(OidcError::from)?;
+
+    //Providers rotate their keys, so fetch them again if the token uses one we haven't seen
+    let claims = match verify_id_token(&tokens.id_token, config, &metadata, &keys) {
+        Err(OidcError::UnknownKey) => {
+            let (metadata, keys) = cache.get(&provider, config, true).await?;
+            verify_id_token(&tokens.id_token, config, &metadata, &keys)?
+        }
+        r => r?,
+    };
+    if claims.nonce.as_deref() != Some(login.nonce.as_str()) {
+        return Err(OidcError::Refused("ID token is not for this sign in").into());
+    }
+
+    let outcome = conn
+        .run(move |c| c.transaction(|| complete(c, &recorder, config, &login, claims)))
+        .await;
+    match &outcome {
+        Ok(Outcome::SignedIn(_)) => metrics::login("oidc", true),
+        Err(OidcError::Refused(_)) => metrics::login("oidc", false),
+        _ => {}
+    }
+    match outcome? {
+        //The same second factor is needed as when logging in with a password
+        Outcome::SignedIn(user) if user.is_staff() || user.totp_enabled_at.is_some() => {
+            Ok(Redirect::to(format!(
+                "{}/login#mfa_token={}&enrolled={}",
+                *BROWSER_BASE_URL,
+                models::Claims::new_mfa_pending_token(&user),
+                user.totp_enabled_at.is_some()
+            )))
+        }
+        Outcome::SignedIn(user) => {
+            let user_id = user.id;
+            let sid = conn
+                .run(move |c| sessions::start(c, user_id, device))
+                .await
+                .map_err(OidcError::from)?;
+            Ok(Redirect::to(format!(
+                "{}/login#token={}",
+                *BROWSER_BASE_URL,
+                models::Claims::new_token(&user, sid)
+            )))
+        }
+        Outcome::Linked => Ok(Redirect::to(format!(
+            "{}/account#linked={}",
+            *BROWSER_BASE_URL, provider
+        ))),
+    }
+}
+
+/// The identities linked to the signed in account
+#[get("/api/v1/oidc/identities")]
+async fn list_identities(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+) -> Result<models::Response, models::Response> {
+    let token = token?;
+    let r = conn
+        .run(move |c| {
+            use crate::schema::user_identities::dsl::*;
+            user_identities
+                .filter(usr_id.eq(token.sub))
+                .order(created_at.asc())
+                .load::<models::UserIdentity>(c)
+        })
+        .await
+        .map_err(OidcError::from)?;
+    Ok(models::ResponseBuilder {
+        data: r,
+        status: Status::Ok,
+    }
+    .build())
+}
+
+/// Unlink a provider from the signed in account
+#[delete("/api/v1/oidc/<provider>/link")]
+async fn unlink_identity(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    provider: String,
+) -> Result<models::Response, models::Response> {
+    let token = token?;
+    let name = provider.clone();
+    let removed = conn
+        .run(move |c| {
+            c.transaction(|| {
+                use crate::schema::user_identities::dsl;
+                let removed = diesel::delete(
+                    dsl::user_identities
+                        .filter(dsl::usr_id.eq(token.sub))
+                        .filter(dsl::provider.eq(&name)),
+                )
+                .execute(c)?;
+                if removed > 0 {
+                    recorder
+                        .event(Some(token.sub), Some(token.sub), "oidc.unlink")
+                        .before(&name)
+                        .save(c)?;
+                }
+                Ok(removed)
+            })
+        })
+        .await
+        .map_err(OidcError::Database)?;
+    if removed == 0 {
+        return Err(models::ResponseBuilder {
+            data: "Identity Not Found",
+            status: Status::NotFound,
+        }
+        .build());
+    }
+    Ok(models::ResponseBuilder {
+        data: format!("Unlinked {}", provider),
+        status: Status::Ok,
+    }
+    .build())
+}
@@ -0,0 +1,441 @@
+use crate::{admin, api_keys, classroom, keys, metrics, mfa, oidc, sessions};
+use rocket::fairing::AdHoc;
+use rocket::http::{ContentType, Status};
+use rocket::{Route, State};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![get_openapi]
+}
+
+/// Generates the schema for a type, adding any types it refers to the generator's components
+pub type SchemaFn = fn(&mut SchemaGenerator) -> Schema;
+
+fn schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    gen.subschema_for::<T>()
+}
+
+/// What is needed to call a route
+pub enum Auth {
+    None,
+    /// A token from logging in, in the `Authorisation` header
+    Token,
+    /// A token from the first step of logging in, when a second factor is needed
+    MfaPending,
+    /// A token belonging to an admin, see `models::Admin`
+    Admin,
+    /// A token from logging in, or for an API key given the scope, see `models::Scoped`
+    Scoped(&'static str),
+    /// An API key in the `X-Api-Key` header
+    ApiKey,
+    /// The `METRICS_TOKEN` as a bearer token, when it is set
+    MetricsToken,
+}
+
+/// What is sent back with a status
+enum Content {
+    /// JSON of the given schema, wrapped as `{"data": ...}` like every `models::Response`
+    Json(SchemaFn),
+    /// JSON of the given schema as it is, for formats set by someone else such as a JWK Set
+    Unwrapped(SchemaFn),
+    /// A file or document of the given media type
+    Raw(&'static str),
+    /// A redirect, with nothing worth describing in the body
+    Redirect,
+}
+
+/// Documents a route, which is matched to its docs by the name of its handler.
+/// Paths, parameters and request media types are taken from the route itself.
+pub struct Operation {
+    handler: &'static str,
+    summary: &'static str,
+    auth: Auth,
+    body: Option<Option<SchemaFn>>,
+    params: Vec<(&'static str, SchemaFn)>,
+    responses: Vec<(u16, &'static str, Content)>,
+    errors: Vec<u16>,
+}
+
+impl Operation {
+    pub fn new(handler: &'static str, summary: &'static str) -> Operation {
+        Operation {
+            handler,
+            summary,
+            auth: Auth::None,
+            body: None,
+            params: vec![],
+            responses: vec![],
+            errors: vec![],
+        }
+    }
+
+    pub fn auth(mut self, auth: Auth) -> Operation {
+        self.auth = auth;
+        self
+    }
+
+    /// A JSON request body
+    pub fn body<T: JsonSchema>(mut self) -> Operation {
+        self.body = Some(Some(schema::<T>));
+        self
+    }
+
+    /// A request body which isn't JSON, described by the route's format
+    pub fn raw_body(mut self) -> Operation {
+        self.body = Some(None);
+        self
+    }
+
+    /// The type of a path or query parameter, parameters are strings unless given here
+    pub fn param<T: JsonSchema>(mut self, name: &'static str) -> Operation {
+        self.params.push((name, schema::<T>));
+        self
+    }
+
+    /// Responds with `T` as the data of a JSON response
+    pub fn returns<T: JsonSchema>(mut self, status: u16, description: &'static str) -> Operation {
+        self.responses
+            .push((status, description, Content::Json(schema::<T>)));
+        self
+    }
+
+    /// Responds with JSON which isn't wrapped in `data`, for routes following another standard
+    pub fn returns_unwrapped<T: JsonSchema>(
+        mut self,
+        status: u16,
+        description: &'static str,
+    ) -> Operation {
+        self.responses
+            .push((status, description, Content::Unwrapped(schema::<T>)));
+        self
+    }
+
+    /// Responds with something other than JSON, such as a file
+    pub fn returns_raw(
+        mut self,
+        status: u16,
+        media_type: &'static str,
+        description: &'static str,
+    ) -> Operation {
+        self.responses
+            .push((status, description, Content::Raw(media_type)));
+        self
+    }
+
+    /// Redirects with a `303 See Other`
+    pub fn redirects(mut self, description: &'static str) -> Operation {
+        self.responses.push((303, description, Content::Redirect));
+        self
+    }
+
+    /// Error statuses this route returns on top of those from its auth, which are added automatically
+    pub fn errors(mut self, statuses: &[u16]) -> Operation {
+        self.errors.extend_from_slice(statuses);
+        self
+    }
+}
+
+/// The body of every error, either a message or the problems with each field from validation
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+struct Error {
+    data: ErrorData,
+}
+
+#[derive(JsonSchema)]
+#[schemars(untagged)]
+#[allow(dead_code)]
+enum ErrorData {
+    Message(String),
+    Fields(std::collections::BTreeMap<String, Vec<String>>),
+}
+
+/// Every documented route
+fn operations() -> Vec<Operation> {
+    vec![
+        crate::operations(),
+        admin::operations(),
+        classroom::operations(),
+        oidc::operations(),
+        mfa::operations(),
+        keys::operations(),
+        api_keys::operations(),
+        sessions::operations(),
+        metrics::operations(),
+        vec![Operation::new("get_openapi", "This document").returns_raw(
+            200,
+            "application/json",
+            "The OpenAPI document for the api",
+        )],
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// The routes which have nothing in `operations`, so are missing from the spec
+fn undocumented<'a>(routes: impl Iterator<Item = &'a Route>) -> Vec<String> {
+    let operations = operations();
+    routes
+        .filter_map(|r| r.name.as_deref())
+        .filter(|name| !operations.iter().any(|o| &o.handler == name))
+        .map(|name| name.to_owned())
+        .collect()
+}
+
+/// The dynamic parameter in a path segment or query, such as `user_id` in `<user_id>`
+fn dynamic_name(segment: &str) -> Option<&str> {
+    let name = segment.strip_prefix('<')?.strip_suffix('>')?;
+    Some(name.trim_end_matches(".."))
+}
+
+fn parameter(
+    gen: &mut SchemaGenerator,
+    operation: Option<&Operation>,
+    name: &str,
+    location: &str,
+) -> Value {
+    let schema = match operation.and_then(|o| o.params.iter().find(|(n, _)| *n == name)) {
+        Some((_, f)) => f(gen),
+        None => schema::<String>(gen),
+    };
+    json!({
+        "name": name,
+        "in": location,
+        "required": location == "path",
+        "schema": schema,
+    })
+}
+
+fn error_response(gen: &mut SchemaGenerator, status: u16) -> Value {
+    let reason = Status::from_code(status)
+        .and_then(|s| s.reason())
+        .unwrap_or("Error");
+    json!({
+        "description": reason,
+        "content": {"application/json": {"schema": schema::<Error>(gen)}},
+    })
+}
+
+fn operation_json(
+    gen: &mut SchemaGenerator,
+    route: &Route,
+    operation: Option<&Operation>,
+) -> Value {
+    let name = route.name.as_deref().unwrap_or_default();
+    let mut op = Map::new();
+    op.insert("operationId".into(), json!(name));
+    op.insert(
+        "summary".into(),
+        json!(operation.map(|o| o.summary).unwrap_or("Undocumented")),
+    );
+
+    let mut parameters = vec![];
+    for segment in route.uri.path().to_string().split('/') {
+        if let Some(name) = dynamic_name(segment) {
+            parameters.push(parameter(gen, operation, name, "path"));
+        }
+    }
+    if let Some(query) = route.uri.query() {
+        for segment in query.to_string().split('&') {
+            if let Some(name) = dynamic_name(segment) {
+                parameters.push(parameter(gen, operation, name, "query"));
+            }
+        }
+    }
+    if !parameters.is_empty() {
+        op.insert("parameters".into(), json!(parameters));
+    }
+
+    let operation = match operation {
+        Some(o) => o,
+        None => {
+            op.insert(
+                "responses".into(),
+                json!({"default": {"description": "Undocumented"}}),
+            );
+            return Value::Object(op);
+        }
+    };
+
+    if let Some(body) = &operation.body {
+        let media_type = route
+            .format
+            .as_ref()
+            .map(|f| f.to_string())
+            .unwrap_or_else(|| "application/json".into());
+        let schema = match body {
+            Some(f) => f(gen),
+            None => schema::<String>(gen),
+        };
+        op.insert(
+            "requestBody".into(),
+            json!({"required": true, "content": {media_type: {"schema": schema}}}),
+        );
+    }
+
+    let mut responses = Map::new();
+    for (status, description, content) in &operation.responses {
+        let response = match content {
+            Content::Json(f) => json!({
+                "description": description,
+                "content": {"application/json": {"schema": {
+                    "type": "object",
+                    "required": ["data"],
+                    "properties": {"data": f(gen)},
+                }}},
+            }),
+            Content::Unwrapped(f) => json!({
+                "description": description,
+                "content": {"application/json": {"schema": f(gen)}},
+            }),
+            Content::Raw(media_type) => json!({
+                "description": description,
+                "content": {*media_type: {"schema": {"type": "string", "format": "binary"}}},
+            }),
+            Content::Redirect => json!({
+                "description": description,
+                "headers": {"Location": {"schema": {"type": "string"}}},
+            }),
+        };
+        //A status can be given more than once, when the media type depends on the request
+        match responses.get_mut(&status.to_string()) {
+            Some(existing) => {
+                if let (Some(Value::Object(a)), Some(Value::Object(b))) =
+                    (existing.get_mut("content"), response.get("content"))
+                {
+                    a.extend(b.clone());
+                }
+            }
+            None => {
+                responses.insert(status.to_string(), response);
+            }
+        }
+    }
+
+    //Rocket's own catcher answers bodies which aren't valid JSON for the type, so there is no `data`
+    if let Some(Some(_)) = operation.body {
+        responses.insert(
+            "422".into(),
+            json!({"description": "The body couldn't be parsed"}),
+        );
+    }
+
+    let scheme = match operation.auth {
+        Auth::None => None,
+        Auth::Token | Auth::MfaPending | Auth::Admin | Auth::Scoped(_) => Some("token"),
+        Auth::ApiKey => Some("apiKey"),
+        Auth::MetricsToken => Some("metricsToken"),
+    };
+    if let Some(scheme) = scheme {
+        op.insert("security".into(), json!([{ scheme: [] }]));
+    }
+    //Checking a token needs the database, which may not be reachable
+    let auth_errors: &[u16] = match operation.auth {
+        Auth::None => &[],
+        Auth::Token | Auth::MfaPending => &[401, 503],
+        Auth::Admin | Auth::Scoped(_) => &[401, 403, 503],
+        Auth::ApiKey | Auth::MetricsToken => &[401],
+    };
+    match operation.auth {
+        Auth::MfaPending => {
+            op.insert(
+                "description".into(),
+                json!("Takes the token returned when logging in needs a second factor."),
+            );
+        }
+        Auth::Scoped(scope) => {
+            op.insert(
+                "description".into(),
+                json!(format!("Tokens for API keys need the `{}` scope.", scope)),
+            );
+        }
+        _ => {}
+    }
+    //Any route may fail with a 500, if only from a panic
+    let mut errors = operation.errors.clone();
+    errors.extend_from_slice(auth_errors);
+    errors.push(500);
+    for status in errors {
+        responses
+            .entry(status.to_string())
+            .or_insert_with(|| error_response(gen, status));
+    }
+    op.insert("responses".into(), Value::Object(responses));
+    Value::Object(op)
+}
+
+/// Build the OpenAPI document for every route mounted on the server
+pub fn generate<'a>(routes: impl Iterator<Item = &'a Route>) -> Value {
+    let operations = operations();
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let mut paths = Map::new();
+    for route in routes {
+        let operation = operations
+            .iter()
+            .find(|o| route.name.as_deref() == Some(o.handler));
+        let path = route
+            .uri
+            .path()
+            .to_string()
+            .split('/')
+            .map(|s| match dynamic_name(s) {
+                Some(name) => format!("{{{}}}", name),
+                None => s.to_owned(),
+            })
+            .collect::<Vec<String>>()
+            .join("/");
+        let method = route.method.as_str().to_lowercase();
+        let item = paths.entry(path).or_insert_with(|| json!({}));
+        item[method] = operation_json(&mut gen, route, operation);
+    }
+
+    let schemas = serde_json::to_value(gen.take_definitions()).unwrap_or_default();
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Kemu Kupu API",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "token": {
+                    "type": "apiKey",
+                    "in": "header",
+                    "name": "Authorisation",
+                    "description": "A token from logging in, or from `/api/v1/api-keys/token`",
+                },
+                "apiKey": {"type": "apiKey", "in": "header", "name": "X-Api-Key"},
+                "metricsToken": {"type": "http", "scheme": "bearer"},
+            },
+        },
+    })
+}
+
+/// The generated document, kept so it is only built once
+struct Spec(String);
+
+/// Generates the OpenAPI document once every route has been mounted
+pub fn fairing() -> AdHoc {
+    AdHoc::on_ignite("OpenAPI", |rocket| {
+        Box::pin(async move {
+            let undocumented = undocumented(rocket.routes());
+            if !undocumented.is_empty() {
+                tracing::warn!(routes = ?undocumented, "Some routes are missing from the OpenAPI document");
+            }
+            let spec = generate(rocket.routes());
+            rocket.manage(Spec(spec.to_string()))
+        })
+    })
+}
+
+/// The OpenAPI document describing every route, as shown at `/api/docs`
+#[get("/api/openapi.json")]
+fn get_openapi(spec: &State<Spec>) -> (ContentType, String) {
+    (ContentType::JSON, spec.0.clone())
+}
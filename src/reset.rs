@@ -0,0 +1,120 @@
+use crate::models;
+use diesel::prelude::*;
+
+fn to_json<T: rocket::serde::Serialize>(value: &T) -> Result<serde_json::Value, diesel::result::Error> {
+    serde_json::to_value(value).map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))
+}
+
+fn from_json<T: rocket::serde::de::DeserializeOwned>(
+    value: serde_json::Value,
+) -> Result<T, diesel::result::Error> {
+    serde_json::from_value(value).map_err(|e| diesel::result::Error::DeserializationError(Box::new(e)))
+}
+
+/// Save a snapshot of the user's statistics, then clear their scores, unlocks, costumes and achievements.
+/// Must be called within a transaction, returns None if the user doesn't exist.
+pub fn reset_with_snapshot(
+    c: &diesel::PgConnection,
+    user_id: i32,
+) -> Result<Option<models::User>, diesel::result::Error> {
+    use crate::schema::{reset_snapshots, scores, unlocks, users};
+    let user = match crate::common::find_user(c, user_id)? {
+        Some(u) => u,
+        None => return Ok(None),
+    };
+    let old_scores = scores::table
+        .filter(scores::usr_id.eq(user_id))
+        .order(scores::id.asc())
+        .load::<models::Score>(c)?;
+    let old_unlocks = unlocks::table
+        .filter(unlocks::usr_id.eq(user_id))
+        .order(unlocks::id.asc())
+        .load::<models::Unlock>(c)?;
+
+    diesel::insert_into(reset_snapshots::table)
+        .values(models::InsertableResetSnapshot {
+            usr_id: user_id,
+            scores: to_json(&old_scores)?,
+            unlocks: to_json(&old_unlocks)?,
+            current_costume: user.current_costume,
+            costumes: user.costumes.into_iter().map(|c| c.name).collect(),
+            achievements: user.achievements.into_iter().map(|a| a.name).collect(),
+            stars: crate::common::star_balance(c, user_id)? as i32,
+            bonus_stars: user.bonus_stars,
+        })
+        .execute(c)?;
+
+    diesel::delete(scores::table.filter(scores::usr_id.eq(user_id))).execute(c)?;
+    diesel::delete(unlocks::table.filter(unlocks::usr_id.eq(user_id))).execute(c)?;
+    let no_achievements: Vec<String> = vec![];
+    diesel::update(users::table.filter(users::id.eq(user_id)))
+        .set((
+            users::achievements.eq(no_achievements),
+            users::costumes.eq(vec!["default".to_owned()]),
+            users::current_costume.eq("default".to_owned()),
+            users::bonus_stars.eq(0),
+        ))
+        .get_result(c)
+        .map(Some)
+}
+
+/// Restore the user's most recent snapshot, if it was taken after `not_before` and hasn't already been restored.
+/// Anything earned since the reset is kept alongside what is restored.
+/// Must be called within a transaction, returns None if there is no snapshot to restore.
+pub fn undo_reset(
+    c: &diesel::PgConnection,
+    user_id: i32,
+    not_before: chrono::NaiveDateTime,
+) -> Result<Option<models::User>, diesel::result::Error> {
+    use crate::schema::{reset_snapshots, scores, unlocks, users};
+    let snapshot = reset_snapshots::table
+        .filter(reset_snapshots::usr_id.eq(user_id))
+        .order(reset_snapshots::created_at.desc())
+        .first::<models::ResetSnapshot>(c)
+        .optional()?;
+    let snapshot = match snapshot {
+        Some(s) if s.restored_at.is_none() && s.created_at >= not_before => s,
+        _ => return Ok(None),
+    };
+
+    let old_scores: Vec<models::Score> = from_json(snapshot.scores)?;
+    let old_unlocks: Vec<models::Unlock> = from_json(snapshot.unlocks)?;
+    diesel::insert_into(scores::table)
+        .values(&old_scores)
+        .on_conflict_do_nothing()
+        .execute(c)?;
+    diesel::insert_into(unlocks::table)
+        .values(&old_unlocks)
+        .on_conflict_do_nothing()
+        .execute(c)?;
+
+    let user = match crate::common::find_user(c, user_id)? {
+        Some(u) => u,
+        None => return Ok(None),
+    };
+    let mut restored_costumes = snapshot.costumes;
+    for costume in user.costumes {
+        if !restored_costumes.contains(&costume.name) {
+            restored_costumes.push(costume.name);
+        }
+    }
+    let mut restored_achievements = snapshot.achievements;
+    for achievement in user.achievements {
+        if !restored_achievements.contains(&achievement.name) {
+            restored_achievements.push(achievement.name);
+        }
+    }
+
+    diesel::update(reset_snapshots::table.filter(reset_snapshots::id.eq(snapshot.id)))
+        .set(reset_snapshots::restored_at.eq(chrono::Utc::now().naive_utc()))
+        .execute(c)?;
+    diesel::update(users::table.filter(users::id.eq(user_id)))
+        .set((
+            users::costumes.eq(restored_costumes),
+            users::achievements.eq(restored_achievements),
+            users::current_costume.eq(snapshot.current_costume),
+            users::bonus_stars.eq(users::bonus_stars + snapshot.bonus_stars),
+        ))
+        .get_result(c)
+        .map(Some)
+}
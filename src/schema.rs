@@ -1,9 +1,152 @@
+table! {
+    api_keys (id) {
+        id -> Int4,
+        usr_id -> Int4,
+        name -> Text,
+        secret -> Text,
+        scopes -> Array<Text>,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    audit_events (id) {
+        id -> Int4,
+        actor_id -> Nullable<Int4>,
+        target_id -> Nullable<Int4>,
+        action -> Text,
+        before -> Nullable<Jsonb>,
+        after -> Nullable<Jsonb>,
+        ip -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    classrooms (id) {
+        id -> Int4,
+        name -> Text,
+        teacher_id -> Int4,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    email_verifications (id) {
+        id -> Int4,
+        usr_id -> Int4,
+        email -> Text,
+        selector -> Text,
+        verifier -> Text,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    login_links (id) {
+        id -> Int4,
+        usr_id -> Int4,
+        secret -> Text,
+        created_by -> Nullable<Int4>,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    oidc_logins (state) {
+        state -> Text,
+        provider -> Text,
+        code_verifier -> Text,
+        nonce -> Text,
+        link_usr_id -> Nullable<Int4>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    password_resets (id) {
+        id -> Int4,
+        usr_id -> Int4,
+        issued_by -> Nullable<Int4>,
+        code -> Text,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    recovery_codes (id) {
+        id -> Int4,
+        usr_id -> Int4,
+        code -> Text,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    reset_snapshots (id) {
+        id -> Int4,
+        usr_id -> Int4,
+        scores -> Jsonb,
+        unlocks -> Jsonb,
+        current_costume -> Text,
+        costumes -> Array<Text>,
+        achievements -> Array<Text>,
+        stars -> Int4,
+        created_at -> Timestamp,
+        restored_at -> Nullable<Timestamp>,
+        bonus_stars -> Int4,
+    }
+}
+
 table! {
     scores (id) {
         id -> Int4,
         usr_id -> Int4,
         score -> Int4,
         num_stars -> Int4,
+        voided -> Bool,
+        flagged -> Bool,
+    }
+}
+
+table! {
+    sessions (id) {
+        id -> Int4,
+        usr_id -> Int4,
+        device_label -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        ip -> Nullable<Text>,
+        created_at -> Timestamp,
+        last_seen_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    unlocks (id) {
+        id -> Int4,
+        usr_id -> Int4,
+        kind -> Text,
+        name -> Text,
+        unlocked_at -> Timestamp,
+    }
+}
+
+table! {
+    user_identities (id) {
+        id -> Int4,
+        usr_id -> Int4,
+        provider -> Text,
+        subject -> Text,
+        email -> Nullable<Text>,
+        created_at -> Timestamp,
+        last_login_at -> Timestamp,
     }
 }
 
@@ -16,12 +159,48 @@ table! {
         current_costume -> Text,
         costumes -> Array<Text>,
         achievements -> Array<Text>,
+        role -> Text,
+        tokens_valid_after -> Timestamp,
+        email -> Nullable<Text>,
+        email_verified -> Bool,
+        pending_nickname -> Nullable<Text>,
+        is_guest -> Bool,
+        last_active_at -> Timestamp,
+        deleted_at -> Nullable<Timestamp>,
+        bonus_stars -> Int4,
+        classroom_id -> Nullable<Int4>,
+        year_level -> Nullable<Int4>,
+        picture_password -> Nullable<Text>,
+        badge_secret -> Nullable<Text>,
+        totp_secret -> Nullable<Text>,
+        totp_enabled_at -> Nullable<Timestamp>,
+        totp_last_step -> Int8,
     }
 }
 
+joinable!(api_keys -> users (usr_id));
+joinable!(email_verifications -> users (usr_id));
+joinable!(password_resets -> users (usr_id));
+joinable!(recovery_codes -> users (usr_id));
+joinable!(reset_snapshots -> users (usr_id));
 joinable!(scores -> users (usr_id));
+joinable!(sessions -> users (usr_id));
+joinable!(unlocks -> users (usr_id));
+joinable!(user_identities -> users (usr_id));
 
 allow_tables_to_appear_in_same_query!(
+    api_keys,
+    audit_events,
+    classrooms,
+    email_verifications,
+    login_links,
+    oidc_logins,
+    password_resets,
+    recovery_codes,
+    reset_snapshots,
     scores,
+    sessions,
+    unlocks,
+    user_identities,
     users,
 );
@@ -0,0 +1,209 @@
+use crate::openapi::{Auth, Operation};
+use crate::{audit, models, UsersDbConn, JWT_EXPIRY_TIME_HOURS};
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::Serialize;
+use schemars::JsonSchema;
+
+/// The longest device label that is kept, anything beyond it is cut off
+const MAX_DEVICE_LABEL_LENGTH: usize = 64;
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![list_sessions, revoke_session]
+}
+
+pub fn operations() -> Vec<Operation> {
+    vec![
+        Operation::new("list_sessions", "List the devices you are logged in on")
+            .auth(Auth::Token)
+            .returns::<Vec<SessionDetails>>(200, "Active sessions, most recently seen first"),
+        Operation::new("revoke_session", "Log out of a device")
+            .auth(Auth::Token)
+            .param::<i32>("session_id")
+            .returns::<models::Session>(200, "The revoked session")
+            .errors(&[404]),
+    ]
+}
+
+/// The device a request came from, recorded against the session started when logging in.
+/// Apps on shared devices can name them with the `X-Device-Label` header.
+pub struct Device {
+    pub label: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Device {
+    type Error = ();
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        request::Outcome::Success(Device {
+            label: req
+                .headers()
+                .get_one("X-Device-Label")
+                .map(|l| l.trim().chars().take(MAX_DEVICE_LABEL_LENGTH).collect::<String>())
+                .filter(|l| !l.is_empty()),
+            user_agent: req.headers().get_one("User-Agent").map(|u| u.to_owned()),
+            ip: req.client_ip().map(|i| i.to_string()),
+        })
+    }
+}
+
+/// A session along with whether it is the one making the request
+#[derive(Serialize, JsonSchema)]
+struct SessionDetails {
+    #[serde(flatten)]
+    session: models::Session,
+    current: bool,
+}
+
+/// Start a session for a user who has just logged in, returning its id to put in their token
+pub fn start(
+    c: &diesel::PgConnection,
+    user_id: i32,
+    device: Device,
+) -> Result<i32, diesel::result::Error> {
+    use crate::schema::sessions::dsl::*;
+    diesel::insert_into(sessions)
+        .values(models::InsertableSession {
+            usr_id: user_id,
+            device_label: device.label,
+            user_agent: device.user_agent,
+            ip: device.ip,
+        })
+        .returning(id)
+        .get_result(c)
+}
+
+/// Sign out every session belonging to the users, returning how many were signed out
+pub fn revoke_all(
+    c: &diesel::PgConnection,
+    user_ids: Vec<i32>,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::sessions::dsl::*;
+    diesel::update(sessions.filter(usr_id.eq_any(user_ids)).filter(revoked_at.is_null()))
+        .set(revoked_at.eq(Some(chrono::Utc::now().naive_utc())))
+        .execute(c)
+}
+
+/// Start a session for the user and respond with a token for it
+pub async fn issue(
+    conn: &UsersDbConn,
+    user: &models::User,
+    device: Device,
+    status: Status,
+) -> models::Response {
+    let user_id = user.id;
+    match conn.run(move |c| start(c, user_id, device)).await {
+        Ok(sid) => models::ResponseBuilder {
+            data: if user.is_guest {
+                models::Claims::new_guest_token(user.id, sid)
+            } else {
+                models::Claims::new_token(user, sid)
+            },
+            status,
+        }
+        .build(),
+        Err(e) => models::Response::database_error(e),
+    }
+}
+
+/// The devices the signed in user is still logged in on, most recently seen first.
+/// Sessions whose tokens have expired, or were revoked by a password change, aren't included.
+#[get("/api/v1/student/sessions")]
+async fn list_sessions(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let token = token.unwrap();
+    let subject = token.sub;
+    let r: Result<Vec<models::Session>, diesel::result::Error> = conn
+        .run(move |c| {
+            use crate::schema::sessions::dsl::*;
+            use crate::schema::users;
+            let valid_after: chrono::NaiveDateTime = users::table
+                .filter(users::id.eq(subject))
+                .select(users::tokens_valid_after)
+                .first(c)?;
+            let expired =
+                chrono::Utc::now().naive_utc() - chrono::Duration::hours(*JWT_EXPIRY_TIME_HOURS as i64);
+            sessions
+                .filter(usr_id.eq(subject))
+                .filter(revoked_at.is_null())
+                .filter(created_at.ge(valid_after))
+                .filter(created_at.gt(expired))
+                .order(last_seen_at.desc())
+                .load::<models::Session>(c)
+        })
+        .await;
+
+    match r {
+        Ok(r) => models::ResponseBuilder {
+            data: r
+                .into_iter()
+                .map(|session| SessionDetails {
+                    current: Some(session.id) == token.sid,
+                    session,
+                })
+                .collect::<Vec<SessionDetails>>(),
+            status: Status::Ok,
+        }
+        .build(),
+        Err(e) => models::Response::database_error(e),
+    }
+}
+
+/// Sign out one of the signed in user's sessions, such as one left open on a shared iPad
+#[delete("/api/v1/student/sessions/<session_id>")]
+async fn revoke_session(
+    token: Result<models::Claims, models::Response>,
+    conn: UsersDbConn,
+    recorder: audit::Recorder,
+    session_id: i32,
+) -> models::Response {
+    if let Err(e) = token {
+        return e;
+    }
+    let subject = token.unwrap().sub;
+    let r: Result<Option<models::Session>, diesel::result::Error> = conn
+        .run(move |c| {
+            c.transaction(|| {
+                use crate::schema::sessions::dsl::*;
+                let session = diesel::update(
+                    sessions
+                        .filter(id.eq(session_id))
+                        .filter(usr_id.eq(subject))
+                        .filter(revoked_at.is_null()),
+                )
+                .set(revoked_at.eq(Some(chrono::Utc::now().naive_utc())))
+                .get_result::<models::Session>(c)
+                .optional()?;
+                if let Some(session) = &session {
+                    recorder
+                        .event(Some(subject), Some(subject), "session.revoke")
+                        .after(session)
+                        .save(c)?;
+                }
+                Ok(session)
+            })
+        })
+        .await;
+
+    match r {
+        Ok(Some(session)) => models::ResponseBuilder {
+            data: session,
+            status: Status::Ok,
+        }
+        .build(),
+        Ok(None) => models::ResponseBuilder {
+            data: "Session Not Found",
+            status: Status::NotFound,
+        }
+        .build(),
+        Err(e) => models::Response::database_error(e),
+    }
+}
@@ -0,0 +1,90 @@
+use crate::{common, logging};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use rocket::http::HeaderMap;
+use rocket::route::{self, Handler, Route};
+use rocket::{Data, Request};
+use tracing::{Instrument, Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Export traces when configured, over OTLP to `OTEL_EXPORTER_OTLP_ENDPOINT` or to the file at `OTEL_TRACES_FILE`.
+/// Trace context is taken from the `traceparent` header of incoming requests either way.
+pub fn layer<S>() -> Option<OpenTelemetryLayer<S, trace::Tracer>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    let config = trace::config().with_resource(Resource::new(vec![KeyValue::new(
+        "service.name",
+        common::env_or("OTEL_SERVICE_NAME", "kemu-api".to_owned()),
+    )]));
+
+    let tracer = if let Some(endpoint) = common::env_opt("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint))
+            .with_trace_config(config)
+            .install_batch(opentelemetry::runtime::Tokio)
+            .expect("Unable to set up the OTLP trace exporter")
+    } else if let Some(file) = common::env_opt("OTEL_TRACES_FILE") {
+        let writer = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&file)
+            .unwrap_or_else(|e| panic!("Unable to open `{}` {}", file, e));
+        opentelemetry::sdk::export::trace::stdout::new_pipeline()
+            .with_writer(writer)
+            .with_trace_config(config)
+            .install_simple()
+    } else {
+        return None;
+    };
+    Some(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Lets the propagator read the trace context from a request's headers
+struct HeaderExtractor<'a>(&'a HeaderMap<'a>);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get_one(key)
+    }
+
+    //Only needed by propagators which look for headers by prefix, the trace context one asks for them by name
+    fn keys(&self) -> Vec<&str> {
+        vec![]
+    }
+}
+
+/// Continue the trace from the `traceparent` header if the request has one
+pub fn set_parent(span: &Span, headers: &HeaderMap<'_>) {
+    let parent = opentelemetry::global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(headers)));
+    span.set_parent(parent);
+}
+
+/// Runs a route's handler inside the span for its request, so everything it does is part of the request's trace
+#[derive(Clone)]
+struct Traced(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Traced {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        let span = logging::context(req).clone();
+        self.0.handle(req, data).instrument(span).await
+    }
+}
+
+/// Trace every request to these routes
+pub fn traced(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(Traced(route.handler));
+            route
+        })
+        .collect()
+}
@@ -0,0 +1,329 @@
+use crate::common::env_or;
+use crate::models;
+use rocket::http::Status;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Number of failures allowed against a username before we start slowing down attempts
+const FREE_ATTEMPTS: u32 = 3;
+
+/// How often the in-memory store drops records which are no longer needed
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 5);
+
+/// The failed login attempts made against a single username or ip address
+#[derive(Clone, Debug)]
+pub struct AttemptRecord {
+    pub failures: u32,
+    pub last_failure: SystemTime,
+    pub blocked_until: Option<SystemTime>,
+}
+
+/// Somewhere to keep track of failed login attempts.
+/// Keys are prefixed with the kind of counter, e.g. `usr:bob`, `ip:127.0.0.1` or `recover-ip:127.0.0.1`.
+pub trait AttemptStore: Send + Sync {
+    fn get(&self, key: &str) -> Option<AttemptRecord>;
+    fn put(&self, key: &str, record: AttemptRecord);
+    fn remove(&self, key: &str);
+}
+
+/// Keeps attempts in memory, these are lost when the server restarts
+pub struct MemoryAttemptStore {
+    /// How long a record is kept after its last failure, this must be at least the lockout period
+    /// or failures would be forgotten before they add up to a lock
+    retention: Duration,
+    records: Mutex<MemoryRecords>,
+}
+
+struct MemoryRecords {
+    map: HashMap<String, AttemptRecord>,
+    last_sweep: SystemTime,
+}
+
+impl MemoryAttemptStore {
+    pub fn new(retention: Duration) -> MemoryAttemptStore {
+        MemoryAttemptStore {
+            retention,
+            records: Mutex::new(MemoryRecords {
+                map: HashMap::new(),
+                last_sweep: SystemTime::now(),
+            }),
+        }
+    }
+}
+
+impl AttemptStore for MemoryAttemptStore {
+    fn get(&self, key: &str) -> Option<AttemptRecord> {
+        self.records.lock().unwrap().map.get(key).cloned()
+    }
+
+    fn put(&self, key: &str, record: AttemptRecord) {
+        let mut records = self.records.lock().unwrap();
+        //Every so often drop the records whose failures have been forgotten, so the map doesn't grow forever.
+        //A record is kept for its whole retention even once any backoff has passed, as its failures still count towards a lock.
+        let now = SystemTime::now();
+        if now.duration_since(records.last_sweep).unwrap_or_default() >= SWEEP_INTERVAL {
+            let retention = self.retention;
+            records
+                .map
+                .retain(|_, r| now.duration_since(r.last_failure).unwrap_or_default() < retention);
+            records.last_sweep = now;
+        }
+        records.map.insert(key.to_owned(), record);
+    }
+
+    fn remove(&self, key: &str) {
+        self.records.lock().unwrap().map.remove(key);
+    }
+}
+
+/// Slows down repeated failed logins with an exponential backoff, and locks accounts
+/// after too many failures.
+pub struct LoginThrottle {
+    store: Box<dyn AttemptStore>,
+    /// Failures against a single username before it is locked
+    user_threshold: u32,
+    /// Failures from a single ip before it is locked out, this is higher as classrooms share an ip
+    ip_threshold: u32,
+    /// Recovery emails requested from a single ip before it must wait, kept apart from logins so that
+    /// asking for emails can't lock a school out of logging in
+    recovery_ip_threshold: u32,
+    /// How long a lock lasts, and how long failures are remembered for
+    lockout: Duration,
+}
+
+impl LoginThrottle {
+    pub fn new(
+        store: Box<dyn AttemptStore>,
+        user_threshold: u32,
+        ip_threshold: u32,
+        recovery_ip_threshold: u32,
+        lockout: Duration,
+    ) -> LoginThrottle {
+        LoginThrottle {
+            store,
+            user_threshold,
+            ip_threshold,
+            recovery_ip_threshold,
+            lockout,
+        }
+    }
+
+    /// Create a throttle configured from the `LOGIN_*` env vars, backed by an in-memory store.
+    pub fn from_env() -> LoginThrottle {
+        let lockout_minutes: u64 = env_or("LOGIN_LOCKOUT_MINUTES", 15);
+        let lockout = Duration::from_secs(lockout_minutes * 60);
+        LoginThrottle::new(
+            Box::new(MemoryAttemptStore::new(lockout)),
+            env_or("LOGIN_LOCKOUT_THRESHOLD", 10),
+            env_or("LOGIN_IP_LOCKOUT_THRESHOLD", 100),
+            env_or("RECOVERY_IP_THRESHOLD", 20),
+            lockout,
+        )
+    }
+
+    fn user_key(usr: &str) -> String {
+        format!("usr:{}", usr.to_lowercase())
+    }
+
+    fn ip_key(ip: &str) -> String {
+        format!("ip:{}", ip)
+    }
+
+    fn recovery_ip_key(ip: &str) -> String {
+        format!("recover-ip:{}", ip)
+    }
+
+    fn email_key(address: &str) -> String {
+        format!("email:{}", address.to_lowercase())
+    }
+
+    /// How long the key must wait before trying again, if at all
+    fn blocked_for(&self, key: &str) -> Option<Duration> {
+        let record = self.store.get(key)?;
+        let until = record.blocked_until?;
+        until.duration_since(SystemTime::now()).ok()
+    }
+
+    /// Check whether a login attempt may go ahead.
+    /// Returns how long the caller must wait if it may not.
+    pub fn check(&self, usr: &str, ip: Option<&str>) -> Result<(), Duration> {
+        let mut wait = self.blocked_for(&LoginThrottle::user_key(usr));
+        if let Some(ip) = ip {
+            wait = wait.max(self.blocked_for(&LoginThrottle::ip_key(ip)));
+        }
+        match wait {
+            Some(w) => Err(w),
+            None => Ok(()),
+        }
+    }
+
+    /// Count a failure against the key, locking it once `threshold` is reached.
+    /// With `backoff` the key is also slowed down after `FREE_ATTEMPTS`, this is left off for ips as a
+    /// classroom shares one and would soon slow each other down.
+    fn fail(&self, key: &str, threshold: u32, backoff: bool) {
+        let now = SystemTime::now();
+        let mut record = match self.store.get(key) {
+            //Failures are forgotten once they are older than the lockout period
+            Some(r) if now.duration_since(r.last_failure).unwrap_or_default() < self.lockout => r,
+            _ => AttemptRecord {
+                failures: 0,
+                last_failure: now,
+                blocked_until: None,
+            },
+        };
+        record.failures += 1;
+        record.last_failure = now;
+        record.blocked_until = if record.failures >= threshold {
+            Some(now + self.lockout)
+        } else if backoff && record.failures >= FREE_ATTEMPTS {
+            let backoff = Duration::from_secs(1 << (record.failures - FREE_ATTEMPTS).min(16));
+            Some(now + backoff.min(self.lockout))
+        } else {
+            None
+        };
+        self.store.put(key, record);
+    }
+
+    /// Record a failed login for this username and ip
+    pub fn record_failure(&self, usr: &str, ip: Option<&str>) {
+        self.fail(&LoginThrottle::user_key(usr), self.user_threshold, true);
+        if let Some(ip) = ip {
+            self.fail(&LoginThrottle::ip_key(ip), self.ip_threshold, false);
+        }
+    }
+
+    /// Record a successful login, clearing any failures against the username
+    pub fn record_success(&self, usr: &str) {
+        self.store.remove(&LoginThrottle::user_key(usr));
+    }
+
+    /// Check whether an email may be sent to this address, counting it if it may.
+    /// Every email counts like a failed login, whether or not the address has an account, so that a single
+    /// address or ip can't be used to flood someone's inbox. These are counted apart from logins.
+    pub fn check_email(&self, address: &str, ip: Option<&str>) -> Result<(), Duration> {
+        let key = LoginThrottle::email_key(address);
+        let mut wait = self.blocked_for(&key);
+        if let Some(ip) = ip {
+            wait = wait.max(self.blocked_for(&LoginThrottle::recovery_ip_key(ip)));
+        }
+        if let Some(w) = wait {
+            return Err(w);
+        }
+        self.fail(&key, self.user_threshold, true);
+        if let Some(ip) = ip {
+            self.fail(
+                &LoginThrottle::recovery_ip_key(ip),
+                self.recovery_ip_threshold,
+                false,
+            );
+        }
+        Ok(())
+    }
+
+    /// Remove any lock on this username, used by teachers when a student has locked themselves out
+    pub fn unlock(&self, usr: &str) {
+        self.store.remove(&LoginThrottle::user_key(usr));
+    }
+}
+
+/// The response sent when an email is blocked by the throttle
+pub fn too_many_emails(wait: Duration) -> models::Response {
+    models::ResponseBuilder {
+        data: "Too many emails have been requested, please try again later",
+        status: Status::TooManyRequests,
+    }
+    .build()
+    .with_header("Retry-After", wait.as_secs().max(1).to_string())
+}
+
+/// The response sent when a login is blocked by the throttle
+pub fn too_many_attempts(wait: Duration) -> models::Response {
+    models::ResponseBuilder {
+        data: "Too many failed login attempts, please try again later",
+        status: Status::TooManyRequests,
+    }
+    .build()
+    .with_header("Retry-After", wait.as_secs().max(1).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+    fn throttle() -> LoginThrottle {
+        LoginThrottle::new(Box::new(MemoryAttemptStore::new(LOCKOUT)), 10, 20, 5, LOCKOUT)
+    }
+
+    #[test]
+    fn a_few_failures_are_free() {
+        let throttle = throttle();
+        for _ in 0..FREE_ATTEMPTS - 1 {
+            throttle.record_failure("aroha", Some("10.0.0.1"));
+        }
+        assert!(throttle.check("aroha", Some("10.0.0.1")).is_ok());
+    }
+
+    #[test]
+    fn failures_back_off_exponentially() {
+        let throttle = throttle();
+        let mut waits = vec![];
+        for _ in 0..FREE_ATTEMPTS + 2 {
+            throttle.record_failure("aroha", None);
+            waits.push(throttle.check("aroha", None).err().map(|w| w.as_secs_f64().round() as u64));
+        }
+        assert_eq!(&waits[FREE_ATTEMPTS as usize - 1..], &[Some(1), Some(2), Some(4)]);
+    }
+
+    #[test]
+    fn usernames_lock_once_the_threshold_is_reached() {
+        let throttle = throttle();
+        for _ in 0..10 {
+            throttle.record_failure("Aroha", None);
+        }
+        let wait = throttle.check("aroha", None).unwrap_err();
+        assert!(wait > LOCKOUT - Duration::from_secs(5) && wait <= LOCKOUT);
+        //Other students are not affected
+        assert!(throttle.check("tama", None).is_ok());
+    }
+
+    #[test]
+    fn success_and_unlock_clear_the_username() {
+        let throttle = throttle();
+        for _ in 0..10 {
+            throttle.record_failure("aroha", None);
+        }
+        throttle.unlock("aroha");
+        assert!(throttle.check("aroha", None).is_ok());
+        for _ in 0..FREE_ATTEMPTS {
+            throttle.record_failure("aroha", None);
+        }
+        throttle.record_success("aroha");
+        assert!(throttle.check("aroha", None).is_ok());
+    }
+
+    #[test]
+    fn ips_lock_without_backing_off() {
+        let throttle = throttle();
+        for i in 0..19 {
+            throttle.record_failure(&format!("student{}", i), Some("10.0.0.1"));
+        }
+        assert!(throttle.check("someone", Some("10.0.0.1")).is_ok());
+        throttle.record_failure("student19", Some("10.0.0.1"));
+        assert!(throttle.check("someone", Some("10.0.0.1")).is_err());
+        assert!(throttle.check("someone", Some("10.0.0.2")).is_ok());
+    }
+
+    #[test]
+    fn recovery_emails_are_counted_apart_from_logins() {
+        let throttle = throttle();
+        for i in 0..5 {
+            assert!(throttle.check_email(&format!("{}@example.com", i), Some("10.0.0.1")).is_ok());
+        }
+        assert!(throttle.check_email("5@example.com", Some("10.0.0.1")).is_err());
+        //Logins from the same ip can still go ahead
+        assert!(throttle.check("aroha", Some("10.0.0.1")).is_ok());
+    }
+}
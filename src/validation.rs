@@ -0,0 +1,251 @@
+use crate::models;
+use lazy_static::lazy_static;
+use rocket::http::Status;
+use rocket::serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+
+/// Vowels with macrons, used throughout Te Reo Māori
+const MACRONS: &str = "āēīōūĀĒĪŌŪ";
+
+#[derive(Deserialize)]
+pub struct UsernamePolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    #[serde(default)]
+    pub reserved: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub breached_list: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct NicknamePolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+}
+
+/// The rules loaded from `./policy.toml`
+#[derive(Deserialize)]
+pub struct Policy {
+    pub username: UsernamePolicy,
+    pub password: PasswordPolicy,
+    pub nickname: NicknamePolicy,
+}
+
+lazy_static! {
+    pub static ref POLICY: Policy = {
+        let data = std::fs::read_to_string("./policy.toml").expect("Unable to find `./policy.toml`");
+        toml::from_str(&data).expect("Unable to parse `./policy.toml`")
+    };
+    static ref BREACHED_PASSWORDS: HashSet<String> = {
+        match &POLICY.password.breached_list {
+            Some(path) => std::fs::read_to_string(path)
+                .unwrap_or_else(|_| panic!("Unable to find breached password list `{}`", path))
+                .lines()
+                .map(|l| l.trim().to_lowercase())
+                .filter(|l| !l.is_empty())
+                .collect(),
+            None => HashSet::default(),
+        }
+    };
+}
+
+/// Load the policy and any breached password list, so that mistakes are found at startup
+pub fn initialize() {
+    lazy_static::initialize(&POLICY);
+    lazy_static::initialize(&BREACHED_PASSWORDS);
+}
+
+/// Problems found with a request, grouped by the field they were found in
+#[derive(Default)]
+pub struct Errors {
+    fields: BTreeMap<&'static str, Vec<String>>,
+}
+
+impl Errors {
+    pub fn add(&mut self, field: &'static str, message: String) {
+        self.fields.entry(field).or_default().push(message);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Merge the errors found by one of the `validate_*` functions into this set
+    pub fn check(&mut self, field: &'static str, result: Result<(), Vec<String>>) {
+        if let Err(messages) = result {
+            for message in messages {
+                self.add(field, message);
+            }
+        }
+    }
+
+    /// Convert into a response, returning Ok if there were no problems found
+    pub fn into_result(self) -> Result<(), models::Response> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        Err(models::ResponseBuilder {
+            data: self.fields,
+            status: Status::BadRequest,
+        }
+        .build())
+    }
+}
+
+fn is_letter(c: char) -> bool {
+    c.is_ascii_alphabetic() || MACRONS.contains(c)
+}
+
+fn check_length(s: &str, min: usize, max: usize, errors: &mut Vec<String>) {
+    let len = s.chars().count();
+    if len < min {
+        errors.push(format!("Must be at least {} characters long", min));
+    }
+    if len > max {
+        errors.push(format!("Must be at most {} characters long", max));
+    }
+}
+
+/// Usernames may contain letters (including macrons), numbers, `_`, `-` and `.`, and must start with a letter.
+pub fn validate_username(usr: &str) -> Result<(), Vec<String>> {
+    let policy = &POLICY.username;
+    let mut errors = vec![];
+    check_length(usr, policy.min_length, policy.max_length, &mut errors);
+    if !usr
+        .chars()
+        .all(|c| is_letter(c) || c.is_ascii_digit() || c == '_' || c == '-' || c == '.')
+    {
+        errors.push("May only contain letters, numbers, '_', '-' and '.'".into());
+    }
+    if !usr.chars().next().map(is_letter).unwrap_or(false) {
+        errors.push("Must start with a letter".into());
+    }
+    let lower = usr.to_lowercase();
+    if policy.reserved.iter().any(|r| r.to_lowercase() == lower) {
+        errors.push("This username is reserved".into());
+    }
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(errors)
+}
+
+/// Passwords may contain anything, but must be a reasonable length, differ from the username
+/// and not appear in the list of breached passwords.
+pub fn validate_password(pwd: &str, usr: &str) -> Result<(), Vec<String>> {
+    let policy = &POLICY.password;
+    let mut errors = vec![];
+    check_length(pwd, policy.min_length, policy.max_length, &mut errors);
+    let lower = pwd.to_lowercase();
+    if lower == usr.to_lowercase() {
+        errors.push("Must not be the same as your username".into());
+    }
+    if BREACHED_PASSWORDS.contains(&lower) {
+        errors.push("This password is too common, please choose another".into());
+    }
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(errors)
+}
+
+/// Nicknames may contain letters (including macrons), numbers, single spaces, `'` and `-`.
+pub fn validate_nickname(nickname: &str) -> Result<(), Vec<String>> {
+    let policy = &POLICY.nickname;
+    let mut errors = vec![];
+    check_length(nickname, policy.min_length, policy.max_length, &mut errors);
+    if !nickname
+        .chars()
+        .all(|c| is_letter(c) || c.is_ascii_digit() || c == ' ' || c == '\'' || c == '-')
+    {
+        errors.push("May only contain letters, numbers, spaces, apostrophes and '-'".into());
+    }
+    if nickname.starts_with(' ') || nickname.ends_with(' ') || nickname.contains("  ") {
+        errors.push("Must not start or end with a space, or contain double spaces".into());
+    }
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(errors)
+}
+
+/// Check a picture password is 3 or 4 pictures, each the name of a costume in `./costume.toml`
+pub fn validate_picture_password(pictures: &[String]) -> Result<(), Vec<String>> {
+    let mut errors = vec![];
+    if !(3..=4).contains(&pictures.len()) {
+        errors.push("Must be 3 or 4 pictures".into());
+    }
+    for picture in pictures {
+        if crate::common::find_costume(picture).is_none() {
+            errors.push(format!("{} is not a picture", picture));
+        }
+    }
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usernames_must_start_with_a_letter_and_use_allowed_characters() {
+        assert!(validate_username("aroha42").is_ok());
+        assert!(validate_username("Mānuka_b.c-d").is_ok());
+        assert!(validate_username("42aroha").is_err());
+        assert!(validate_username("aroha smith").is_err());
+        assert!(validate_username("ab").is_err());
+        assert!(validate_username(&"a".repeat(21)).is_err());
+    }
+
+    #[test]
+    fn reserved_usernames_are_refused_whatever_the_case() {
+        assert!(validate_username("Admin").is_err());
+        assert!(validate_username("KAIAKO").is_err());
+    }
+
+    #[test]
+    fn passwords_are_checked_against_the_username_and_breached_list() {
+        assert!(validate_password("pohutukawa-bloom-77", "aroha").is_ok());
+        assert!(validate_password("short", "aroha").is_err());
+        assert!(validate_password("Aroha123", "aroha123").is_err());
+        assert!(validate_password("PASSWORD", "aroha").is_err());
+    }
+
+    #[test]
+    fn nicknames_may_have_single_spaces_between_words() {
+        assert!(validate_nickname("Whaea Mere").is_ok());
+        assert!(validate_nickname("O'Brien-Ngata").is_ok());
+        assert!(validate_nickname(" Mere").is_err());
+        assert!(validate_nickname("Whaea  Mere").is_err());
+        assert!(validate_nickname("Mere!").is_err());
+    }
+
+    #[test]
+    fn picture_passwords_are_three_or_four_costumes() {
+        let pictures = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert!(validate_picture_password(&pictures(&["default", "sailor", "magician"])).is_ok());
+        assert!(validate_picture_password(&pictures(&["default", "sailor"])).is_err());
+        assert!(validate_picture_password(&pictures(&["default", "sailor", "unicorn"])).is_err());
+    }
+
+    #[test]
+    fn errors_are_grouped_by_field() {
+        let mut errors = Errors::default();
+        assert!(errors.is_empty());
+        errors.check("usr", Ok(()));
+        assert!(errors.is_empty());
+        errors.check("usr", validate_username("1"));
+        errors.add("pwd", "Too short".into());
+        assert_eq!(errors.fields["usr"].len(), 2);
+        assert_eq!(errors.fields["pwd"], vec!["Too short"]);
+        assert!(errors.into_result().is_err());
+    }
+}